@@ -1,12 +1,172 @@
+use ::fs::path;
+use ::fs::shell::{quote, tokenize};
+use ::fs::transfer;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{stdin, stdout, BufReader, BufWriter};
 use std::net::TcpStream;
+use std::path::Path;
 use std::thread;
 use std::sync::atomic::{Ordering, AtomicBool};
-use std::sync::Arc;
-use std::str;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::env;
 
+// Bytes received from the server. The printer thread echoes them to stdout,
+// while put/get take the lock for the whole transfer and consume the replies themselves.
+struct Inbox {
+  rx: Receiver<Vec<u8>>,
+  pending: VecDeque<u8>,
+}
+
+impl Read for Inbox {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.pending.is_empty() {
+      match self.rx.recv() {
+        Ok(bytes) => self.pending.extend(bytes),
+        Err(_) => return Ok(0),
+      }
+    }
+    let size = std::cmp::min(buf.len(), self.pending.len());
+    for (i, byte) in self.pending.drain(..size).enumerate() {
+      buf[i] = byte;
+    }
+    Ok(size)
+  }
+}
+
+impl Inbox {
+  // Prints whatever the server sent before the transfer started, e.g. output of the previous command
+  fn print_preceding(&mut self) {
+    let pending: Vec<u8> = self.pending.drain(..).collect();
+    print!("{}", String::from_utf8_lossy(&pending));
+    while let Ok(bytes) = self.rx.recv_timeout(Duration::from_millis(100)) {
+      print!("{}", String::from_utf8_lossy(&bytes));
+    }
+    stdout().flush().ok();
+  }
+
+  fn read_line(&mut self) -> Result<String> {
+    let mut line = vec![];
+    let mut byte = [0u8];
+    loop {
+      if self.read(&mut byte)? == 0 { return Err(anyhow!("Connection closed")) };
+      if byte[0] == b'\n' { break };
+      line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+  }
+
+  fn read_reply(&mut self) -> Result<String> {
+    let line = self.read_line()?;
+    match line.strip_prefix("ok") {
+      Some(rest) => Ok(rest.trim().to_owned()),
+      None => Err(anyhow!("{}", line.strip_prefix("error ").unwrap_or(&line).to_owned())),
+    }
+  }
+}
+
+struct Session<'a, W: Write> {
+  inbox: &'a mut Inbox,
+  writer: &'a mut W,
+}
+
+impl<'a, W: Write> Session<'a, W> {
+  fn send(&mut self, line: &str) -> Result<()> {
+    self.writer.write_all(format!("{}\n", line).as_bytes())?;
+    self.writer.flush()?;
+    Ok(())
+  }
+
+  fn put(&mut self, local: &Path, remote: &str, recursive: bool) -> Result<()> {
+    if local.is_dir() {
+      if !recursive { return Err(anyhow!("{} is a directory, use put -r", local.display())) };
+      self.send(&format!("mkdirp {}", quote(remote)))?;
+      self.inbox.read_reply()?;
+      for entry in fs::read_dir(local)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        self.put(&entry.path(), &format!("{}/{}", remote, name), recursive)?;
+      }
+      return Ok(());
+    }
+    let size = fs::metadata(local)?.len() as usize;
+    let crc = transfer::copy_chunked(&mut File::open(local)?, &mut std::io::sink(), size, |_| ())?;
+    self.send(&transfer::upload_header(remote, size, crc))?;
+    self.writer.write_all(remote.as_bytes())?;
+    let label = local.display().to_string();
+    transfer::copy_chunked(&mut File::open(local)?, self.writer, size, |done| progress(&label, done, size))?;
+    self.writer.flush()?;
+    println!();
+    self.inbox.read_reply()?;
+    Ok(())
+  }
+
+  fn get(&mut self, remote: &str, local: &Path, recursive: bool) -> Result<()> {
+    self.send(&format!("entries {}", quote(remote)))?;
+    if let Ok(listing) = self.inbox.read_reply() {
+      let count = listing.parse::<usize>().map_err(|_| anyhow!("Malformed listing of {}: {}", remote, listing))?;
+      let mut entries = vec![];
+      for _ in 0..count {
+        entries.push(self.inbox.read_line()?);
+      }
+      if !recursive { return Err(anyhow!("{} is a directory, use get -r", remote)) };
+      fs::create_dir_all(local)?;
+      // Names come from the server, only single path components are let through to the host
+      for entry in entries {
+        let (kind, name) = entry.split_once(' ').filter(|(_, name)| path::check_name(name).is_ok())
+                                .ok_or_else(|| anyhow!("Malformed entry of {}: {}", remote, entry))?;
+        let (from, to) = (format!("{}/{}", remote.trim_end_matches('/'), name), local.join(name));
+        match kind {
+          "d" => self.get(&from, &to, recursive)?,
+          "f" => self.download(&from, &to)?,
+          "l" => println!("{}: skipped, a symbolic link", from),
+          _ => return Err(anyhow!("Malformed entry of {}: {}", remote, entry)),
+        }
+      }
+      return Ok(());
+    }
+    self.download(remote, local)
+  }
+
+  fn download(&mut self, remote: &str, local: &Path) -> Result<()> {
+    self.send(&format!("download {}", quote(remote)))?;
+    let header = self.inbox.read_reply()?;
+    let size = header.parse::<usize>().map_err(|_| anyhow!("Malformed download header: {}", header))?;
+    let mut file = BufWriter::new(File::create(local)?);
+    let received = transfer::copy_chunked(self.inbox, &mut file, size, |done| progress(remote, done, size))?;
+    file.flush()?;
+    println!();
+    let verified = self.inbox.read_reply().and_then(|crc| match crc.parse::<u32>() {
+      Ok(crc) if crc == received => Ok(()),
+      Ok(_) => Err(anyhow!("Checksum mismatch for {}", remote)),
+      Err(_) => Err(anyhow!("Malformed download checksum: {}", crc)),
+    });
+    if verified.is_err() { fs::remove_file(local).ok(); }
+    verified
+  }
+}
+
+fn progress(label: &str, done: usize, total: usize) {
+  let percent = (done * 100).checked_div(total).unwrap_or(100);
+  print!("\r{}: {}/{} bytes ({}%)", label, done, total, percent);
+  stdout().flush().ok();
+}
+
+// put [-r] <local> <remote> and get [-r] <remote> <local> are run by the client itself
+fn transfer_command(line: &str) -> Option<(String, bool, String, String)> {
+  let tokens = tokenize(line).ok()?;
+  let words: Vec<&str> = tokens.iter().map(|token| token.as_str()).collect();
+  match words.as_slice() {
+    [cmd @ ("put" | "get"), from, to] => Some((cmd.to_string(), false, from.to_string(), to.to_string())),
+    [cmd @ ("put" | "get"), "-r", from, to] => Some((cmd.to_string(), true, from.to_string(), to.to_string())),
+    _ => None,
+  }
+}
+
 fn main() -> std::io::Result<()> {
   let mut host = "127.0.0.1";
   let mut port = "4242";
@@ -26,29 +186,66 @@ fn main() -> std::io::Result<()> {
   let cond = Arc::new(AtomicBool::new(true));
   let read_cond = cond.clone();
   let write_cond = cond.clone();
+  let transferring = Arc::new(AtomicBool::new(false));
+  let print_paused = transferring.clone();
+  let (tx, rx) = channel();
+  let inbox = Arc::new(Mutex::new(Inbox { rx, pending: VecDeque::new() }));
+  let printer_inbox = inbox.clone();
+
+  thread::spawn(move || {
+    let mut buffer = [0u8; 1024];
+    while let Ok(size) = reader.read(&mut buffer) {
+      if size == 0 || tx.send(buffer[..size].to_vec()).is_err() { break };
+    }
+  }); // thread which endlessly fetches data from the socket
 
   let writer_thread = thread::spawn(move || {
     let mut buffer = String::new();
     while write_cond.load(Ordering::SeqCst) {
       stdin().read_line(&mut buffer).ok();
-      writer.write(&buffer.as_bytes()).ok();
-      writer.flush().ok();
+      match transfer_command(&buffer) {
+        Some((cmd, recursive, from, to)) => {
+          transferring.store(true, Ordering::SeqCst);
+          let mut inbox = inbox.lock().unwrap();
+          inbox.print_preceding();
+          let mut session = Session { inbox: &mut inbox, writer: &mut writer };
+          let result = if cmd == "put" { session.put(Path::new(&from), &to, recursive) }
+                       else { session.get(&from, Path::new(&to), recursive) };
+          if let Err(why) = result { println!("{} failed: {}", cmd, why) };
+          session.send("").ok(); // asks the server for a fresh prompt
+          transferring.store(false, Ordering::SeqCst);
+        }
+        None => {
+          writer.write_all(buffer.as_bytes()).ok();
+          writer.flush().ok();
+        }
+      }
       buffer = "".to_owned();
     }
   }); // thread which endlessly captures stdin and sends it to the socket
 
   let reader_thread = thread::spawn(move || {
-    let mut buffer = [0u8, 32];
-    while let Ok(size) = reader.read(&mut buffer) { 
-      if size == 0 { break };
-      let s = str::from_utf8(&buffer).unwrap_or("");
-      print!("{}", s);
-      buffer = [0u8, 32];
+    loop {
+      if print_paused.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(10));
+        continue;
+      }
+      let mut inbox = printer_inbox.lock().unwrap();
+      let bytes: Vec<u8> = if inbox.pending.is_empty() {
+        match inbox.rx.recv_timeout(Duration::from_millis(50)) {
+          Ok(bytes) => bytes,
+          Err(RecvTimeoutError::Timeout) => continue,
+          Err(RecvTimeoutError::Disconnected) => break,
+        }
+      } else {
+        inbox.pending.drain(..).collect()
+      };
+      print!("{}", String::from_utf8_lossy(&bytes));
       stdout().flush().ok();
     };
     read_cond.store(false, Ordering::SeqCst);
     println!("Press Enter to exit");
-  }); // thread which endlessly fetches data from the socket and prints it to stdout
+  }); // thread which endlessly prints data from the socket to stdout
 
   writer_thread.join().ok();
   reader_thread.join().ok();
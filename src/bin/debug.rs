@@ -0,0 +1,149 @@
+use ::fs::debug::{hexdump, BitmapKind, BlockUse};
+use ::fs::filesystem::Filesystem;
+use ::fs::structure::HOLE;
+use ::fs::Fs;
+use anyhow::{anyhow, Result};
+use std::env;
+use std::io::IsTerminal;
+
+const USAGE: &str = "Usage: ext2debug image command [argument]...
+  Opens images whose free counters disagree with the bitmaps too, and writes only for the
+  commands that change something.
+  stats                      prints the superblock and the free counts of the bitmaps
+  inode number               prints the inode
+  blocks path                prints the data blocks of the entry at path, and those of its extent tree
+  bd block                   dumps the data block in hex
+  icheck block               prints the inode the data block belongs to
+  free blocks|inodes         lists the runs of free blocks or inodes
+  setb|freeb block           marks the data block taken or free
+  seti|freei number          marks the inode taken or free
+  sif number field value     sets a field of the inode, like size, mode or direct[0]
+  ssv field value            sets free_blocks_count or free_inodes_count of the superblock
+  map [width]                draws which file every data block belongs to, 64 cells a row by default
+  frag                       lists the extents of every file and how fragmented the image is";
+
+// Rows of the block map at most; bigger images get several blocks a cell
+const MAP_ROWS: usize = 32;
+
+fn number(arg: &str) -> Result<usize> {
+  arg.parse().map_err(|_| anyhow!("Not a number: {}", arg))
+}
+
+fn bitmap(arg: &str) -> Result<BitmapKind> {
+  match arg {
+    "blocks" => Ok(BitmapKind::Blocks),
+    "inodes" => Ok(BitmapKind::Inodes),
+    _ => Err(anyhow!("Expected blocks or inodes: {}", arg)),
+  }
+}
+
+// Block numbers, runs of consecutive ones joined as `first-last`
+fn ranges(blocks: &[usize]) -> String {
+  let mut runs: Vec<(usize, usize)> = vec![];
+  for &block in blocks {
+    match runs.last_mut() {
+      Some((_, last)) if block != HOLE && *last != HOLE && *last + 1 == block => *last = block,
+      _ => runs.push((block, block)),
+    }
+  }
+  let shown: Vec<String> = runs.iter().map(|&(first, last)| match (first, last) {
+    (HOLE, _) => "hole".to_owned(),
+    (first, last) if first == last => first.to_string(),
+    (first, last) => format!("{}-{}", first, last),
+  }).collect();
+  shown.join(" ")
+}
+
+// Notes the inodes a scan went past because their blocks could not be read
+fn report_corrupted(inodes: &[usize]) {
+  for inode in inodes { eprintln!("inode {}: corrupted, skipped", inode) };
+}
+
+fn run(image: &str, command: &str, args: &[String]) -> Result<()> {
+  let mut fs = Fs::open_raw(image)?;
+  let free = |fs: &Fs, kind| fs.free_runs(kind).iter().map(|(_, len)| len).sum::<usize>();
+  match (command, args) {
+    ("stats", []) => {
+      println!("{:#?}", fs.superblock());
+      println!("Bitmaps: {} free blocks, {} free inodes", free(&fs, BitmapKind::Blocks), free(&fs, BitmapKind::Inodes));
+    }
+    ("inode", [inode]) => {
+      let inode = number(inode)?;
+      let state = if fs.free_runs(BitmapKind::Inodes).iter().any(|&(start, len)| (start..start + len).contains(&inode)) { "free" } else { "in use" };
+      println!("Inode {} ({})\n{:#?}", inode, state, fs.raw_inode(inode)?);
+    }
+    ("blocks", [path]) => {
+      let inode = fs.lookup(path)?;
+      let (blocks, nodes) = fs.inode_blocks(inode)?;
+      println!("Inode {}, {} blocks", inode, blocks.len());
+      if !blocks.is_empty() { println!("Data: {}", ranges(&blocks)) };
+      if !nodes.is_empty() { println!("Extent tree: {}", ranges(&nodes)) };
+    }
+    ("bd", [block]) => print!("{}", hexdump(&fs.raw_block(number(block)?)?)),
+    ("icheck", [block]) => {
+      let (owner, corrupted) = fs.block_owner(number(block)?)?;
+      report_corrupted(&corrupted);
+      match owner {
+        Some(BlockUse::Data { inode, logical }) => println!("Block {} is block {} of inode {}", block, logical, inode),
+        Some(BlockUse::Index { inode }) => println!("Block {} is in the extent tree of inode {}", block, inode),
+        Some(BlockUse::Reserved) => println!("Block {} is reserved", block),
+        None => println!("Block {} belongs to no inode", block),
+      }
+    }
+    ("free", [kind]) => {
+      let runs = fs.free_runs(bitmap(kind)?);
+      for (start, len) in runs.iter() {
+        if *len == 1 { println!("{}", start) } else { println!("{}-{}", start, start + len - 1) };
+      }
+      println!("{} free", free(&fs, bitmap(kind)?));
+    }
+    ("setb", [block]) | ("freeb", [block]) => fs.set_bit(BitmapKind::Blocks, number(block)?, command == "setb")?,
+    ("seti", [inode]) | ("freei", [inode]) => fs.set_bit(BitmapKind::Inodes, number(inode)?, command == "seti")?,
+    ("sif", [inode, field, value]) => {
+      let inode = number(inode)?;
+      let mut raw = fs.raw_inode(inode)?;
+      raw.set_field(field, value)?;
+      fs.write_raw_inode(inode, &raw)?;
+    }
+    ("ssv", [field, value]) => fs.set_superblock_field(field, value)?,
+    ("map", []) | ("map", [_]) => {
+      let width = args.first().map_or(Ok(64), |width| number(width))?.max(1);
+      let layout = fs.layout()?;
+      report_corrupted(&layout.corrupted);
+      let colour = std::io::stdout().is_terminal();
+      let blocks_per_cell = layout.owners.len().div_ceil(width * MAP_ROWS);
+      if blocks_per_cell > 1 { println!("{} blocks a cell", blocks_per_cell) };
+      print!("{}\n{}", layout.render_map(width, width * MAP_ROWS, colour), layout.legend(colour));
+    }
+    ("frag", []) => {
+      let layout = fs.layout()?;
+      report_corrupted(&layout.corrupted);
+      let mut files: Vec<_> = layout.files.iter().filter(|file| file.extents > 0).collect();
+      files.sort_by_key(|file| std::cmp::Reverse(file.extents));
+      println!("{:>8} {:>8}  path", "extents", "blocks");
+      for file in files {
+        let path = file.path.clone().unwrap_or_else(|| format!("<inode {}>", file.inode));
+        println!("{:>8} {:>8}  {}", file.extents, file.blocks, path);
+      }
+      let stats = fs.fragmentation()?;
+      println!("\n{} of {} files fragmented, {} extents, fragmentation factor {:.1}%", stats.fragmented_files, stats.files,
+               stats.extents, stats.factor() * 100.0);
+      println!("{} free blocks in {} runs, the longest {} blocks", fs.statfs().free_blocks, stats.free_extents,
+               stats.largest_free_extent);
+    }
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  }
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let result = match args.as_slice() {
+    [image, command, rest @ ..] => run(image, command, rest),
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = result {
+    eprintln!("ext2debug: {}", why);
+    std::process::exit(1);
+  }
+}
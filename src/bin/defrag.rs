@@ -0,0 +1,31 @@
+use ::fs::Fs;
+use anyhow::Result;
+use std::env;
+
+const USAGE: &str = "Usage: ext2defrag [-n] image
+  Moves every file stored in several runs of blocks into a single one. Each file moves on its own,
+  so the image stays usable if this is interrupted.
+  -n           only report what would be moved";
+
+fn run(image: &str, dry_run: bool) -> Result<()> {
+  let mut fs = Fs::open_image(image)?;
+  let report = fs.defragment(dry_run)?;
+  let verb = if dry_run { "Would move" } else { "Moved" };
+  println!("{} {} files, {} blocks", verb, report.files, report.blocks);
+  println!("Extents: {} -> {}", report.extents_before, report.extents_after);
+  if report.skipped > 0 { println!("{} fragmented files left as they are, for want of a long enough free run", report.skipped) };
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let result = match args.as_slice() {
+    [image] if !image.starts_with('-') => run(image, false),
+    [flag, image] if flag == "-n" => run(image, true),
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = result {
+    eprintln!("ext2defrag: {}", why);
+    std::process::exit(1);
+  }
+}
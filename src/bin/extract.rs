@@ -0,0 +1,32 @@
+use ::fs::host;
+use ::fs::Fs;
+use anyhow::Result;
+use std::env;
+use std::path::Path;
+
+const USAGE: &str = "Usage: ext2extract image destination [path]
+  Recreates the entry at path in the image, the root by default, at destination on the host";
+
+fn run(image: &str, dest: &str, source: &str) -> Result<()> {
+  let fs = Fs::open_image(image)?;
+  let copied = host::extract(&fs, source, Path::new(dest))?;
+  for skipped in copied.skipped.iter() {
+    eprintln!("Skipped {}", skipped);
+  }
+  println!("Extracted {} files, {} directories and {} symbolic links to {}", copied.files, copied.directories,
+           copied.symlinks, dest);
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let result = match args.as_slice() {
+    [image, dest] => run(image, dest, "/"),
+    [image, dest, source] => run(image, dest, source),
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = result {
+    eprintln!("ext2extract: {}", why);
+    std::process::exit(1);
+  }
+}
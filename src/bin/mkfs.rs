@@ -0,0 +1,96 @@
+use ::fs::host;
+use ::fs::manifest::{self, Manifest};
+use ::fs::structure::{parse_size, Superblock, BLOCK_SIZE, INCOMPAT_NAMES};
+use ::fs::Fs;
+use anyhow::{anyhow, Result};
+use std::env;
+use std::path::Path;
+
+const USAGE: &str = "Usage: ext2mkfs [-b block-size] [-N inodes] [-O feature[,feature]...] [-d directory | -m manifest] image size
+  size         bytes the image takes, with an optional K, M or G suffix
+  -b           bytes per block: 1024 (default), 2048 or 4096
+  -N           number of inodes, one per 8K of the image by default
+  -O           features to enable: extents, inline_data; without extents files hold at most 12 blocks,
+               and a directory growing past them turns extents on for the image
+  -d           host directory to copy into the image
+  -m           manifest to build the image from, the same every time, printing its SHA-256";
+
+struct Options {
+  block_size: usize,
+  inodes_count: Option<usize>,
+  feature_incompat: u32,
+  directory: Option<String>,
+  manifest: Option<String>,
+  image: String,
+  size: usize,
+}
+
+fn parse_features(names: &str) -> Result<u32> {
+  names.split(',').try_fold(0, |features, name| {
+    let (_, bit) = INCOMPAT_NAMES.iter().find(|(known, _)| *known == name).ok_or(anyhow!("Unknown feature: {}", name))?;
+    Ok(features | bit)
+  })
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+  let (mut block_size, mut inodes_count, mut feature_incompat) = (BLOCK_SIZE, None, 0);
+  let (mut directory, mut manifest) = (None, None);
+  let mut operands = vec![];
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let mut value = || args.next().ok_or(anyhow!("Option {} needs a value", arg));
+    match arg.as_str() {
+      "-b" => block_size = parse_size(value()?)?,
+      "-N" => inodes_count = Some(value()?.parse().map_err(|_| anyhow!("Invalid number of inodes"))?),
+      "-O" => feature_incompat |= parse_features(value()?)?,
+      "-d" => directory = Some(value()?.clone()),
+      "-m" => manifest = Some(value()?.clone()),
+      option if option.starts_with('-') => return Err(anyhow!("Unknown option: {}", option)),
+      operand => operands.push(operand.to_owned()),
+    }
+  }
+  if directory.is_some() && manifest.is_some() { return Err(anyhow!("Options -d and -m exclude each other")) };
+  match operands.as_slice() {
+    [image, size] => Ok(Options {
+      block_size, inodes_count, feature_incompat, directory, manifest, image: image.clone(), size: parse_size(size)?,
+    }),
+    _ => Err(anyhow!("Expected an image and its size")),
+  }
+}
+
+fn run(options: &Options) -> Result<()> {
+  let superblock = Superblock::fitting(options.size, options.block_size, options.inodes_count, options.feature_incompat)?;
+  println!("Formatting {}: {} blocks of {} bytes, {} inodes", options.image, superblock.blocks_count,
+           superblock.block_size, superblock.inodes_count);
+  if let Some(file) = &options.manifest {
+    let manifest = Manifest::load(Path::new(file))?;
+    let digest = manifest::build(&manifest, &options.image, superblock)?;
+    println!("Built {} entries from {}", manifest.entries.len(), file);
+    println!("SHA-256 {}", manifest::hex(&digest));
+    return Ok(());
+  }
+  let mut fs = Fs::format_with(&options.image, superblock)?;
+  if let Some(directory) = &options.directory {
+    let copied = host::populate(&mut fs, Path::new(directory), "/")?;
+    for skipped in copied.skipped.iter() {
+      eprintln!("Skipped {}", skipped);
+    }
+    println!("Copied {} files, {} directories and {} symbolic links from {}", copied.files, copied.directories,
+             copied.symlinks, directory);
+  }
+  let stats = fs.statfs();
+  println!("{} of {} blocks and {} of {} inodes free", stats.free_blocks, stats.blocks, stats.free_inodes, stats.inodes);
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let options = match parse_args(&args) {
+    Ok(options) => options,
+    Err(why) => { eprintln!("{}\n{}", why, USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = run(&options) {
+    eprintln!("ext2mkfs: {}", why);
+    std::process::exit(1);
+  }
+}
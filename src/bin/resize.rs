@@ -0,0 +1,40 @@
+use ::fs::structure::{parse_size, Superblock};
+use ::fs::Fs;
+use anyhow::{anyhow, Result};
+use std::env;
+
+const USAGE: &str = "Usage: ext2resize [-N inodes] image size
+  Grows or shrinks the image to size bytes, with an optional K, M or G suffix. Shrinking moves
+  the blocks and inodes in use out of the part removed, and fails if they do not fit.
+  -N           number of inodes, by default one per 8K of the image but no fewer than now";
+
+fn run(image: &str, size: &str, inodes_count: Option<&str>) -> Result<()> {
+  let mut fs = Fs::open_image(image)?;
+  let old = fs.superblock().clone();
+  let size = parse_size(size)?;
+  let inodes_count = match inodes_count {
+    Some(count) => count.parse().map_err(|_| anyhow!("Invalid number of inodes"))?,
+    None => std::cmp::max(Superblock::fitting(size, old.block_size, None, old.feature_incompat)?.inodes_count, old.inodes_count),
+  };
+  let new = Superblock::fitting(size, old.block_size, Some(inodes_count), old.feature_incompat)?;
+  println!("Resizing {}: {} -> {} blocks of {} bytes, {} -> {} inodes", image, old.blocks_count, new.blocks_count,
+           old.block_size, old.inodes_count, new.inodes_count);
+  let moved = fs.resize(new.blocks_count, new.inodes_count)?;
+  if moved.blocks > 0 || moved.inodes > 0 { println!("Moved {} blocks and {} inodes", moved.blocks, moved.inodes) };
+  let stats = fs.statfs();
+  println!("{} of {} blocks and {} of {} inodes free", stats.free_blocks, stats.blocks, stats.free_inodes, stats.inodes);
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let result = match args.as_slice() {
+    [image, size] if !image.starts_with('-') => run(image, size, None),
+    [flag, inodes, image, size] if flag == "-N" => run(image, size, Some(inodes)),
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = result {
+    eprintln!("ext2resize: {}", why);
+    std::process::exit(1);
+  }
+}
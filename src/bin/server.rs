@@ -1,114 +1,234 @@
+use ::fs::filesystem::{FileType, Filesystem, Handle};
+use ::fs::shell::{read_input, Command, Pipeline, Shell};
+use ::fs::path;
+use ::fs::transfer;
 use ::fs::Fs;
-use anyhow::Error;
+use anyhow::{anyhow, Error, Result};
 use daemonize::Daemonize;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
-use std::str::FromStr;
-
-#[derive(Debug)]
-enum Command {
-  Pwd,
-  Ls,
-  Help,
-  Exit,
-  Cd(String),
-  Touch(String),
-  Mkdir(String),
-  Cat(String),
-  Rm(String),
+
+// Writes an upload into the image chunk by chunk as it arrives. After a failure the rest
+// is still consumed, so that the connection stays in step with the client.
+struct Upload<'a, F: Filesystem> {
+  fs: &'a mut F,
+  handle: Handle,
+  offset: usize,
+  failure: Option<Error>,
+}
+
+impl<'a, F: Filesystem> Write for Upload<'a, F> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    if self.failure.is_none() {
+      self.failure = self.fs.write_at(self.handle, self.offset, buf).err();
+    }
+    self.offset += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+// Reads a file of the image chunk by chunk for a download. A failure, or a file that ends early,
+// is padded with zeros up to the size announced and reported once the content is sent.
+struct Download<'a, F: Filesystem> {
+  fs: &'a F,
+  handle: Handle,
+  offset: usize,
+  failure: Option<Error>,
 }
 
-impl FromStr for Command {
-  type Err = String;
-
-  fn from_str(s: &str) -> Result<Command, Self::Err> {
-    let split: Vec<&str> = s.split(" ").collect();
-    match split.as_slice() {
-      ["pwd"] => Ok(Command::Pwd),
-      ["ls"] => Ok(Command::Ls),
-      ["exit"] => Ok(Command::Exit),
-      ["help"] => Ok(Command::Help),
-      ["cd", dest] => Ok(Command::Cd((*dest).to_owned())),
-      ["touch", name] => Ok(Command::Touch((*name).to_owned())),
-      ["mkdir", name] => Ok(Command::Mkdir((*name).to_owned())),
-      ["cat", name] => Ok(Command::Cat((*name).to_owned())),
-      ["rm", name] => Ok(Command::Rm((*name).to_owned())),
-      x => Err("Unknown command: ".to_owned() + x.get(0).unwrap_or(&"")),
+impl<'a, F: Filesystem> Read for Download<'a, F> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let bytes = match self.failure {
+      None => self.fs.read_at(self.handle, self.offset, buf.len()).unwrap_or_else(|why| {
+        self.failure = Some(why);
+        vec![]
+      }),
+      Some(_) => vec![],
+    };
+    if bytes.len() < buf.len() && self.failure.is_none() {
+      self.failure = Some(anyhow!("The file ended after {} bytes", self.offset + bytes.len()));
+    }
+    buf[..bytes.len()].copy_from_slice(&bytes);
+    buf[bytes.len()..].fill(0);
+    self.offset += buf.len();
+    Ok(buf.len())
+  }
+}
+
+// Receives the upload announced by `line` into the image. The outer error means the framing
+// is lost and the connection has to be dropped, the inner one that the file was not stored.
+fn receive_upload<F: Filesystem, R: BufRead>(shell: &mut Shell<F>, reader: &mut R, line: &str) -> Result<Result<()>> {
+  let (size, crc, length) = transfer::parse_upload_header(line).ok_or_else(|| anyhow!("Malformed upload header: {}", line))?;
+  if length > transfer::MAX_PATH { return Err(anyhow!("Upload path of {} bytes is too long", length)) };
+  let stats = shell.fs().statfs()?;
+  if size > stats.blocks * stats.block_size {
+    return Err(anyhow!("Upload of {} bytes does not fit into the file system", size));
+  }
+  let mut name = vec![0; length];
+  reader.read_exact(&mut name)?;
+  let name = String::from_utf8(name).map_err(|_| anyhow!("Upload path is not valid UTF-8"))?;
+  let dest = shell.resolve(&name);
+  // The content goes to a temporary sibling first, so that a failed upload leaves an existing file as it was
+  let temp = match temporary_sibling(shell.fs(), &dest) {
+    Err(why) => {
+      transfer::copy_chunked(reader, &mut std::io::sink(), size, |_| ())?;
+      return Ok(Err(why));
+    }
+    Ok(temp) => temp,
+  };
+  let handle = match shell.fs().create(&temp, &[]).and_then(|_| shell.fs().open(&temp)) {
+    Err(why) => {
+      shell.fs().unlink(&temp).ok();
+      transfer::copy_chunked(reader, &mut std::io::sink(), size, |_| ())?;
+      return Ok(Err(why));
     }
+    Ok(handle) => handle,
+  };
+  let mut upload = Upload { fs: shell.fs(), handle, offset: 0, failure: None };
+  let received = transfer::copy_chunked(reader, &mut upload, size, |_| ());
+  let failure = upload.failure.take();
+  let closed = shell.fs().close(handle);
+  let stored = match (&received, failure) {
+    (Err(_), _) => Err(anyhow!("Upload of {} was cut short", name)),
+    (_, Some(why)) => Err(why),
+    (Ok(received), None) if *received != crc => Err(anyhow!("Checksum mismatch for {}", name)),
+    _ => closed.and_then(|_| replace(shell.fs(), &temp, &dest)),
+  };
+  if stored.is_err() { shell.fs().unlink(&temp).ok(); }
+  received?;
+  Ok(stored)
+}
+
+// A free name next to `dest` for receiving its content, refusing a `dest` that is a directory
+fn temporary_sibling<F: Filesystem>(fs: &mut F, dest: &str) -> Result<String> {
+  if let Ok(meta) = fs.stat(dest) {
+    if meta.file_type == FileType::Directory { return Err(anyhow!("Is a directory: {}", dest)) };
   }
+  let (parent, _) = path::split_parent(dest)?;
+  (0..1000).map(|n| path::resolve(&parent, &format!(".upload-{}", n)))
+    .find(|temp| fs.lstat(temp).is_err())
+    .ok_or_else(|| anyhow!("No room for a temporary file in {}", parent))
 }
 
-const HELP_MESSAGE: &str = "pwd              - prints active directory
-ls               - lists all filenames in active directory
-exit             - exits the application
-help             - prints this message
-cd    [dest]     - sets active directory to `dest`
-touch [filename] - creates a new file with content of the next entered line
-mkdir [dirname]  - creates a new directory
-cat   [filename] - prints the content of the file
-rm    [name]     - removes file or directory";
-
-fn handle_client(fs: &mut Fs, stream: &mut TcpStream) -> std::io::Result<()> {
+// Moves the complete upload at `temp` over `dest`, keeping the permission bits of the file replaced
+fn replace<F: Filesystem>(fs: &mut F, temp: &str, dest: &str) -> Result<()> {
+  if let Ok(old) = fs.lstat(dest) {
+    if let Some(mode) = old.mode { fs.set_mode(temp, mode)? };
+    fs.unlink(dest)?;
+  }
+  fs.rename(temp, dest)
+}
+
+fn handle_client<F: Filesystem>(fs: &mut F, stream: &mut TcpStream) -> std::io::Result<()> {
   let mut reader = BufReader::new(stream.try_clone()?);
   let mut writer = BufWriter::new(stream);
-  writer.write(
+  writer.write_all(
     b"Welcome to a modest ext2-like file system!. Type `help` to list its capabilities.\n",
   )?;
   fn write_err<R: Write>(writer: &mut BufWriter<R>, err: Error) {
-    writer.write(format!("{:?}\n", err).as_bytes()).ok();
-    ()
+    writer.write_all(format!("{:?}\n", err).as_bytes()).ok();
   }
   fn write_msg<R: Write>(writer: &mut BufWriter<R>, msg: &String) {
-    writer.write(format!("{}\n", msg).as_bytes()).ok();
-    ()
+    writer.write_all(format!("{}\n", msg).as_bytes()).ok();
+  }
+  fn write_reply<R: Write>(writer: &mut BufWriter<R>, reply: anyhow::Result<String>) {
+    let line = match reply {
+      Ok(msg) if msg.is_empty() => "ok".to_owned(),
+      Ok(msg) => format!("ok {}", msg),
+      Err(why) => format!("error {}", why),
+    };
+    write_msg(writer, &line);
+  }
+  fn write_results<R: Write>(writer: &mut BufWriter<R>, errors: Vec<Error>) {
+    for err in errors {
+      write_err(writer, err);
+    }
   }
 
+  let mut shell = Shell::new(fs);
+  let mut prompt = true;
   loop {
-    writer.write(format!("{} > ", fs.cur_dir).as_bytes())?;
+    if prompt { writer.write_all(format!("{} > ", shell.cwd).as_bytes())? };
+    prompt = true;
     writer.flush().ok();
     let mut buffer = String::new();
     match reader.read_line(&mut buffer) {
       Err(why) => write_msg(&mut writer, &format!("Error while reading input: {}", why)),
       Ok(_) => {
         let buffer = buffer.replace(&['\n', '\r'][..], "");
-        match buffer.parse::<Command>() {
-          Ok(Command::Pwd) => write_msg(&mut writer, &format!("{}", fs.pwd())),
-          Ok(Command::Ls) => match fs.ls() {
-            Err(why) => write_err(&mut writer, why),
-            Ok(names) => write_msg(&mut writer, &format!("{}", names.join("\n"))),
-          },
-          Ok(Command::Exit) => break,
-          Ok(Command::Help) => write_msg(&mut writer, &format!("{}", HELP_MESSAGE)),
-          Ok(Command::Cd(dest)) => fs
-            .cd(dest)
-            .map_or_else(|e| write_err(&mut writer, e), |_| ()),
-          Ok(Command::Touch(name)) => {
-            let mut buffer = String::new();
-            match reader.read_line(&mut buffer) {
-              Err(why) => write_msg(&mut writer, &format!("Error while reading input: {}", why)),
-              Ok(_) => {
-                buffer.pop();
-                fs.touch(name, buffer.as_bytes())
-                  .map_or_else(|e| write_err(&mut writer, e), |_| ())
+        if buffer.split(' ').next() == Some("upload") {
+          prompt = false;
+          match receive_upload(&mut shell, &mut reader, &buffer) {
+            Ok(stored) => write_reply(&mut writer, stored.map(|_| String::new())),
+            Err(why) => { write_reply(&mut writer, Err(why)); break },
+          }
+          continue;
+        }
+        let pipeline = match buffer.parse::<Pipeline>() {
+          Err(why) => { write_msg(&mut writer, &why); continue },
+          Ok(pipeline) => pipeline,
+        };
+        match pipeline.single() {
+          Some(Command::Exit) => break,
+          Some(Command::Download(name)) => {
+            prompt = false;
+            let source = shell.resolve(name);
+            let opened = shell.fs().stat(&source).and_then(|meta| Ok((meta.size, shell.fs().open(&source)?)));
+            match opened {
+              Err(why) => write_reply(&mut writer, Err(why)),
+              Ok((size, handle)) => {
+                write_reply(&mut writer, Ok(size.to_string()));
+                let mut download = Download { fs: shell.fs(), handle, offset: 0, failure: None };
+                let crc = transfer::copy_chunked(&mut download, &mut writer, size, |_| ())?;
+                let failure = download.failure.take();
+                shell.fs().close(handle).ok();
+                write_reply(&mut writer, failure.map_or(Ok(crc.to_string()), Err));
               }
             }
           }
-          Ok(Command::Mkdir(name)) => fs
-            .mkdir(name)
-            .map_or_else(|e| write_err(&mut writer, e), |_| ()),
-          Ok(Command::Cat(name)) => {
-            let msg = fs
-              .cat(name)
-              .map_or_else(|e| format!("{}", e), |content| content);
-            write_msg(&mut writer, &msg)
+          Some(Command::Entries(name)) => {
+            prompt = false;
+            let dir = shell.resolve(name);
+            let reply = shell.fs().readdir(&dir).map(|entries| {
+              entries.iter().fold(entries.len().to_string(), |acc, entry| match entry.file_type {
+                FileType::Directory => format!("{}\nd {}", acc, entry.name),
+                FileType::File => format!("{}\nf {}", acc, entry.name),
+                FileType::Symlink => format!("{}\nl {}", acc, entry.name),
+              })
+            });
+            write_reply(&mut writer, reply)
+          }
+          Some(Command::Mkdirp(name)) => {
+            prompt = false;
+            let dest = shell.resolve(name);
+            let reply = match shell.fs().stat(&dest) {
+              Ok(meta) if meta.file_type == FileType::Directory => Ok(String::new()),
+              Ok(_) => Err(anyhow!("Is not a directory: {}", name)),
+              Err(_) => shell.fs().mkdir(&dest).map(|_| String::new()),
+            };
+            write_reply(&mut writer, reply)
+          }
+          _ => {
+            let input = match pipeline.stages.first() {
+              Some(Command::Write(_, input)) => read_input(&mut reader, input),
+              _ => Ok(vec![]),
+            };
+            let mut errors = vec![];
+            match input {
+              Err(why) => errors.push(why),
+              Ok(input) => {
+                let mut output = shell.run(&pipeline, input, &mut errors);
+                if !output.is_empty() && !output.ends_with(b"\n") { output.push(b'\n') };
+                writer.write_all(&output)?;
+              }
+            }
+            write_results(&mut writer, errors);
           }
-          Ok(Command::Rm(name)) => fs
-            .rm(name)
-            .map_or_else(|e| write_err(&mut writer, e), |_| ()),
-          Err(why) => write_msg(&mut writer, &format!("{}", why)),
         }
       }
     }
@@ -138,7 +258,7 @@ fn main() {
     [_] => {}
     _ => {
       println!("Possible arguments: [optional port]");
-      return ();
+      return;
     }
   }
   println!("Running file system daemon on port {}", port);
@@ -0,0 +1,53 @@
+use ::fs::tar;
+use ::fs::Fs;
+use anyhow::Result;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+
+const USAGE: &str = "Usage: ext2tar export image path [archive]
+       ext2tar import image [archive] [dest]
+  Writes path of the image and everything below it as a tar archive, or recreates the entries of
+  an archive inside dest, the root by default. The archive is standard input or output if left
+  out or given as -";
+
+fn export(image: &str, path: &str, archive: &str) -> Result<()> {
+  let fs = Fs::open_image(image)?;
+  let copied = match archive {
+    "-" => tar::export(&fs, path, BufWriter::new(io::stdout().lock()))?,
+    archive => tar::export(&fs, path, BufWriter::new(File::create(archive)?))?,
+  };
+  eprintln!("Archived {} files, {} directories and {} symbolic links", copied.files, copied.directories, copied.symlinks);
+  Ok(())
+}
+
+fn import(image: &str, archive: &str, dest: &str) -> Result<()> {
+  let mut fs = Fs::open_image(image)?;
+  let copied = match archive {
+    "-" => tar::import(&mut fs, BufReader::new(io::stdin().lock()), dest)?,
+    archive => tar::import(&mut fs, BufReader::new(File::open(archive)?), dest)?,
+  };
+  for skipped in copied.skipped.iter() {
+    eprintln!("Skipped {}", skipped);
+  }
+  eprintln!("Imported {} files, {} directories and {} symbolic links into {}", copied.files, copied.directories,
+            copied.symlinks, dest);
+  Ok(())
+}
+
+fn main() {
+  let args: Vec<_> = env::args().skip(1).collect();
+  let args: Vec<&str> = args.iter().map(String::as_str).collect();
+  let result = match args.as_slice() {
+    ["export", image, path] => export(image, path, "-"),
+    ["export", image, path, archive] => export(image, path, archive),
+    ["import", image] => import(image, "-", "/"),
+    ["import", image, archive] => import(image, archive, "/"),
+    ["import", image, archive, dest] => import(image, archive, dest),
+    _ => { eprintln!("{}", USAGE); std::process::exit(2) },
+  };
+  if let Err(why) = result {
+    eprintln!("ext2tar: {}", why);
+    std::process::exit(1);
+  }
+}
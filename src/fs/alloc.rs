@@ -0,0 +1,201 @@
+use crate::structure::*;
+use crate::Fs;
+
+use anyhow::{anyhow, Result};
+
+/// Blocks held back after the end of a file that keeps growing through a handle
+pub const PREALLOC_BLOCKS: usize = 8;
+
+// How scattered the files and the free space of an image are
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Fragmentation {
+  /// Files, directories and links with at least one block
+  pub files: usize,
+  /// Those of them not stored in a single run of blocks
+  pub fragmented_files: usize,
+  /// Runs of consecutive blocks over all files
+  pub extents: usize,
+  pub blocks: usize,
+  /// Runs of consecutive free blocks
+  pub free_extents: usize,
+  pub largest_free_extent: usize,
+  /// Inodes in use whose blocks could not be read, left out of the counts
+  pub corrupted: Vec<usize>,
+}
+
+impl Fragmentation {
+  /// Share of the extents beyond the one every file would ideally have, from 0 to 1
+  pub fn factor(&self) -> f64 {
+    if self.extents == 0 { 0.0 } else { (self.extents - self.files) as f64 / self.extents as f64 }
+  }
+}
+
+// Number of runs of consecutive blocks in `blocks`
+pub fn extents(blocks: &[usize]) -> usize {
+  blocks.iter().enumerate().filter(|&(i, &block)| i == 0 || block != blocks[i - 1] + 1).count()
+}
+
+// The block after the last one of `blocks` that is not a hole, where the file should go on
+pub fn goal(blocks: &[usize]) -> usize {
+  blocks.iter().rev().find(|&&block| block != HOLE).map_or(0, |last| last + 1)
+}
+
+// Block allocation. Blocks are handed out in as few runs as possible, starting at a goal block,
+// usually the one after the end of the file. Files growing through a handle also get a window of
+// blocks right after their end reserved in memory, so that nothing else lands there before they
+// are closed.
+impl Fs {
+  /// Finds `count` free blocks near `goal` in as few runs as possible, without taking them yet
+  pub(crate) fn find_blocks(&self, goal: usize, count: usize) -> Result<Vec<usize>> {
+    let mut unavailable = self.data_bitmap.merged(&self.reserved);
+    let mut blocks = Vec::with_capacity(count);
+    let mut goal = goal;
+    while blocks.len() < count {
+      let (start, run) = unavailable.find_free_run(goal, count - blocks.len())
+                                    .ok_or(anyhow!("Could not locate enough free datablocks"))?;
+      for ind in start..start + run {
+        unavailable.set(ind, true)?;
+        blocks.push(ind);
+      }
+      goal = start + run;
+    }
+    Ok(blocks)
+  }
+
+  pub(crate) fn take_blocks(&mut self, blocks: &[usize]) -> Result<()> {
+    for &ind in blocks {
+      self.data_bitmap.set(ind, true)?;
+    }
+    self.superblock.free_blocks_count -= blocks.len();
+    Ok(())
+  }
+
+  /// Frees `blocks`, skipping holes
+  pub(crate) fn release_blocks(&mut self, blocks: &[usize]) -> Result<()> {
+    for &ind in blocks.iter().filter(|&&ind| ind != HOLE) {
+      self.data_bitmap.set(ind, false)?;
+      self.superblock.free_blocks_count += 1;
+    }
+    Ok(())
+  }
+
+  /// Takes `count` blocks to grow the file `inode_ind` past `goal`, its window first,
+  /// and reserves a new window after them once it is used up
+  pub(crate) fn grow_blocks(&mut self, inode_ind: usize, goal: usize, count: usize) -> Result<Vec<usize>> {
+    let (window_start, window_len) = self.windows.get(&inode_ind).copied().unwrap_or((goal, 0));
+    let from_window = std::cmp::min(window_len, count);
+    let mut blocks: Vec<usize> = (window_start..window_start + from_window).collect();
+    let goal = blocks.last().map_or(goal, |last| last + 1);
+    blocks.extend(self.find_blocks(goal, count - from_window)?);
+    for ind in window_start..window_start + from_window {
+      self.reserved.set(ind, false)?;
+    }
+    self.windows.remove(&inode_ind);
+    if from_window < window_len {
+      self.windows.insert(inode_ind, (window_start + from_window, window_len - from_window));
+    }
+    self.take_blocks(&blocks)?;
+    if let (Some(&last), false) = (blocks.last(), self.windows.contains_key(&inode_ind)) {
+      self.reserve_window(inode_ind, last + 1)?;
+    }
+    Ok(blocks)
+  }
+
+  // Reserves up to `PREALLOC_BLOCKS` blocks from `start` on, if it is free
+  fn reserve_window(&mut self, inode_ind: usize, start: usize) -> Result<()> {
+    match self.data_bitmap.merged(&self.reserved).find_free_run(start, PREALLOC_BLOCKS) {
+      Some((found, run)) if found == start => {
+        for ind in start..start + run {
+          self.reserved.set(ind, true)?;
+        }
+        self.windows.insert(inode_ind, (start, run));
+      }
+      _ => {},
+    }
+    Ok(())
+  }
+
+  pub(crate) fn release_window(&mut self, inode_ind: usize) -> Result<()> {
+    if let Some((start, len)) = self.windows.remove(&inode_ind) {
+      for ind in start..start + len {
+        self.reserved.set(ind, false)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Counts the runs of blocks every file is stored in, and the runs of free blocks
+  pub fn fragmentation(&self) -> Result<Fragmentation> {
+    let mut stats = Fragmentation::default();
+    for inode_ind in (0..self.superblock.inodes_count).filter(|&ind| !self.inode_bitmap.free_at(ind)) {
+      let blocks = match self.read_inode(inode_ind).and_then(|inode| self.block_map(&inode)) {
+        Ok(blocks) => blocks,
+        Err(_) => { stats.corrupted.push(inode_ind); continue },
+      };
+      let blocks: Vec<usize> = blocks.into_iter().filter(|&block| block != HOLE).collect();
+      if blocks.is_empty() { continue };
+      let runs = extents(&blocks);
+      stats.files += 1;
+      stats.blocks += blocks.len();
+      stats.extents += runs;
+      if runs > 1 { stats.fragmented_files += 1 };
+    }
+    let mut from = 0;
+    while let Some(start) = self.data_bitmap.find_free_from(from) {
+      let run = self.data_bitmap.free_run(start, self.superblock.blocks_count);
+      stats.free_extents += 1;
+      stats.largest_free_extent = std::cmp::max(stats.largest_free_extent, run);
+      from = start + run;
+    }
+    Ok(stats)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::temp_fs;
+
+  fn blocks(fs: &Fs, path: &str) -> Vec<usize> {
+    let inode = fs.read_inode(fs.lookup(path).unwrap()).unwrap();
+    fs.block_map(&inode).unwrap()
+  }
+
+  #[test]
+  fn interleaved_growth_stays_contiguous() {
+    let (mut fs, _image) = temp_fs("alloc-interleaved", 0);
+    fs.create("/a", b"").unwrap();
+    fs.create("/b", b"").unwrap();
+    let (a, b) = (fs.open("/a").unwrap(), fs.open("/b").unwrap());
+    for i in 0..6 {
+      fs.write_at(a, i * BLOCK_SIZE, &[1; BLOCK_SIZE]).unwrap();
+      fs.write_at(b, i * BLOCK_SIZE, &[2; BLOCK_SIZE]).unwrap();
+    }
+    assert_eq!(extents(&blocks(&fs, "/a")), 1);
+    assert_eq!(extents(&blocks(&fs, "/b")), 1);
+    let free = fs.statfs().free_blocks;
+    fs.close(a).unwrap();
+    fs.close(b).unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+    assert_eq!(fs.reserved.count_free(), BLOCKS_COUNT);
+    assert_eq!(fs.read("/a").unwrap(), vec![1; 6 * BLOCK_SIZE]);
+    let stats = fs.fragmentation().unwrap();
+    assert_eq!((stats.fragmented_files, stats.extents, stats.blocks), (0, stats.files, fs.statfs().blocks - free - 1));
+  }
+
+  #[test]
+  fn runs_near_the_goal() {
+    let (mut fs, _image) = temp_fs("alloc-runs", 0);
+    fs.create("/hole", &[0; 3 * BLOCK_SIZE]).unwrap();
+    fs.create("/next", &[0; BLOCK_SIZE]).unwrap();
+    let hole = blocks(&fs, "/hole");
+    fs.unlink("/hole").unwrap();
+    assert_eq!(fs.find_blocks(0, 2).unwrap(), hole[..2].to_vec());
+    let big = fs.find_blocks(0, 5).unwrap();
+    assert_eq!(extents(&big), 1);
+    assert!(big[0] > hole[2]);
+    let stats = fs.fragmentation().unwrap();
+    assert_eq!(stats.free_extents, 2);
+  }
+}
@@ -0,0 +1,280 @@
+use crate::structure::*;
+use crate::Fs;
+
+use anyhow::{anyhow, Result};
+
+// Low-level access for inspecting and repairing images: raw inodes and blocks by number, who
+// owns a block, the bitmaps and the free counters. Nothing here checks that the image stays consistent.
+
+/// One of the two bitmaps of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapKind {
+  Blocks,
+  Inodes,
+}
+
+/// What a data block is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockUse {
+  /// Block number `logical` of the content of the inode
+  Data { inode: usize, logical: usize },
+  /// A node of the extent tree of the inode
+  Index { inode: usize },
+  /// Block 0, which stands for holes and is never handed out
+  Reserved,
+}
+
+impl Inode {
+  /// Sets the field `name`, or the block slot `direct[i]`, to `value`; modes are octal
+  pub fn set_field(&mut self, name: &str, value: &str) -> Result<()> {
+    let invalid = || anyhow!("Invalid value of {}: {}", name, value);
+    let number = |radix| usize::from_str_radix(value, radix).map_err(|_| invalid());
+    let flag = || match value { "0" | "false" => Ok(false), "1" | "true" => Ok(true), _ => Err(invalid()) };
+    if let Some(slot) = name.strip_prefix("direct[").and_then(|rest| rest.strip_suffix(']')) {
+      let slot: usize = slot.parse().ok().filter(|&slot| slot < INODE_LINKS).ok_or(anyhow!("No such block slot: {}", name))?;
+      self.direct[slot] = number(10)?;
+      return Ok(());
+    }
+    match name {
+      "size" => self.size = number(10)?,
+      "mtime" => self.mtime = number(10)? as u64,
+      "mode" => self.mode = number(8).ok().filter(|&mode| mode <= 0o7777).ok_or_else(invalid)? as u16,
+      "uid" => self.uid = value.parse().map_err(|_| invalid())?,
+      "gid" => self.gid = value.parse().map_err(|_| invalid())?,
+      "is_directory" => self.is_directory = flag()?,
+      "is_symlink" => self.is_symlink = flag()?,
+      "extents" => self.extents = flag()?,
+      "inline_data" => self.inline_data = flag()?,
+      "indexed" => self.indexed = flag()?,
+      _ => return Err(anyhow!("No such inode field: {}", name)),
+    }
+    Ok(())
+  }
+}
+
+impl Fs {
+  /// Opens the image at `filename` for repairs: like `open_image` it refuses files that are not images
+  /// and writes nothing, but the free counters may disagree with the bitmaps and the root may be missing
+  pub fn open_raw(filename: &str) -> Result<Self> {
+    Fs::read_image(filename)
+  }
+
+  pub fn superblock(&self) -> &Superblock { &self.superblock }
+
+  /// Sets a free counter of the superblock, `free_blocks_count` or `free_inodes_count`, and writes
+  /// it out; the layout of the image cannot be changed this way
+  pub fn set_superblock_field(&mut self, name: &str, value: &str) -> Result<()> {
+    let (counter, limit) = match name {
+      "free_blocks_count" => (&mut self.superblock.free_blocks_count, self.superblock.blocks_count),
+      "free_inodes_count" => (&mut self.superblock.free_inodes_count, self.superblock.inodes_count),
+      _ => return Err(anyhow!("No such superblock counter: {}", name)),
+    };
+    *counter = value.parse().ok().filter(|&count| count <= limit).ok_or(anyhow!("Invalid value of {}: {}", name, value))?;
+    self.dump_superblock()
+  }
+
+  fn check_inode(&self, inode_ind: usize) -> Result<()> {
+    if inode_ind >= self.superblock.inodes_count { return Err(anyhow!("No such inode: {}", inode_ind)) };
+    Ok(())
+  }
+
+  fn check_block(&self, block: usize) -> Result<()> {
+    if block >= self.superblock.blocks_count { return Err(anyhow!("No such block: {}", block)) };
+    Ok(())
+  }
+
+  /// The inode `inode_ind` as it is on disk, whether it is in use or not
+  pub fn raw_inode(&self, inode_ind: usize) -> Result<Inode> {
+    self.check_inode(inode_ind)?;
+    self.read_inode(inode_ind)
+  }
+
+  /// Overwrites the inode `inode_ind` on disk, leaving the bitmaps alone
+  pub fn write_raw_inode(&mut self, inode_ind: usize, inode: &Inode) -> Result<()> {
+    self.check_inode(inode_ind)?;
+    self.update_inode(inode_ind, inode)
+  }
+
+  /// Data blocks of the inode in order, `HOLE` for holes, and then those of its extent tree
+  pub fn inode_blocks(&self, inode_ind: usize) -> Result<(Vec<usize>, Vec<usize>)> {
+    let inode = self.raw_inode(inode_ind)?;
+    Ok((self.block_map(&inode)?, self.index_blocks(&inode)?))
+  }
+
+  /// Content of the data block `block`
+  pub fn raw_block(&self, block: usize) -> Result<Vec<u8>> {
+    self.check_block(block)?;
+    Ok(self.storage.read(self.superblock.data_blocks + block * self.superblock.block_size, self.superblock.block_size)?)
+  }
+
+  /// What the data block `block` is used for, going through every inode in use; none if nothing
+  /// refers to it. Also gives the inodes skipped on the way because their blocks could not be read
+  pub fn block_owner(&self, block: usize) -> Result<(Option<BlockUse>, Vec<usize>)> {
+    self.check_block(block)?;
+    if block == HOLE { return Ok((Some(BlockUse::Reserved), vec![])) };
+    let mut corrupted = vec![];
+    for inode_ind in (0..self.superblock.inodes_count).filter(|&ind| !self.inode_bitmap.free_at(ind)) {
+      let (blocks, nodes) = match self.inode_blocks(inode_ind) {
+        Ok(blocks) => blocks,
+        Err(_) => { corrupted.push(inode_ind); continue },
+      };
+      if let Some(logical) = blocks.iter().position(|&data| data == block) {
+        return Ok((Some(BlockUse::Data { inode: inode_ind, logical }), corrupted));
+      }
+      if nodes.contains(&block) { return Ok((Some(BlockUse::Index { inode: inode_ind }), corrupted)) };
+    }
+    Ok((None, corrupted))
+  }
+
+  /// Start and length of every run of free bits in the bitmap
+  pub fn free_runs(&self, kind: BitmapKind) -> Vec<(usize, usize)> {
+    let (bits, count) = match kind {
+      BitmapKind::Blocks => (self.data_bitmap.as_bytes(), self.superblock.blocks_count),
+      BitmapKind::Inodes => (self.inode_bitmap.as_bytes(), self.superblock.inodes_count),
+    };
+    let bitmap = DataBitmap::from_bytes(bits.to_vec());
+    let mut runs = vec![];
+    let mut from = 0;
+    while let Some(start) = bitmap.find_free_from(from).filter(|&start| start < count) {
+      let len = bitmap.free_run(start, count - start);
+      runs.push((start, len));
+      from = start + len;
+    }
+    runs
+  }
+
+  /// Marks the bit `ind` of the bitmap taken or free, keeping the free count of the superblock in
+  /// step, and writes both out
+  pub fn set_bit(&mut self, kind: BitmapKind, ind: usize, taken: bool) -> Result<()> {
+    match kind {
+      BitmapKind::Blocks => {
+        self.check_block(ind)?;
+        if self.data_bitmap.free_at(ind) != taken { return Ok(()) };
+        self.data_bitmap.set(ind, taken)?;
+        self.superblock.free_blocks_count = if taken { self.superblock.free_blocks_count.saturating_sub(1) } else { self.superblock.free_blocks_count + 1 };
+        self.dump_data_bitmap()?;
+      }
+      BitmapKind::Inodes => {
+        self.check_inode(ind)?;
+        if self.inode_bitmap.free_at(ind) != taken { return Ok(()) };
+        self.inode_bitmap.set(ind, taken)?;
+        self.superblock.free_inodes_count = if taken { self.superblock.free_inodes_count.saturating_sub(1) } else { self.superblock.free_inodes_count + 1 };
+        self.dump_inode_bitmap()?;
+      }
+    }
+    self.dump_superblock()
+  }
+}
+
+/// `bytes` the way `hexdump -C` shows them, with runs of identical lines collapsed into `*`
+pub fn hexdump(bytes: &[u8]) -> String {
+  let mut out = String::new();
+  let mut previous: Option<&[u8]> = None;
+  let mut collapsed = false;
+  for (i, line) in bytes.chunks(16).enumerate() {
+    if previous == Some(line) {
+      if !collapsed { out.push_str("*\n") };
+      collapsed = true;
+      continue;
+    }
+    previous = Some(line);
+    collapsed = false;
+    let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let (left, right) = hex.split_at(std::cmp::min(8, hex.len()));
+    let text: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+    out.push_str(&format!("{:08x}  {:<23}  {:<23}  |{}|\n", i * 16, left.join(" "), right.join(" "), text));
+  }
+  out.push_str(&format!("{:08x}\n", bytes.len()));
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::temp_fs;
+
+  #[test]
+  fn inspect_and_repair() {
+    let (mut fs, _image) = temp_fs("debug", 0);
+    fs.create("/file", &[7; 3000]).unwrap();
+    let inode = fs.lookup("/file").unwrap();
+    let (blocks, nodes) = fs.inode_blocks(inode).unwrap();
+    assert_eq!((blocks.len(), nodes.len()), (3, 0));
+    assert_eq!(fs.block_owner(blocks[2]).unwrap(), (Some(BlockUse::Data { inode, logical: 2 }), vec![]));
+    assert_eq!(fs.block_owner(HOLE).unwrap(), (Some(BlockUse::Reserved), vec![]));
+    assert_eq!(fs.raw_block(blocks[0]).unwrap(), vec![7; 1024]);
+    assert!(fs.raw_block(fs.superblock().blocks_count).is_err());
+
+    let free = fs.statfs().free_blocks;
+    let runs = fs.free_runs(BitmapKind::Blocks);
+    assert_eq!(runs.iter().map(|(_, len)| len).sum::<usize>(), free);
+    let (start, _) = runs[0];
+    fs.set_bit(BitmapKind::Blocks, start, true).unwrap();
+    assert_eq!(fs.statfs().free_blocks, free - 1);
+    assert_eq!(fs.free_runs(BitmapKind::Blocks)[0].0, start + 1);
+    fs.set_bit(BitmapKind::Blocks, start, false).unwrap();
+    fs.set_bit(BitmapKind::Blocks, start, false).unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+
+    let mut raw = fs.raw_inode(inode).unwrap();
+    raw.set_field("size", "5").unwrap();
+    raw.set_field("mode", "600").unwrap();
+    assert!(raw.set_field("mode", "99").is_err());
+    assert!(raw.set_field("direct[12]", "1").is_err());
+    fs.write_raw_inode(inode, &raw).unwrap();
+    assert_eq!(fs.read("/file").unwrap(), vec![7; 5]);
+    assert_eq!(fs.stat("/file").unwrap().mode, Some(0o600));
+  }
+
+  #[test]
+  fn scans_skip_corrupted_inodes() {
+    let (mut fs, _image) = temp_fs("debug-corrupted", 0);
+    fs.create("/bad", &[1; 2048]).unwrap();
+    fs.create("/good", &[2; 1024]).unwrap();
+    let (bad, good) = (fs.lookup("/bad").unwrap(), fs.lookup("/good").unwrap());
+    let block = fs.inode_blocks(good).unwrap().0[0];
+    let mut raw = fs.raw_inode(bad).unwrap();
+    raw.set_field("size", &(INODE_LINKS * 1024 + 1).to_string()).unwrap();
+    fs.write_raw_inode(bad, &raw).unwrap();
+    assert!(fs.inode_blocks(bad).is_err());
+
+    assert_eq!(fs.block_owner(block).unwrap(), (Some(BlockUse::Data { inode: good, logical: 0 }), vec![bad]));
+    let layout = fs.layout().unwrap();
+    assert_eq!(layout.corrupted, vec![bad]);
+    assert!(layout.files.iter().any(|file| file.inode == good && file.blocks == 1));
+    let stats = fs.fragmentation().unwrap();
+    assert_eq!((stats.files, stats.corrupted.clone()), (2, vec![bad]));
+  }
+
+  #[test]
+  fn repair_counters() {
+    let (mut fs, image) = temp_fs("debug-counters", 0);
+    let free = fs.statfs().free_blocks;
+    fs.set_superblock_field("free_blocks_count", "3").unwrap();
+    assert!(fs.set_superblock_field("free_inodes_count", "100000").is_err());
+    assert!(fs.set_superblock_field("blocks_count", "8").is_err());
+    drop(fs);
+    let before = std::fs::read(image.path()).unwrap();
+    assert!(Fs::open_image(image.as_str()).is_err());
+    let mut fs = Fs::open_raw(image.as_str()).unwrap();
+    assert_eq!(std::fs::read(image.path()).unwrap(), before);
+    assert_eq!(fs.free_runs(BitmapKind::Blocks).iter().map(|(_, len)| len).sum::<usize>(), free);
+    fs.set_superblock_field("free_blocks_count", &free.to_string()).unwrap();
+    drop(fs);
+    assert_eq!(Fs::open_image(image.as_str()).unwrap().statfs().free_blocks, free);
+  }
+
+  #[test]
+  fn hexdumps() {
+    let mut bytes = b"0123456789abcdef".to_vec();
+    bytes.extend(vec![0; 40]);
+    assert_eq!(hexdump(&bytes), "\
+00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|
+00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+*
+00000030  00 00 00 00 00 00 00 00                           |........|
+00000038
+");
+  }
+}
@@ -0,0 +1,147 @@
+use crate::alloc::extents;
+use crate::structure::*;
+use crate::Fs;
+
+use anyhow::Result;
+
+// Offline defragmentation. Every file stored in more than one run of blocks moves to the first
+// free run long enough for all of it, going through the inodes in order. A file moves in three
+// steps: its blocks are copied and the copies taken on disk, the inode is pointed at them, and
+// only then are the old blocks freed. Stopping at any point leaves every file whole, at worst
+// with a few blocks taken that nothing refers to.
+
+/// What a defragmentation did, or would do on a dry run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Defragmented {
+  /// Files moved into a single run of blocks
+  pub files: usize,
+  pub blocks: usize,
+  /// Runs of consecutive blocks over all files, before and after
+  pub extents_before: usize,
+  pub extents_after: usize,
+  /// Fragmented files left alone, as they are open or no free run is long enough for them
+  pub skipped: usize,
+}
+
+impl Fs {
+  /// Moves every fragmented file into a single run of blocks, or only works out what that
+  /// would give if `dry_run`
+  pub fn defragment(&mut self, dry_run: bool) -> Result<Defragmented> {
+    let extents_before = self.fragmentation()?.extents;
+    let mut report = Defragmented { extents_before, extents_after: extents_before, ..Default::default() };
+    let open: Vec<usize> = self.handles.values().copied().collect();
+    // Blocks as they would be after the moves so far
+    let mut unavailable = self.data_bitmap.merged(&self.reserved);
+    for inode_ind in ROOT_INODE..self.superblock.inodes_count {
+      if self.inode_bitmap.free_at(inode_ind) { continue };
+      let inode = self.read_inode(inode_ind)?;
+      let blocks = self.block_map(&inode)?;
+      let data: Vec<usize> = blocks.iter().copied().filter(|&block| block != HOLE).collect();
+      let runs = extents(&data);
+      if runs < 2 { continue };
+      let start = match unavailable.find_free_run(0, data.len()) {
+        Some((start, len)) if len == data.len() && !open.contains(&inode_ind) => start,
+        _ => { report.skipped += 1; continue },
+      };
+      if dry_run {
+        for ind in start..start + data.len() { unavailable.set(ind, true)? };
+        for &ind in data.iter().chain(self.index_blocks(&inode)?.iter()) { unavailable.set(ind, false)? };
+      } else {
+        let mut next = start..start + data.len();
+        let moved: Vec<usize> = blocks.iter().map(|&block| if block == HOLE { HOLE } else { next.next().unwrap() }).collect();
+        self.atomically(|fs| fs.move_blocks(inode_ind, inode, &blocks, &moved))?;
+        unavailable = self.data_bitmap.merged(&self.reserved);
+      }
+      report.files += 1;
+      report.blocks += data.len();
+      report.extents_after -= runs - 1;
+    }
+    Ok(report)
+  }
+
+  /// Copies the blocks of the file `moved` puts elsewhere, `moved` being its new block map, and
+  /// points the inode at the copies
+  pub(crate) fn move_blocks(&mut self, inode_ind: usize, mut inode: Inode, blocks: &[usize], moved: &[usize]) -> Result<()> {
+    let block_size = self.superblock.block_size;
+    let (mut copies, mut old) = (vec![], vec![]);
+    for (&from, &to) in blocks.iter().zip(moved).filter(|(from, to)| from != to) {
+      let content = self.storage.read(self.superblock.data_blocks + from * block_size, block_size)?;
+      self.storage.write(self.superblock.data_blocks + to * block_size, &content)?;
+      copies.push(to);
+      old.push(from);
+    }
+    self.take_blocks(&copies)?;
+    // The old extent tree stays taken until the inode no longer refers to it, so that the new
+    // tree does not go over it
+    let old_nodes = self.index_blocks(&inode)?;
+    self.set_block_map(&mut inode, moved)?;
+    self.dump_data_bitmap()?;
+    self.dump_superblock()?;
+    self.storage.sync()?;
+    self.update_inode(inode_ind, &inode)?;
+    self.storage.sync()?;
+    self.release_blocks(&old)?;
+    self.release_blocks(&old_nodes)?;
+    self.dump_data_bitmap()?;
+    self.dump_superblock()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::TempPath;
+
+  fn pattern(blocks: usize) -> Vec<u8> {
+    (0..blocks * 1024).map(|i| (i / 1024 + i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn scattered_files_become_contiguous() {
+    let path = TempPath::new("defrag.img");
+    let mut fs = Fs::format_with(path.as_str(), Superblock::new(1024, 64, 128, INCOMPAT_EXTENTS).unwrap()).unwrap();
+    fs.mkdir("/fill").unwrap();
+    let mut count = 0;
+    while fs.create(&format!("/fill/{}", count), &[1; 1024]).is_ok() { count += 1 };
+    for i in (0..count).step_by(2) { fs.unlink(&format!("/fill/{}", i)).unwrap() };
+    // Ten blocks in as many holes, more extents than the inode holds
+    fs.create("/scattered", &pattern(10)).unwrap();
+    for i in (1..count).step_by(2) { fs.unlink(&format!("/fill/{}", i)).unwrap() };
+    let inode = fs.read_inode(fs.lookup("/scattered").unwrap()).unwrap();
+    assert_eq!(extents(&fs.block_map(&inode).unwrap()), 10);
+    let nodes = fs.index_blocks(&inode).unwrap().len();
+    assert!(nodes > 0);
+    let free = fs.statfs().free_blocks;
+
+    let planned = fs.defragment(true).unwrap();
+    assert_eq!((planned.files, planned.blocks, planned.skipped), (1, 10, 0));
+    assert_eq!(planned.extents_after, planned.extents_before - 9);
+    assert_eq!(fs.statfs().free_blocks, free);
+
+    let done = fs.defragment(false).unwrap();
+    assert_eq!(done, planned);
+    assert_eq!(fs.fragmentation().unwrap().extents, done.extents_after);
+    assert_eq!(fs.read("/scattered").unwrap(), pattern(10));
+    assert_eq!(fs.statfs().free_blocks, free + nodes);
+    assert_eq!(fs.defragment(false).unwrap().files, 0);
+
+    // Nothing is left over on disk either
+    drop(fs);
+    let fs = Fs::open_image(path.as_str()).unwrap();
+    assert_eq!(fs.read("/scattered").unwrap(), pattern(10));
+    assert_eq!(fs.statfs().free_blocks, free + nodes);
+  }
+
+  #[test]
+  fn files_without_room_stay() {
+    let path = TempPath::new("defrag-full.img");
+    let mut fs = Fs::format_with(path.as_str(), Superblock::new(1024, 16, 16, 0).unwrap()).unwrap();
+    for i in 0..12 { fs.create(&format!("/{}", i), &[1; 1024]).unwrap() };
+    for i in (0..12).step_by(2) { fs.unlink(&format!("/{}", i)).unwrap() };
+    fs.create("/scattered", &pattern(6)).unwrap();
+    let report = fs.defragment(false).unwrap();
+    assert_eq!((report.files, report.skipped), (0, 1));
+    assert_eq!(fs.read("/scattered").unwrap(), pattern(6));
+  }
+}
@@ -0,0 +1,301 @@
+use crate::filesystem::FileType;
+use crate::structure::*;
+use crate::{now, Fs};
+
+use std::convert::TryInto;
+use anyhow::{anyhow, Result};
+
+/// Bytes before the name in a record: inode, record length, name length and file type
+const RECORD_HEADER: usize = 8;
+
+// An entry of a directory as stored in its records, `.` and `..` included
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirRecord {
+  pub inode: usize,
+  pub file_type: FileType,
+  pub name: String,
+}
+
+// Where a record sits in its block, and what it holds unless it is unused
+pub(crate) struct Slot {
+  pub offset: usize,
+  pub rec_len: usize,
+  pub record: Option<DirRecord>,
+}
+
+// Bytes a record with a name of `name_len` bytes takes, rounded up to 4 like in ext2
+pub(crate) fn record_len(name_len: usize) -> usize {
+  (RECORD_HEADER + name_len + 3) & !3
+}
+
+fn type_code(file_type: FileType) -> u8 {
+  match file_type {
+    FileType::File => 1,
+    FileType::Directory => 2,
+    FileType::Symlink => 7,
+  }
+}
+
+fn file_type(code: u8) -> Result<FileType> {
+  match code {
+    1 => Ok(FileType::File),
+    2 => Ok(FileType::Directory),
+    7 => Ok(FileType::Symlink),
+    _ => Err(anyhow!("Unknown file type in directory record: {}", code)),
+  }
+}
+
+pub(crate) fn parse(block: &[u8]) -> Result<Vec<Slot>> {
+  let mut slots = vec![];
+  let mut offset = 0;
+  while offset < block.len() {
+    let header = block.get(offset..offset + RECORD_HEADER).ok_or(anyhow!("Corrupted directory record at {}", offset))?;
+    let inode = u32::from_le_bytes(header[0..4].try_into()?) as usize;
+    let rec_len = u16::from_le_bytes(header[4..6].try_into()?) as usize;
+    let name_len = header[6] as usize;
+    if rec_len < record_len(name_len) || offset + rec_len > block.len() {
+      return Err(anyhow!("Corrupted directory record at {}", offset))
+    }
+    let record = if inode == UNUSED_INODE { None } else {
+      let name = &block[offset + RECORD_HEADER..offset + RECORD_HEADER + name_len];
+      Some(DirRecord { inode, file_type: file_type(header[7])?, name: String::from_utf8_lossy(name).into_owned() })
+    };
+    slots.push(Slot { offset, rec_len, record });
+    offset += rec_len;
+  }
+  Ok(slots)
+}
+
+fn write_record(block: &mut [u8], offset: usize, rec_len: usize, record: Option<&DirRecord>) {
+  let (inode, name, code) = record.map_or((UNUSED_INODE, "", 0), |record| (record.inode, &record.name, type_code(record.file_type)));
+  block[offset..offset + 4].copy_from_slice(&(inode as u32).to_le_bytes());
+  block[offset + 4..offset + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+  block[offset + 6] = name.len() as u8;
+  block[offset + 7] = code;
+  block[offset + RECORD_HEADER..offset + RECORD_HEADER + name.len()].copy_from_slice(name.as_bytes());
+}
+
+// Puts `record` into an unused record or the slack after a used one, if either is long enough
+pub(crate) fn insert(block: &mut [u8], record: &DirRecord) -> Result<bool> {
+  let needed = record_len(record.name.len());
+  for slot in parse(block)? {
+    match &slot.record {
+      None if slot.rec_len >= needed => {
+        write_record(block, slot.offset, slot.rec_len, Some(record));
+        return Ok(true);
+      }
+      Some(used) if slot.rec_len - record_len(used.name.len()) >= needed => {
+        let used_len = record_len(used.name.len());
+        write_record(block, slot.offset, used_len, Some(used));
+        write_record(block, slot.offset + used_len, slot.rec_len - used_len, Some(record));
+        return Ok(true);
+      }
+      _ => {},
+    }
+  }
+  Ok(false)
+}
+
+// Takes the record of `name` out, giving its space to the record before or marking it unused
+pub(crate) fn remove(block: &mut [u8], name: &str) -> Result<Option<DirRecord>> {
+  let slots = parse(block)?;
+  let found = slots.iter().position(|slot| slot.record.as_ref().is_some_and(|record| record.name == name));
+  let i = match found { Some(i) => i, None => return Ok(None) };
+  let (slot, record) = (&slots[i], slots[i].record.clone());
+  if i == 0 {
+    write_record(block, slot.offset, slot.rec_len, None);
+  } else {
+    let before = &slots[i - 1];
+    write_record(block, before.offset, before.rec_len + slot.rec_len, before.record.as_ref());
+  }
+  Ok(record)
+}
+
+// A block of `len` bytes holding `records`, the last one stretching to the end
+pub(crate) fn new_block(len: usize, records: &[DirRecord]) -> Vec<u8> {
+  let mut block = vec![0; len];
+  let mut offset = 0;
+  for (i, record) in records.iter().enumerate() {
+    let rec_len = if i + 1 == records.len() { len - offset } else { record_len(record.name.len()) };
+    write_record(&mut block, offset, rec_len, Some(record));
+    offset += rec_len;
+  }
+  block
+}
+
+// Directories are made of blocks of ext2 records: inode, record length, name length, file type
+// and name. Every block is covered by its records, each stretching over the unused space after
+// it, so an entry is added into the slack of a record and removed by merging it into the one
+// before. Only the block that changes is written. Directories with inline data have a single
+// block of `INLINE_DATA_MAX` bytes, which moves to a real block once it is full. Directories
+// outgrowing their first block get a hash index, see `htree`.
+impl Fs {
+  fn dir_block_size(&self, dir: &Inode) -> usize {
+    if dir.inline_data { INLINE_DATA_MAX } else { self.superblock.block_size }
+  }
+
+  /// Content of a new directory `dir_ind` inside `parent_ind`
+  pub(crate) fn new_dir_content(&self, dir_ind: usize, parent_ind: usize) -> Vec<u8> {
+    let inline = self.superblock.feature_incompat & INCOMPAT_INLINE_DATA != 0;
+    let len = if inline { INLINE_DATA_MAX } else { self.superblock.block_size };
+    new_block(len, &[
+      DirRecord { inode: dir_ind, file_type: FileType::Directory, name: ".".to_owned() },
+      DirRecord { inode: parent_ind, file_type: FileType::Directory, name: "..".to_owned() },
+    ])
+  }
+
+  /// Every entry of the directory in order, `.` and `..` included
+  pub(crate) fn dir_records(&self, dir: &Inode) -> Result<Vec<DirRecord>> {
+    let mut records = vec![];
+    for block in self.read_bytes(dir)?.chunks(self.dir_block_size(dir)) {
+      records.extend(parse(block)?.into_iter().filter_map(|slot| slot.record));
+    }
+    Ok(records)
+  }
+
+  pub(crate) fn find_record(&self, dir: &Inode, name: &str) -> Result<Option<DirRecord>> {
+    if dir.indexed { return self.dx_find_record(dir, name) };
+    Ok(self.dir_records(dir)?.into_iter().find(|record| record.name == name))
+  }
+
+  /// The `i`-th block of a directory with blocks
+  pub(crate) fn read_dir_block(&self, dir: &Inode, i: usize) -> Result<Vec<u8>> {
+    if i >= self.block_count(dir)? { return Err(anyhow!("Directory block {} out of range", i)) };
+    let ind = self.blocks_in(dir, i..i + 1)?[0];
+    Ok(self.storage.read(self.superblock.data_blocks + ind * self.superblock.block_size, self.superblock.block_size)?)
+  }
+
+  pub(crate) fn write_dir_block(&mut self, dir: &mut Inode, i: usize, block: &[u8]) -> Result<()> {
+    if dir.inline_data {
+      dir.set_inline_bytes(block);
+      return Ok(());
+    }
+    let ind = self.blocks_in(dir, i..i + 1)?[0];
+    self.storage.write(self.superblock.data_blocks + ind * self.superblock.block_size, block)?;
+    Ok(())
+  }
+
+  /// Adds `block` at the end of the directory, returning its number there. A directory that
+  /// outgrows the direct slots of its inode moves its blocks to an extent tree, turning on the
+  /// extents feature of the image if it is off, so that an index can grow past `INODE_LINKS` blocks.
+  pub(crate) fn append_dir_block(&mut self, dir: &mut Inode, block: &[u8]) -> Result<usize> {
+    let count = self.block_count(dir)?;
+    if !dir.extents && count == INODE_LINKS {
+      let blocks = self.block_map(dir)?;
+      self.superblock.feature_incompat |= INCOMPAT_EXTENTS;
+      dir.extents = true;
+      self.set_block_map(dir, &blocks)?;
+    }
+    let found = self.find_blocks(self.goal_before(dir, count)?, 1)?;
+    self.take_blocks(&found)?;
+    self.map_blocks(dir, count, &found)?;
+    dir.size += self.superblock.block_size;
+    self.write_dir_block(dir, count, block)?;
+    self.dump_data_bitmap()?;
+    self.dump_superblock()?;
+    Ok(count)
+  }
+
+  /// Adds `record` to the first block with room for it, or to a new block at the end
+  pub(crate) fn add_record(&mut self, dir_ind: usize, dir: &mut Inode, record: &DirRecord) -> Result<()> {
+    if dir.indexed { return self.dx_add_record(dir_ind, dir, record) };
+    let content = self.read_bytes(dir)?;
+    let mut added = false;
+    for (i, block) in content.chunks(self.dir_block_size(dir)).enumerate() {
+      let mut block = block.to_vec();
+      if insert(&mut block, record)? {
+        self.write_dir_block(dir, i, &block)?;
+        added = true;
+        break;
+      }
+    }
+    if !added && dir.inline_data {
+      // The records move to a block of their own, the last one stretching over the new space
+      let mut block = content;
+      let last = parse(&block)?.pop().ok_or(anyhow!("Empty directory: inode {}", dir_ind))?;
+      let rec_len = last.rec_len + self.superblock.block_size - block.len();
+      block.resize(self.superblock.block_size, 0);
+      write_record(&mut block, last.offset, rec_len, last.record.as_ref());
+      insert(&mut block, record)?;
+      self.update_bytes(dir, &block)?;
+    } else if !added && content.len() == self.superblock.block_size {
+      return self.make_indexed(dir_ind, dir, record);
+    } else if !added {
+      self.append_dir_block(dir, &new_block(self.superblock.block_size, std::slice::from_ref(record)))?;
+    }
+    dir.mtime = now();
+    self.update_inode(dir_ind, dir)
+  }
+
+  /// Removes the entry `name`, returning what it pointed to
+  pub(crate) fn remove_record(&mut self, dir_ind: usize, dir: &mut Inode, name: &str) -> Result<DirRecord> {
+    if dir.indexed { return self.dx_remove_record(dir_ind, dir, name) };
+    let content = self.read_bytes(dir)?;
+    for (i, block) in content.chunks(self.dir_block_size(dir)).enumerate() {
+      let mut block = block.to_vec();
+      if let Some(record) = remove(&mut block, name)? {
+        self.write_dir_block(dir, i, &block)?;
+        dir.mtime = now();
+        self.update_inode(dir_ind, dir)?;
+        return Ok(record);
+      }
+    }
+    Err(anyhow!("Unknown filename: {}", name))
+  }
+
+  /// Points the entry `name` of the directory `dir_ind` at `inode_ind`, rewriting the record in place
+  pub(crate) fn repoint_record(&mut self, dir_ind: usize, name: &str, inode_ind: usize) -> Result<()> {
+    let mut dir = self.read_dir_inode(dir_ind)?;
+    let content = self.read_bytes(&dir)?;
+    for (i, block) in content.chunks(self.dir_block_size(&dir)).enumerate() {
+      let mut block = block.to_vec();
+      let found = parse(&block)?.into_iter()
+        .find(|slot| slot.record.as_ref().is_some_and(|record| record.name == name));
+      if let Some(Slot { offset, rec_len, record: Some(record) }) = found {
+        write_record(&mut block, offset, rec_len, Some(&DirRecord { inode: inode_ind, ..record }));
+        self.write_dir_block(&mut dir, i, &block)?;
+        return self.update_inode(dir_ind, &dir);
+      }
+    }
+    Err(anyhow!("Unknown filename: {}", name))
+  }
+
+  /// Points the `..` entry of the directory `dir_ind` at `parent_ind`
+  pub(crate) fn set_parent_record(&mut self, dir_ind: usize, parent_ind: usize) -> Result<()> {
+    self.repoint_record(dir_ind, "..", parent_ind)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(inode: usize, name: &str) -> DirRecord {
+    DirRecord { inode, file_type: FileType::File, name: name.to_owned() }
+  }
+
+  fn names(block: &[u8]) -> Vec<String> {
+    parse(block).unwrap().into_iter().filter_map(|slot| slot.record.map(|record| record.name)).collect()
+  }
+
+  #[test]
+  fn records_in_a_block() {
+    let mut block = new_block(64, &[record(1, "."), record(1, "..")]);
+    assert!(insert(&mut block, &record(2, "first")).unwrap());
+    assert!(insert(&mut block, &record(3, "second")).unwrap());
+    assert_eq!(names(&block), vec![".", "..", "first", "second"]);
+    // 12 + 12 + 16 + 16 bytes are taken, the last 8 are too few for one more name
+    assert!(!insert(&mut block, &record(4, "x")).unwrap());
+    assert_eq!(remove(&mut block, "first").unwrap(), Some(record(2, "first")));
+    assert_eq!(remove(&mut block, "first").unwrap(), None);
+    assert!(insert(&mut block, &record(4, "third")).unwrap());
+    assert_eq!(names(&block), vec![".", "..", "third", "second"]);
+
+    let mut block = new_block(32, &[record(5, "only")]);
+    remove(&mut block, "only").unwrap();
+    assert!(names(&block).is_empty());
+    assert!(insert(&mut block, &record(6, "again")).unwrap());
+    assert_eq!(parse(&block).unwrap()[0].rec_len, 32);
+    assert!(parse(&[0; 16]).is_err());
+  }
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+  File,
+  Directory,
+  Symlink,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+  pub inode: usize,
+  pub file_type: FileType,
+  pub size: usize,
+  /// Bytes taken by the blocks of the entry, less than `size` for files with holes
+  pub allocated: usize,
+  /// Permission bits, if the backend keeps them
+  pub mode: Option<u16>,
+  /// Owner and group ids, if the backend keeps them
+  pub owner: Option<(u32, u32)>,
+  /// Last modification, in seconds since the Unix epoch
+  pub mtime: u64,
+}
+
+// An entry of a directory together with the metadata of the inode it points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+  pub name: String,
+  pub inode: usize,
+  pub file_type: FileType,
+  pub size: usize,
+  pub allocated: usize,
+  pub mode: Option<u16>,
+  pub owner: Option<(u32, u32)>,
+  pub mtime: u64,
+}
+
+impl DirEntry {
+  pub fn new(name: &str, meta: Metadata) -> Self {
+    DirEntry {
+      name: name.to_owned(), inode: meta.inode, file_type: meta.file_type, size: meta.size,
+      allocated: meta.allocated, mode: meta.mode, owner: meta.owner, mtime: meta.mtime,
+    }
+  }
+}
+
+// Space usage of a whole file system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statfs {
+  pub block_size: usize,
+  pub blocks: usize,
+  pub free_blocks: usize,
+  pub inodes: usize,
+  pub free_inodes: usize,
+  /// Longest file name in bytes
+  pub name_max: usize,
+}
+
+// What `Filesystem::seek` looks for, like SEEK_DATA and SEEK_HOLE of lseek(2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+  Data,
+  Hole,
+}
+
+// An open regular file, see `Filesystem::open`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle(pub usize);
+
+// Operations every file system backend provides. All paths are absolute,
+// see `path::resolve` for turning user input into one.
+pub trait Filesystem {
+  /// Finds the inode number of the entry at `path`, following symbolic links
+  fn lookup(&self, path: &str) -> Result<usize>;
+
+  fn stat(&self, path: &str) -> Result<Metadata>;
+
+  /// Like `stat`, but describes a symbolic link itself rather than what it points to
+  fn lstat(&self, path: &str) -> Result<Metadata>;
+
+  /// Lists the entries of the directory at `path` in directory order, without `.` and `..`
+  fn readdir(&self, path: &str) -> Result<Vec<DirEntry>>;
+
+  /// Reads the whole content of the regular file at `path`
+  fn read(&self, path: &str) -> Result<Vec<u8>>;
+
+  /// Replaces the content of an existing regular file
+  fn write(&mut self, path: &str, content: &[u8]) -> Result<()>;
+
+  /// Creates a new regular file, failing if `path` is already taken
+  fn create(&mut self, path: &str, content: &[u8]) -> Result<()>;
+
+  /// Opens the regular file at `path` for reading and writing at any offset
+  fn open(&mut self, path: &str) -> Result<Handle>;
+
+  /// Reads up to `size` bytes from `offset` on, fewer at the end of the file
+  fn read_at(&self, handle: Handle, offset: usize, size: usize) -> Result<Vec<u8>>;
+
+  /// Writes `data` at `offset`, growing the file if it ends there or earlier; a gap reads as zeros
+  fn write_at(&mut self, handle: Handle, offset: usize, data: &[u8]) -> Result<()>;
+
+  /// Offset of the first data or hole at `offset` or after it, where the end of the file counts
+  /// as a hole. Nothing is found from the end of the file on, or for data past the last block.
+  fn seek(&self, handle: Handle, offset: usize, whence: Whence) -> Result<Option<usize>>;
+
+  /// Frees the blocks of the regular file at `path` between `offset` and `offset + len`,
+  /// which read as zeros afterwards. The size of the file stays the same.
+  fn punch_hole(&mut self, path: &str, offset: usize, len: usize) -> Result<()>;
+
+  /// Releases the handle together with whatever was reserved for the file while it was open
+  fn close(&mut self, handle: Handle) -> Result<()>;
+
+  /// Creates an empty regular file, or updates the modification time of an existing entry
+  fn touch(&mut self, path: &str) -> Result<()>;
+
+  /// Sets the modification time of the entry at `path`, of a symbolic link itself rather than its target
+  fn set_mtime(&mut self, path: &str, mtime: u64) -> Result<()>;
+
+  /// Sets the permission bits of the entry at `path`, of a symbolic link itself rather than its target
+  fn set_mode(&mut self, path: &str, mode: u16) -> Result<()>;
+
+  /// Sets the owner and group of the entry at `path`, of a symbolic link itself rather than its target
+  fn set_owner(&mut self, path: &str, uid: u32, gid: u32) -> Result<()>;
+
+  fn mkdir(&mut self, path: &str) -> Result<()>;
+
+  /// Creates a symbolic link at `path` pointing to `target`, which is not checked to exist
+  fn symlink(&mut self, target: &str, path: &str) -> Result<()>;
+
+  fn readlink(&self, path: &str) -> Result<String>;
+
+  /// Removes a file, or a directory together with everything inside it
+  fn unlink(&mut self, path: &str) -> Result<()>;
+
+  /// Moves the entry at `from` to `to`, failing if `to` is already taken
+  fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+
+  /// Flushes everything written so far to the underlying storage
+  fn sync(&mut self) -> Result<()>;
+
+  fn statfs(&self) -> Result<Statfs>;
+}
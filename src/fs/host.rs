@@ -0,0 +1,244 @@
+use crate::filesystem::{FileType, Filesystem};
+use crate::path;
+use crate::walk::{Walk, WalkEntry};
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+
+// Copying trees between the host file system and an image. Regular files, directories and
+// symbolic links carry over with their content, permission bits and modification time. Owners
+// are copied into the image but not back to the host, where changing them mostly takes root. The
+// times of symbolic links on the host are left alone too, as the standard library cannot set them.
+
+/// What a copy went through
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Copied {
+  pub files: usize,
+  pub directories: usize,
+  pub symlinks: usize,
+  /// Entries left out, such as devices or names the other side cannot hold, each with the reason
+  pub skipped: Vec<String>,
+}
+
+// Seconds since the Unix epoch of the last modification
+fn host_mtime(meta: &fs::Metadata) -> u64 {
+  meta.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs())
+}
+
+fn join(dir: &str, name: &str) -> String {
+  format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// Copies everything inside the host directory `source` into the existing directory `dest` of
+/// the image, which gets the permissions, owner and modification time of `source`. Entries are added in name order, so
+/// the same tree always gives the same image.
+pub fn populate<F: Filesystem>(fs: &mut F, source: &Path, dest: &str) -> Result<Copied> {
+  let mut copied = Copied::default();
+  populate_dir(fs, source, dest, &mut copied)?;
+  let meta = fs::metadata(source)?;
+  fs.set_mode(dest, (meta.permissions().mode() & 0o7777) as u16)?;
+  fs.set_owner(dest, meta.uid(), meta.gid())?;
+  fs.set_mtime(dest, host_mtime(&meta))?;
+  Ok(copied)
+}
+
+fn populate_dir<F: Filesystem>(fs: &mut F, source: &Path, dest: &str, copied: &mut Copied) -> Result<()> {
+  let mut entries = fs::read_dir(source)?.collect::<std::io::Result<Vec<_>>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+  for entry in entries {
+    let host_path = entry.path();
+    let name = entry.file_name().into_string()
+      .map_err(|name| anyhow!("File name is not valid UTF-8: {:?}", name))?;
+    let path = join(dest, &name);
+    let meta = fs::symlink_metadata(&host_path)?;
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+      let target = fs::read_link(&host_path)?;
+      let target = target.to_str().ok_or(anyhow!("Link target is not valid UTF-8: {:?}", target))?;
+      fs.symlink(target, &path)?;
+      copied.symlinks += 1;
+    } else if file_type.is_dir() {
+      fs.mkdir(&path)?;
+      populate_dir(fs, &host_path, &path, copied)?;
+      copied.directories += 1;
+    } else if file_type.is_file() {
+      fs.create(&path, &fs::read(&host_path)?)?;
+      copied.files += 1;
+    } else {
+      copied.skipped.push(format!("{}: not a regular file, directory or symbolic link", host_path.display()));
+      continue;
+    }
+    // Directories are stamped after their entries, which would bump the time otherwise
+    fs.set_mode(&path, (meta.permissions().mode() & 0o7777) as u16)?;
+    fs.set_owner(&path, meta.uid(), meta.gid())?;
+    fs.set_mtime(&path, host_mtime(&meta))?;
+  }
+  Ok(())
+}
+
+/// Recreates the entry `source` of the image, and everything below it, at `dest` on the host.
+/// Existing files are overwritten. Entries whose names the host cannot hold are left out.
+/// Symbolic links already found where an entry goes are replaced rather than followed, so nothing
+/// is written outside `dest`.
+pub fn extract<F: Filesystem>(fs: &F, source: &str, dest: &Path) -> Result<Copied> {
+  let mut copied = Copied::default();
+  let source = path::resolve("/", source);
+  let skipped_depth = path::components(&source).len();
+  // Directories get their times and permissions once everything inside them is written, deepest first
+  let mut directories: Vec<(PathBuf, WalkEntry)> = vec![];
+  let walk = Walk::new(fs, &source).prune(|item| item.depth > 0 && path::check_name(&item.entry.name).is_err());
+  for item in walk {
+    let item = item?;
+    if item.depth > 0 {
+      if let Err(why) = path::check_name(&item.entry.name) {
+        copied.skipped.push(format!("{}: {}", item.path, why));
+        continue;
+      }
+    }
+    let host_path = path::components(&item.path)[skipped_depth..].iter().fold(dest.to_owned(), |host, name| host.join(name));
+    match item.entry.file_type {
+      FileType::Directory => {
+        // The destination itself is left to be a link if the caller chose one
+        if item.depth > 0 { remove_symlink(&host_path)? };
+        fs::create_dir_all(&host_path)?;
+        copied.directories += 1;
+        directories.push((host_path, item));
+      }
+      FileType::File => {
+        if fs::symlink_metadata(&host_path).is_ok_and(|meta| !meta.is_dir()) { fs::remove_file(&host_path)? };
+        let mut file = fs::File::options().write(true).create_new(true).open(&host_path)?;
+        file.write_all(&fs.read(&item.path)?)?;
+        restore_metadata(&file, &item)?;
+        copied.files += 1;
+      }
+      FileType::Symlink => {
+        if fs::symlink_metadata(&host_path).is_ok() { fs::remove_file(&host_path)? };
+        std::os::unix::fs::symlink(fs.readlink(&item.path)?, &host_path)?;
+        copied.symlinks += 1;
+      }
+    }
+  }
+  for (host_path, item) in directories.iter().rev() {
+    restore_metadata(&fs::File::open(host_path)?, item)?;
+  }
+  Ok(copied)
+}
+
+fn remove_symlink(host_path: &Path) -> Result<()> {
+  if fs::symlink_metadata(host_path).is_ok_and(|meta| meta.file_type().is_symlink()) { fs::remove_file(host_path)? };
+  Ok(())
+}
+
+// Gives the open host file or directory the modification time and permissions of `item`
+fn restore_metadata(file: &fs::File, item: &WalkEntry) -> Result<()> {
+  file.set_modified(UNIX_EPOCH + Duration::from_secs(item.entry.mtime))?;
+  if let Some(mode) = item.entry.mode {
+    file.set_permissions(fs::Permissions::from_mode(mode as u32))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::FileType;
+  use crate::structure::*;
+  use crate::testing::{temp_fs, TempPath};
+  use crate::Fs;
+  use std::time::{Duration, SystemTime};
+
+  #[test]
+  fn populate_from_a_host_tree() {
+    let tree = TempPath::new("host-tree");
+    let source = tree.path();
+    fs::create_dir_all(source.join("etc/empty")).unwrap();
+    fs::write(source.join("etc/hostname"), b"box\n").unwrap();
+    fs::write(source.join("big"), vec![3; 5 * 4096 + 1]).unwrap();
+    fs::set_permissions(source.join("big"), fs::Permissions::from_mode(0o600)).unwrap();
+    // Only root may give files away, elsewhere they keep the owner of the test
+    std::os::unix::fs::chown(source.join("big"), Some(1234), Some(5678)).ok();
+    std::os::unix::fs::symlink("etc/hostname", source.join("link")).unwrap();
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    fs::File::open(source.join("etc/hostname")).unwrap().set_modified(old).unwrap();
+
+    let image = TempPath::new("host.img");
+    let mut fs = Fs::format_with(image.as_str(), Superblock::new(4096, 64, 8, 0).unwrap()).unwrap();
+    let copied = populate(&mut fs, source, "/").unwrap();
+    assert_eq!((copied.files, copied.directories, copied.symlinks), (2, 2, 1));
+    assert_eq!(fs.read("/etc/hostname").unwrap(), b"box\n");
+    assert_eq!(fs.stat("/etc/hostname").unwrap().mtime, 1_000_000_000);
+    assert_eq!(fs.read("/big").unwrap(), vec![3; 5 * 4096 + 1]);
+    assert_eq!(fs.stat("/big").unwrap().mode, Some(0o600));
+    let host = fs::metadata(source.join("big")).unwrap();
+    assert_eq!(fs.stat("/big").unwrap().owner, Some((host.uid(), host.gid())));
+    assert_eq!(fs.readlink("/link").unwrap(), "etc/hostname");
+    assert_eq!(fs.stat("/etc/empty").unwrap().file_type, FileType::Directory);
+    assert_eq!(fs.stat("/etc").unwrap().mtime, host_mtime(&fs::metadata(source.join("etc")).unwrap()));
+    assert_eq!(fs.statfs().block_size, 4096);
+    assert_eq!(fs::metadata(image.path()).unwrap().len() as usize, Superblock::new(4096, 64, 8, 0).unwrap().image_size());
+
+    // Of the 8 inodes 7 are taken now, so a second copy runs out of them
+    fs.mkdir("/again").unwrap();
+    assert!(populate(&mut fs, source, "/again").is_err());
+  }
+
+  #[test]
+  fn extract_to_the_host() {
+    let (mut fs, _image) = temp_fs("host-extract", 0);
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/hostname", b"box\n").unwrap();
+    fs.symlink("etc/hostname", "/link").unwrap();
+    fs.set_mtime("/etc/hostname", 1_000_000_000).unwrap();
+    fs.set_mtime("/etc", 1_100_000_000).unwrap();
+    // Names the host cannot hold may come from images written before they were checked
+    let mut root = fs.read_inode(ROOT_INODE).unwrap();
+    let file = fs.lookup("/etc/hostname").unwrap();
+    let record = crate::directory::DirRecord { inode: file, file_type: FileType::File, name: "a/b".to_owned() };
+    fs.add_record(ROOT_INODE, &mut root, &record).unwrap();
+
+    let extracted = TempPath::new("host-extracted");
+    let dest = extracted.path();
+    let copied = extract(&fs, "/", dest).unwrap();
+    assert_eq!((copied.files, copied.directories, copied.symlinks), (1, 2, 1));
+    assert_eq!(copied.skipped, vec!["/a/b: Invalid file name: contains '/'"]);
+    assert_eq!(fs::read(dest.join("etc/hostname")).unwrap(), b"box\n");
+    assert_eq!(fs::read_link(dest.join("link")).unwrap(), Path::new("etc/hostname"));
+    assert_eq!(host_mtime(&fs::metadata(dest.join("etc/hostname")).unwrap()), 1_000_000_000);
+    assert_eq!(host_mtime(&fs::metadata(dest.join("etc")).unwrap()), 1_100_000_000);
+
+    // A single file lands at the destination itself
+    extract(&fs, "/etc/hostname", &dest.join("copy")).unwrap();
+    assert_eq!(fs::read(dest.join("copy")).unwrap(), b"box\n");
+  }
+
+  #[test]
+  fn extract_replaces_links_on_the_host() {
+    let (mut fs, _image) = temp_fs("host-links", 0);
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/hostname", b"box\n").unwrap();
+    fs.create("/motd", b"hello\n").unwrap();
+    fs.set_mode("/motd", 0o600).unwrap();
+
+    let outside = TempPath::new("host-outside");
+    fs::create_dir_all(outside.path()).unwrap();
+    fs::write(outside.path().join("motd"), b"mine\n").unwrap();
+    let extracted = TempPath::new("host-linked");
+    let dest = extracted.path();
+    fs::create_dir_all(dest).unwrap();
+    std::os::unix::fs::symlink(outside.path(), dest.join("etc")).unwrap();
+    std::os::unix::fs::symlink(outside.path().join("motd"), dest.join("motd")).unwrap();
+
+    extract(&fs, "/", dest).unwrap();
+    assert!(!fs::symlink_metadata(dest.join("etc")).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read(dest.join("etc/hostname")).unwrap(), b"box\n");
+    assert_eq!(fs::read(dest.join("motd")).unwrap(), b"hello\n");
+    assert!(!outside.path().join("hostname").exists());
+    let motd = outside.path().join("motd");
+    assert_eq!(fs::read(&motd).unwrap(), b"mine\n");
+    assert_ne!(fs::metadata(&motd).unwrap().permissions().mode() & 0o777, 0o600);
+  }
+}
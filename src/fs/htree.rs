@@ -0,0 +1,285 @@
+use crate::directory::{insert, new_block, parse, record_len, remove, DirRecord};
+use crate::structure::*;
+use crate::{now, Fs};
+
+use std::convert::TryInto;
+use anyhow::{anyhow, Result};
+
+/// Where the index starts in the first block, after `.` and `..`: hash version, depth and padding
+const ROOT_INFO: usize = 24;
+/// Where the count, the limit and the entries start in the first block and in index nodes
+const ROOT_ENTRIES: usize = ROOT_INFO + 4;
+const NODE_ENTRIES: usize = 8;
+
+const HASH_VERSION: u8 = 1;
+
+/// Hash of a name the index is ordered by, 32-bit FNV-1a
+pub fn name_hash(name: &str) -> u32 {
+  name.bytes().fold(0x811c_9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+fn entries_at(is_root: bool) -> usize {
+  if is_root { ROOT_ENTRIES } else { NODE_ENTRIES }
+}
+
+// Index entries that fit a block after the count and the limit
+fn limit(block_size: usize, is_root: bool) -> usize {
+  (block_size - entries_at(is_root) - 4) / 8
+}
+
+// Hash and directory block of every entry; the hash of the first one is always 0
+fn read_entries(block: &[u8], is_root: bool) -> Result<Vec<(u32, usize)>> {
+  let at = entries_at(is_root);
+  let count = u16::from_le_bytes(block[at..at + 2].try_into()?) as usize;
+  (0..count).map(|i| {
+    let entry = at + 4 + 8 * i;
+    let hash = u32::from_le_bytes(block[entry..entry + 4].try_into()?);
+    let child = u32::from_le_bytes(block[entry + 4..entry + 8].try_into()?) as usize;
+    Ok((hash, child))
+  }).collect()
+}
+
+fn write_entries(block: &mut [u8], is_root: bool, entries: &[(u32, usize)]) {
+  let (at, limit) = (entries_at(is_root), limit(block.len(), is_root));
+  block[at..at + 2].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+  block[at + 2..at + 4].copy_from_slice(&(limit as u16).to_le_bytes());
+  for (i, &(hash, child)) in entries.iter().enumerate() {
+    let entry = at + 4 + 8 * i;
+    block[entry..entry + 4].copy_from_slice(&hash.to_le_bytes());
+    block[entry + 4..entry + 8].copy_from_slice(&(child as u32).to_le_bytes());
+  }
+}
+
+// An index node, which a linear reader takes for a block with one unused record
+fn new_node(block_size: usize, entries: &[(u32, usize)]) -> Vec<u8> {
+  let mut block = vec![0; block_size];
+  block[4..6].copy_from_slice(&(block_size as u16).to_le_bytes());
+  write_entries(&mut block, false, entries);
+  block
+}
+
+// Position of the entry covering `hash`, the last one not above it
+fn covering(entries: &[(u32, usize)], hash: u32) -> usize {
+  entries.partition_point(|&(start, _)| start <= hash).saturating_sub(1)
+}
+
+// Where records sorted by hash are split in two blocks: near the middle of their bytes, and
+// between two different hashes, so that every hash stays in one block
+fn split_point(records: &[DirRecord], block_size: usize) -> Result<usize> {
+  let lens: Vec<usize> = records.iter().map(|record| record_len(record.name.len())).collect();
+  let total: usize = lens.iter().sum();
+  let (mut middle, mut bytes) = (0, 0);
+  while middle < records.len() && 2 * (bytes + lens[middle]) <= total {
+    bytes += lens[middle];
+    middle += 1;
+  }
+  let hashes: Vec<u32> = records.iter().map(|record| name_hash(&record.name)).collect();
+  (1..records.len())
+    .filter(|&i| hashes[i - 1] != hashes[i])
+    .filter(|&i| lens[..i].iter().sum::<usize>() <= block_size && lens[i..].iter().sum::<usize>() <= block_size)
+    .min_by_key(|&i| (i as isize - middle as isize).abs())
+    .ok_or(anyhow!("Too many names with the same hash to split a directory block"))
+}
+
+// Hash index of directories, in the style of the ext3 htree. The first block keeps `.` and `..`,
+// the latter stretching over the rest of the block, where the index hides from linear readers:
+// entries of a starting hash and a directory block, sorted by hash. With a depth of 1 they point
+// at index nodes, blocks with a single unused record followed by entries the same way, and those
+// at leaves, which are ordinary blocks of records whose names hash from their entry's hash up to
+// the next one's. A name is found by following the entries covering its hash, reading one block
+// per level. A full leaf is split in two by hash, and a full index node likewise.
+impl Fs {
+  // Blocks of the index nodes on the way to the leaf for `hash`, each with the position of the
+  // entry followed there, and the leaf
+  fn dx_find(&self, dir: &Inode, hash: u32) -> Result<(Vec<(usize, usize)>, usize)> {
+    let mut block = self.read_dir_block(dir, 0)?;
+    let depth = block[ROOT_INFO + 1] as usize;
+    let mut path = vec![];
+    let mut node = 0;
+    loop {
+      let entries = read_entries(&block, path.is_empty())?;
+      if entries.is_empty() { return Err(anyhow!("Corrupted directory index at block {}", node)) };
+      let position = covering(&entries, hash);
+      path.push((node, position));
+      node = entries[position].1;
+      if path.len() > depth { return Ok((path, node)) };
+      block = self.read_dir_block(dir, node)?;
+    }
+  }
+
+  pub(crate) fn dx_find_record(&self, dir: &Inode, name: &str) -> Result<Option<DirRecord>> {
+    let (_, leaf) = self.dx_find(dir, name_hash(name))?;
+    let block = self.read_dir_block(dir, leaf)?;
+    Ok(parse(&block)?.into_iter().filter_map(|slot| slot.record).find(|record| record.name == name))
+  }
+
+  pub(crate) fn dx_remove_record(&mut self, dir_ind: usize, dir: &mut Inode, name: &str) -> Result<DirRecord> {
+    let (_, leaf) = self.dx_find(dir, name_hash(name))?;
+    let mut block = self.read_dir_block(dir, leaf)?;
+    let record = remove(&mut block, name)?.ok_or(anyhow!("Unknown filename: {}", name))?;
+    self.write_dir_block(dir, leaf, &block)?;
+    dir.mtime = now();
+    self.update_inode(dir_ind, dir)?;
+    Ok(record)
+  }
+
+  pub(crate) fn dx_add_record(&mut self, dir_ind: usize, dir: &mut Inode, record: &DirRecord) -> Result<()> {
+    let (path, leaf) = self.dx_find(dir, name_hash(&record.name))?;
+    let mut block = self.read_dir_block(dir, leaf)?;
+    if insert(&mut block, record)? {
+      self.write_dir_block(dir, leaf, &block)?;
+    } else {
+      let mut records: Vec<DirRecord> = parse(&block)?.into_iter().filter_map(|slot| slot.record).collect();
+      records.push(record.clone());
+      records.sort_by_key(|record| name_hash(&record.name));
+      let block_size = self.superblock.block_size;
+      let middle = split_point(&records, block_size)?;
+      self.dx_check_room(dir, &path)?;
+      // New blocks are taken before any block in use changes, so running out of space changes nothing
+      let upper = self.append_dir_block(dir, &new_block(block_size, &records[middle..]))?;
+      self.dx_insert_entry(dir, &path, name_hash(&records[middle].name), upper)?;
+      self.write_dir_block(dir, leaf, &new_block(block_size, &records[..middle]))?;
+    }
+    dir.mtime = now();
+    self.update_inode(dir_ind, dir)
+  }
+
+  // Fails if the nodes on `path` have no room for one more entry even after splitting
+  fn dx_check_room(&self, dir: &Inode, path: &[(usize, usize)]) -> Result<()> {
+    let block_size = self.superblock.block_size;
+    for (level, &(node, _)) in path.iter().enumerate().rev() {
+      let block = self.read_dir_block(dir, node)?;
+      let is_root = level == 0;
+      if read_entries(&block, is_root)?.len() < limit(block_size, is_root) { return Ok(()) };
+      if is_root && block[ROOT_INFO + 1] == 0 { return Ok(()) };
+    }
+    Err(anyhow!("Directory index is full"))
+  }
+
+  // Adds an entry for `child` right after the one followed at the end of `path`
+  fn dx_insert_entry(&mut self, dir: &mut Inode, path: &[(usize, usize)], hash: u32, child: usize) -> Result<()> {
+    let block_size = self.superblock.block_size;
+    let (node, position) = path[path.len() - 1];
+    let is_root = path.len() == 1;
+    let mut block = self.read_dir_block(dir, node)?;
+    let mut entries = read_entries(&block, is_root)?;
+    entries.insert(position + 1, (hash, child));
+    if entries.len() <= limit(block_size, is_root) {
+      write_entries(&mut block, is_root, &entries);
+      return self.write_dir_block(dir, node, &block);
+    }
+    if is_root {
+      if block[ROOT_INFO + 1] > 0 { return Err(anyhow!("Directory index is full")) };
+      // The entries move to an index node below the root, which then points at it alone
+      let below = self.append_dir_block(dir, &new_node(block_size, &entries))?;
+      block[ROOT_INFO + 1] = 1;
+      write_entries(&mut block, true, &[(0, below)]);
+      return self.write_dir_block(dir, node, &block);
+    }
+    let middle = entries.len() / 2;
+    let upper = self.append_dir_block(dir, &new_node(block_size, &entries[middle..]))?;
+    write_entries(&mut block, false, &entries[..middle]);
+    self.write_dir_block(dir, node, &block)?;
+    self.dx_insert_entry(dir, &path[..path.len() - 1], entries[middle].0, upper)
+  }
+
+  /// Turns a directory with a single full block into an indexed one with `record` added
+  pub(crate) fn make_indexed(&mut self, dir_ind: usize, dir: &mut Inode, record: &DirRecord) -> Result<()> {
+    let block_size = self.superblock.block_size;
+    let mut records: Vec<DirRecord> = parse(&self.read_dir_block(dir, 0)?)?.into_iter().filter_map(|slot| slot.record).collect();
+    let dots: Vec<DirRecord> = records.drain(..2).collect();
+    records.push(record.clone());
+    records.sort_by_key(|record| name_hash(&record.name));
+    let middle = split_point(&records, block_size)?;
+    let lower = self.append_dir_block(dir, &new_block(block_size, &records[..middle]))?;
+    let upper = self.append_dir_block(dir, &new_block(block_size, &records[middle..]))?;
+    let mut root = new_block(block_size, &dots);
+    root[ROOT_INFO] = HASH_VERSION;
+    write_entries(&mut root, true, &[(0, lower), (name_hash(&records[middle].name), upper)]);
+    self.write_dir_block(dir, 0, &root)?;
+    dir.indexed = true;
+    dir.mtime = now();
+    self.update_inode(dir_ind, dir)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::{temp_fs, TempPath};
+
+  #[test]
+  fn indexed_lookups() {
+    let (mut fs, _image) = temp_fs("htree-lookups", 0);
+    fs.mkdir("/dir").unwrap();
+    for i in 0..150 { fs.create(&format!("/dir/file{}", i), b"").unwrap() };
+    let dir = fs.read_inode(fs.lookup("/dir").unwrap()).unwrap();
+    assert!(dir.indexed);
+    assert_eq!(fs.dir_records(&dir).unwrap().len(), 152);
+    for i in 0..150 { assert!(fs.lookup(&format!("/dir/file{}", i)).is_ok()) };
+    assert!(fs.lookup("/dir/file150").is_err());
+    for i in (0..150).step_by(3) { fs.unlink(&format!("/dir/file{}", i)).unwrap() };
+    assert!(fs.lookup("/dir/file0").is_err());
+    assert!(fs.lookup("/dir/file1").is_ok());
+    assert_eq!(fs.readdir("/dir").unwrap().len(), 100);
+    // Leaves keep the range of hashes they cover, so their free space goes to names hashing there
+    let size = fs.stat("/dir").unwrap().size;
+    for i in (0..150).step_by(3) { fs.create(&format!("/dir/file{}", i), b"").unwrap() };
+    assert_eq!(fs.stat("/dir").unwrap().size, size);
+    fs.rename("/dir/file7", "/dir/seven").unwrap();
+    assert_eq!(fs.stat("/dir/seven").unwrap().inode, fs.lookup("/dir/seven").unwrap());
+  }
+
+  #[test]
+  fn thousands_of_entries_without_extents() {
+    let image = TempPath::new("htree-thousands.img");
+    let mut fs = Fs::format_with(image.as_str(), Superblock::new(BLOCK_SIZE, 2048, 4096, 0).unwrap()).unwrap();
+    let free = fs.statfs().free_blocks;
+    fs.mkdir("/dir").unwrap();
+    fs.create("/dir/file", b"").unwrap();
+    for i in 0..3000 { fs.create(&format!("/dir/entry{:04}", i), b"").unwrap() };
+    let dir = fs.read_inode(fs.lookup("/dir").unwrap()).unwrap();
+    assert!(dir.indexed && dir.extents);
+    assert!(dir.size > INODE_LINKS * BLOCK_SIZE);
+    assert_eq!(fs.superblock().feature_incompat, INCOMPAT_EXTENTS);
+    // Only the directory that outgrew its slots changes, files still point at their blocks directly
+    assert!(!fs.read_inode(fs.lookup("/dir/file").unwrap()).unwrap().extents);
+    for i in 0..3000 { assert!(fs.lookup(&format!("/dir/entry{:04}", i)).is_ok()) };
+    assert_eq!(fs.readdir("/dir").unwrap().len(), 3001);
+    drop(fs);
+    let mut fs = Fs::open_image(image.as_str()).unwrap();
+    for i in (0..3000).step_by(2) { fs.unlink(&format!("/dir/entry{:04}", i)).unwrap() };
+    assert_eq!(fs.readdir("/dir").unwrap().len(), 1501);
+    for i in (1..3000).step_by(2) { fs.unlink(&format!("/dir/entry{:04}", i)).unwrap() };
+    fs.unlink("/dir/file").unwrap();
+    fs.unlink("/dir").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+  }
+
+  #[test]
+  fn index_grows_a_level() {
+    let (mut fs, _image) = temp_fs("htree-levels", INCOMPAT_EXTENTS);
+    fs.mkdir("/dir").unwrap();
+    let long = "x".repeat(NAME_MAX - 4);
+    let depth = |fs: &Fs| {
+      let dir = fs.read_inode(fs.lookup("/dir").unwrap()).unwrap();
+      if dir.indexed { Some(fs.read_dir_block(&dir, 0).unwrap()[ROOT_INFO + 1]) } else { None }
+    };
+    let mut count = 0;
+    while depth(&fs) != Some(1) {
+      fs.create(&format!("/dir/{}{:04}", long, count), b"").unwrap();
+      count += 1;
+    }
+    // Every leaf holds at least one name of 264 bytes, and the root indexes up to 124 of them
+    assert!(count > limit(BLOCK_SIZE, true));
+    for _ in 0..50 {
+      fs.create(&format!("/dir/{}{:04}", long, count), b"").unwrap();
+      count += 1;
+    }
+    for i in 0..count { assert!(fs.lookup(&format!("/dir/{}{:04}", long, i)).is_ok()) };
+    assert_eq!(fs.readdir("/dir").unwrap().len(), count);
+    fs.unlink("/dir").unwrap();
+    assert_eq!(fs.statfs().free_inodes, INODES_COUNT - 2);
+  }
+}
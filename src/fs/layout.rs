@@ -0,0 +1,142 @@
+use crate::alloc::extents;
+use crate::walk::Walk;
+use crate::structure::*;
+use crate::Fs;
+
+use std::collections::HashMap;
+use anyhow::Result;
+
+// Where files lie in the data area, for judging how fragmented an image is. Every file with
+// blocks gets a symbol, and the map shows one cell per block, or per group of blocks on big
+// images, with the symbol of the file taking most of them.
+
+const SYMBOLS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+// ANSI foreground colours the symbols cycle through
+const COLOURS: &[u8] = &[31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+/// How the blocks of one inode lie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+  pub inode: usize,
+  /// None for inodes in use that no directory refers to
+  pub path: Option<String>,
+  /// Data blocks together with those of the extent tree
+  pub blocks: usize,
+  /// Runs of consecutive data blocks
+  pub extents: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layout {
+  /// Every inode in use, in the order of their first block
+  pub files: Vec<FileLayout>,
+  /// Index into `files` of the owner of every data block
+  pub owners: Vec<Option<usize>>,
+  pub free: Vec<bool>,
+  /// Inodes in use whose blocks could not be read, left out of the map
+  pub corrupted: Vec<usize>,
+}
+
+impl Fs {
+  /// Owner of every data block, found by going through all inodes in use. Inodes whose blocks
+  /// cannot be read, and directories that cannot be listed, are skipped
+  pub fn layout(&self) -> Result<Layout> {
+    let mut paths = HashMap::new();
+    for item in Walk::new(self, "/").flatten() {
+      paths.insert(item.entry.inode, item.path);
+    }
+    let count = self.superblock.blocks_count;
+    let mut files = vec![];
+    let mut owned: Vec<Vec<usize>> = vec![];
+    let mut corrupted = vec![];
+    for inode_ind in (ROOT_INODE..self.superblock.inodes_count).filter(|&ind| !self.inode_bitmap.free_at(ind)) {
+      let (blocks, nodes) = match self.inode_blocks(inode_ind) {
+        Ok(blocks) => blocks,
+        Err(_) => { corrupted.push(inode_ind); continue },
+      };
+      let data: Vec<usize> = blocks.into_iter().filter(|&block| block != HOLE).collect();
+      let mut taken: Vec<usize> = data.iter().copied().chain(nodes).collect();
+      taken.retain(|&block| block < count);
+      files.push(FileLayout { inode: inode_ind, path: paths.remove(&inode_ind), blocks: taken.len(), extents: extents(&data) });
+      owned.push(taken);
+    }
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by_key(|&i| (owned[i].iter().min().copied().unwrap_or(usize::MAX), files[i].inode));
+    let mut owners = vec![None; count];
+    for (at, &i) in order.iter().enumerate() {
+      for &block in owned[i].iter() { owners[block] = Some(at) };
+    }
+    let files = order.iter().map(|&i| files[i].clone()).collect();
+    let free = (0..count).map(|block| self.data_bitmap.free_at(block)).collect();
+    Ok(Layout { files, owners, free, corrupted })
+  }
+}
+
+impl Layout {
+  fn symbol(&self, file: usize, colour: bool) -> String {
+    let symbol = SYMBOLS[file % SYMBOLS.len()] as char;
+    if colour { format!("\x1b[{}m{}\x1b[0m", COLOURS[file % COLOURS.len()], symbol) } else { symbol.to_string() }
+  }
+
+  /// The data area in rows of `width` cells, at most `cells` of them; `.` is free, `*` taken by
+  /// no file, which is block 0 and leaked blocks
+  pub fn render_map(&self, width: usize, cells: usize, colour: bool) -> String {
+    let per_cell = std::cmp::max(1, self.owners.len().div_ceil(std::cmp::max(cells, 1)));
+    let mut map = String::new();
+    for (i, group) in self.owners.chunks(per_cell).enumerate() {
+      let mut counts: HashMap<usize, usize> = HashMap::new();
+      for owner in group.iter().flatten() { *counts.entry(*owner).or_default() += 1 };
+      let start = i * per_cell;
+      let cell = match counts.into_iter().max_by_key(|&(file, count)| (count, std::cmp::Reverse(file))) {
+        Some((file, _)) => self.symbol(file, colour),
+        None if self.free[start..start + group.len()].iter().all(|&free| free) => ".".to_owned(),
+        None => "*".to_owned(),
+      };
+      map.push_str(&cell);
+      if (i + 1) % width == 0 { map.push('\n') };
+    }
+    if !map.ends_with('\n') { map.push('\n') };
+    map
+  }
+
+  /// Symbol, path and extents of every file with blocks
+  pub fn legend(&self, colour: bool) -> String {
+    let mut legend = String::new();
+    for (i, file) in self.files.iter().enumerate().filter(|(_, file)| file.blocks > 0) {
+      let path = file.path.clone().unwrap_or_else(|| format!("<inode {}>", file.inode));
+      legend.push_str(&format!("{} {} ({} blocks, {} extents)\n", self.symbol(i, colour), path, file.blocks, file.extents));
+    }
+    legend
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::TempPath;
+
+  #[test]
+  fn maps_and_fragments() {
+    let path = TempPath::new("layout.img");
+    let mut fs = Fs::format_with(path.as_str(), Superblock::new(1024, 16, 16, INCOMPAT_EXTENTS).unwrap()).unwrap();
+    fs.create("/a", &[1; 2048]).unwrap();
+    fs.create("/b", &[2; 1024]).unwrap();
+    fs.create("/c", &[3; 1024]).unwrap();
+    // The third block of `a` goes past `c`
+    let handle = fs.open("/a").unwrap();
+    fs.write_at(handle, 2048, &[1; 1024]).unwrap();
+    fs.close(handle).unwrap();
+    fs.unlink("/b").unwrap();
+
+    let layout = fs.layout().unwrap();
+    let paths: Vec<Option<&str>> = layout.files.iter().map(|file| file.path.as_deref()).collect();
+    assert_eq!(paths, vec![Some("/"), Some("/a"), Some("/c")]);
+    assert_eq!(layout.render_map(8, 64, false), "*ABB.CB.\n........\n");
+    assert_eq!(layout.render_map(8, 4, false), "BB..\n");
+    assert!(layout.legend(false).contains("B /a (3 blocks, 2 extents)\n"));
+    let stats = fs.fragmentation().unwrap();
+    assert_eq!((stats.files, stats.fragmented_files, stats.extents), (3, 1, 4));
+    assert!((stats.factor() - 0.25).abs() < 1e-9);
+  }
+}
@@ -1,14 +1,43 @@
 pub mod structure;
+pub mod alloc;
+pub mod mapping;
+pub mod directory;
+pub mod htree;
 pub mod storage;
+pub mod filesystem;
+pub mod path;
+pub mod transfer;
+pub mod walk;
+pub mod host;
+pub mod tar;
+pub mod manifest;
+pub mod debug;
+pub mod layout;
+pub mod defrag;
+pub mod resize;
+pub mod shell;
 
 use structure::*;
+use directory::DirRecord;
 use storage::Storage;
+use filesystem::{DirEntry, FileType, Filesystem, Handle, Metadata, Statfs, Whence};
 
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
 
 use anyhow::{anyhow, Result, Error};
+use sha2::{Digest, Sha256};
+
+// Symbolic links followed while resolving a single path before giving up on a loop
+const MAX_SYMLINKS: usize = 40;
+
+// Seconds since the Unix epoch, the resolution of inode timestamps
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
 
 #[derive(Debug)]
 pub struct Fs {
@@ -16,247 +45,1049 @@ pub struct Fs {
   data_bitmap: DataBitmap,
   inode_bitmap: InodeBitmap,
   storage: Storage,
-  pub cur_dir: String,
-  cur_inode_ind: usize,
+  /// Blocks held back for files growing through a handle, never written to disk
+  reserved: DataBitmap,
+  /// First block and length of the window reserved for an inode
+  windows: HashMap<usize, (usize, usize)>,
+  /// Inode every open handle refers to
+  handles: HashMap<usize, usize>,
+  next_handle: usize,
 }
 
 impl Fs {
   fn read_inode(&self, inode_ind: usize) -> Result<Inode> {
     let bytes = self.storage.read(self.superblock.inode_table + inode_ind * INODE_SIZE, INODE_SIZE)?;
-    let inode: Inode = bincode::deserialize(&bytes)?; 
+    let inode: Inode = bincode::deserialize(&bytes)?;
     Ok(inode)
   }
 
   fn update_inode(&mut self, inode_ind: usize, inode: &Inode) -> Result<()> {
-    let bytes = bincode::serialize(inode)?;
+    // Padded to the full record, which may be the last thing in the image when files take no blocks
+    let mut bytes = bincode::serialize(inode)?;
+    bytes.resize(INODE_SIZE, 0);
     self.storage.write(self.superblock.inode_table + inode_ind * INODE_SIZE, &bytes)?;
     Ok(())
   }
 
   fn write_new_inode(&mut self, inode: &Inode) -> Result<usize> {
     let ind = self.inode_bitmap.find_free().ok_or(anyhow!("Could not locate free inode"))?;
-    let offset = self.superblock.inode_table + ind * self.superblock.inode_size;
-    let bytes = bincode::serialize(inode)?;
-    self.storage.write(offset, &bytes)?;
+    self.update_inode(ind, inode)?;
     self.inode_bitmap.set(ind, true)?;
+    self.superblock.free_inodes_count -= 1;
     self.dump_inode_bitmap()?;
+    self.dump_superblock()?;
     Ok(ind)
   }
 
+  fn dump_superblock(&mut self) -> Result<()> {
+    let bytes = bincode::serialize(&self.superblock)?;
+    self.storage.write(0, &bytes)?;
+    Ok(())
+  }
+
   fn dump_inode_bitmap(&mut self) -> Result<()> {
-    let bytes = bincode::serialize(&self.inode_bitmap)?;
-    self.storage.write(self.superblock.inode_bitmap, &bytes)?;
+    self.storage.write(self.superblock.inode_bitmap, self.inode_bitmap.as_bytes())?;
     Ok(())
   }
 
   fn dump_data_bitmap(&mut self) -> Result<()> {
-    let bytes = bincode::serialize(&self.data_bitmap)?;
-    self.storage.write(self.superblock.data_bitmap, &bytes)?;
+    self.storage.write(self.superblock.data_bitmap, self.data_bitmap.as_bytes())?;
     Ok(())
   }
 
   fn free_inode(&mut self, inode_ind: usize) -> Result<()> {
     let inode = self.read_inode(inode_ind)?;
-    let blocks_taken = (inode.size as f64 / self.superblock.block_size as f64).ceil() as usize; 
-    for i in 0..blocks_taken {
-      self.data_bitmap.set(inode.direct[i], false)?;
-    }
+    self.release_blocks(&self.block_map(&inode)?)?;
+    self.release_blocks(&self.index_blocks(&inode)?)?;
+    self.release_window(inode_ind)?;
     self.inode_bitmap.set(inode_ind, false)?;
+    self.superblock.free_inodes_count += 1;
     self.dump_data_bitmap()?;
     self.dump_inode_bitmap()?;
+    self.dump_superblock()?;
     Ok(())
   }
 
-  fn read_data<T: DeserializeOwned>(&self, inode: &Inode) -> Result<T> {
-    let data_indices = &inode.direct[0..(inode.size as f64 / BLOCK_SIZE as f64).ceil() as usize];
+  fn read_bytes(&self, inode: &Inode) -> Result<Vec<u8>> {
+    if inode.inline_data { return Ok(inode.inline_bytes()) };
+    let data_indices = self.block_map(inode)?;
     let mut left_to_read = inode.size;
     let mut bytes: Vec<u8> = vec![];
     for ind in data_indices {
-      let read = std::cmp::min(left_to_read, BLOCK_SIZE);
+      let read = std::cmp::min(left_to_read, self.superblock.block_size);
       left_to_read -= read;
-      let mut batch = self.storage.read(self.superblock.data_blocks + ind * BLOCK_SIZE, read)?;
+      if ind == HOLE { bytes.resize(bytes.len() + read, 0); continue };
+      let mut batch = self.storage.read(self.superblock.data_blocks + ind * self.superblock.block_size, read)?;
       bytes.append(&mut batch);
     }
-    let data: T = bincode::deserialize(&bytes)?;
-    Ok(data)
-  }
-
-  fn update_data<T: Serialize>(&mut self, inode: &mut Inode, data: &T) -> Result<()> {
-    let data_bytes = bincode::serialize(data)?;
-    let blocks_taken = (inode.size as f64 / self.superblock.block_size as f64).ceil() as usize;
-    let blocks_needed = (data_bytes.len() as f64 / self.superblock.block_size as f64).ceil() as usize;
-    if blocks_needed > INODE_LINKS { return Err(anyhow!("The file is too big")) };
-    let mut indices: Vec<usize> = vec![];
-    for i in 0..blocks_needed {
-      if i < blocks_taken { indices.push(inode.direct[i]) }
-      else {
-        let from = indices.last().unwrap_or(&0);
-        let found = self.data_bitmap.find_free_from(*from + 1)
-                                    .ok_or(anyhow!("Could not locate enough free datablocks"))?;
-        indices.push(found); 
-      }
-    }
-    if blocks_needed < blocks_taken {
-      for &ind in inode.direct[blocks_needed..blocks_taken].iter() {
-        self.data_bitmap.set(ind, false)?;
-      }
+    Ok(bytes)
+  }
+
+  fn update_bytes(&mut self, inode: &mut Inode, data_bytes: &[u8]) -> Result<()> {
+    if inode.inline_data {
+      if data_bytes.len() <= INLINE_DATA_MAX { inode.set_inline_bytes(data_bytes); return Ok(()) };
+      inode.drop_inline_data();
     }
-    let mut direct = [0; INODE_LINKS];
-    for i in 0..indices.len() {
-      direct[i] = indices[i];
+    let old_indices = self.block_map(inode)?;
+    let blocks_needed = data_bytes.len().div_ceil(self.superblock.block_size);
+    if !inode.extents && blocks_needed > INODE_LINKS { return Err(anyhow!("The file is too big")) };
+    let mut indices = old_indices.clone();
+    indices.resize(blocks_needed, HOLE);
+    // Every block is found before any is taken or written, so running out of space changes nothing.
+    // The whole content is written, so holes get blocks too.
+    let missing: Vec<usize> = (0..blocks_needed).filter(|&i| indices[i] == HOLE).collect();
+    if let Some(&first) = missing.first() {
+      let found = self.find_blocks(alloc::goal(&indices[..first]), missing.len())?;
+      self.take_blocks(&found)?;
+      for (&i, block) in missing.iter().zip(found) { indices[i] = block };
     }
+    self.release_blocks(old_indices.get(blocks_needed..).unwrap_or(&[]))?;
+    // The extent tree may need blocks of its own, so it is updated before any data is written,
+    // from the first block that changes on and unmapping those past the new end
+    let mut mapped = indices.clone();
+    mapped.resize(std::cmp::max(old_indices.len(), blocks_needed), HOLE);
+    let unchanged = mapped.iter().zip(old_indices.iter()).take_while(|(new, old)| new == old).count();
+    self.map_blocks(inode, unchanged, &mapped[unchanged..])?;
     for (i, &ind) in indices.iter().enumerate() {
       let block_size = self.superblock.block_size;
       let from = i * block_size;
       let to = std::cmp::min(data_bytes.len(), from + block_size);
       let write_from = self.superblock.data_blocks + ind * block_size;
       self.storage.write(write_from, &data_bytes[from..to])?;
-      self.data_bitmap.set(ind, true)?;
     }
     self.dump_data_bitmap()?;
+    self.dump_superblock()?;
     inode.size = data_bytes.len();
-    inode.direct = direct;
     Ok(())
   }
 
-  fn write_data<T: Serialize>(&mut self, is_directory: bool, data: &T) -> Result<(usize, Inode)> {
+  fn write_bytes(&mut self, file_type: FileType, data_bytes: &[u8]) -> Result<(usize, Inode)> {
+    if self.superblock.free_inodes_count == 0 { return Err(anyhow!("Could not locate free inode")) };
     let mut inode = Inode{
       size: 0,
-      is_directory: is_directory,
+      is_directory: file_type == FileType::Directory,
+      is_symlink: file_type == FileType::Symlink,
       direct: [0; INODE_LINKS],
+      mtime: now(),
+      extents: self.superblock.feature_incompat & INCOMPAT_EXTENTS != 0,
+      inline_data: self.superblock.feature_incompat & INCOMPAT_INLINE_DATA != 0,
+      indexed: false,
+      mode: match file_type { FileType::Directory => 0o755, FileType::File => 0o644, FileType::Symlink => 0o777 },
+      uid: 0,
+      gid: 0,
     };
-    self.update_data(&mut inode, data)?;
+    self.update_bytes(&mut inode, data_bytes)?;
     let inode_ind = self.write_new_inode(&inode)?;
     Ok((inode_ind, inode))
   }
 
+  /// Opens the image at `filename`, creating an empty one without any features if there is none
   pub fn new(filename: &str) -> Result<Self> {
+    Fs::open_or_create(filename, Superblock::default())
+  }
+
+  /// Opens the existing image at `filename`. Unlike `new` it never writes anything while doing
+  /// so: files that are not images, and images whose counters disagree with the bitmaps, are refused.
+  pub fn open_image(filename: &str) -> Result<Self> {
+    let fs = Fs::read_image(filename)?;
+    fs.check_counters()?;
+    if fs.inode_bitmap.free_at(ROOT_INODE) { return Err(anyhow!("{} has no root directory", filename)) };
+    Ok(fs)
+  }
+
+  /// Creates an empty image at `filename` with the given incompatible features, replacing any there
+  pub fn format(filename: &str, feature_incompat: u32) -> Result<Self> {
+    Fs::format_with(filename, Superblock { feature_incompat, ..Superblock::default() })
+  }
+
+  /// Creates an empty image at `filename` laid out by `superblock`, see `Superblock::new`,
+  /// replacing any there. The file takes the full size of the image.
+  pub fn format_with(filename: &str, superblock: Superblock) -> Result<Self> {
+    if std::path::Path::new(filename).exists() { std::fs::remove_file(filename)? };
+    let size = superblock.image_size();
+    let mut fs = Fs::open_or_create(filename, superblock)?;
+    fs.storage.set_len(size)?;
+    Ok(fs)
+  }
+
+  /// Overwrites every free inode and data block with zeros, so that nothing of removed entries
+  /// stays behind in the image
+  pub fn zero_free(&mut self) -> Result<()> {
+    let free: Vec<usize> = (0..self.superblock.inodes_count).filter(|&ind| self.inode_bitmap.free_at(ind)).collect();
+    for ind in free {
+      self.storage.write(self.superblock.inode_table + ind * INODE_SIZE, &[0; INODE_SIZE])?;
+    }
+    let taken = self.data_bitmap.merged(&self.reserved);
+    let zeros = vec![0; self.superblock.block_size];
+    for ind in (0..self.superblock.blocks_count).filter(|&ind| taken.free_at(ind)) {
+      self.storage.write(self.superblock.data_blocks + ind * self.superblock.block_size, &zeros)?;
+    }
+    Ok(())
+  }
+
+  /// SHA-256 of the whole image, with the superblock and bitmaps written out first. Whatever
+  /// the file is short of the full size of the image counts as zeros.
+  pub fn digest(&mut self) -> Result<[u8; 32]> {
+    self.sync()?;
+    let mut hasher = Sha256::new();
+    let (size, stored) = (self.superblock.image_size(), self.storage.file_len()?);
+    for offset in (0..size).step_by(transfer::CHUNK_SIZE) {
+      let len = std::cmp::min(transfer::CHUNK_SIZE, size - offset);
+      let mut chunk = self.storage.read(offset, len.min(stored.saturating_sub(offset)))?;
+      chunk.resize(len, 0);
+      hasher.update(chunk);
+    }
+    Ok(hasher.finalize().into())
+  }
+
+  // Reads the superblock and bitmaps of the image at `filename`, failing if the file holds none
+  fn read_image(filename: &str) -> Result<Self> {
+    if !std::path::Path::new(filename).exists() { return Err(anyhow!("No such image: {}", filename)) };
+    let storage = Storage::new(filename)?;
+    let invalid = |why: String| anyhow!("{} is not an image: {}", filename, why);
+    let bytes = storage.read(0, SUPERBLOCK_SIZE).map_err(|_| invalid("too short for a superblock".to_owned()))?;
+    let sb: Superblock = bincode::deserialize(&bytes).map_err(|why| invalid(why.to_string()))?;
+    sb.check_layout().map_err(|why| invalid(why.to_string()))?;
+    if storage.file_len()? < sb.inode_table { return Err(invalid("the bitmaps are cut short".to_owned())) };
+    let data_bitmap = DataBitmap::from_bytes(storage.read(sb.data_bitmap, sb.blocks_count / 8)?);
+    let inode_bitmap = InodeBitmap::from_bytes(storage.read(sb.inode_bitmap, sb.inodes_count / 8)?);
+    Ok(Fs::with_bitmaps(storage, sb, data_bitmap, inode_bitmap))
+  }
+
+  fn with_bitmaps(storage: Storage, superblock: Superblock, data_bitmap: DataBitmap, inode_bitmap: InodeBitmap) -> Self {
+    let reserved = DataBitmap::new(superblock.blocks_count);
+    Fs {
+      superblock,
+      data_bitmap,
+      inode_bitmap,
+      storage,
+      reserved,
+      windows: HashMap::new(),
+      handles: HashMap::new(),
+      next_handle: 0,
+    }
+  }
+
+  // Opens the image at `filename`, writing `superblock` and empty bitmaps over whatever of them
+  // cannot be read, and a root directory if there is none
+  fn open_or_create(filename: &str, superblock: Superblock) -> Result<Self> {
     let mut storage = Storage::new(filename)?;
-    fn read_or_new<T: Default + Serialize + DeserializeOwned + Debug>
-                    (storage: &mut Storage, offset: usize, size: usize) -> Result<T> {
+    fn read_or_new<T: Serialize + DeserializeOwned + Debug>
+                    (storage: &mut Storage, offset: usize, size: usize, default: T) -> Result<T> {
       storage.read(offset, size).map_err(Error::msg)
         .and_then(|bytes| bincode::deserialize(&bytes).map_err(Error::msg))
         .or_else(|_| {
-          let default: T = Default::default();
           bincode::serialize(&default).map_err(Error::msg)
             .and_then(|bytes| storage.write(offset, &bytes).map_err(Error::msg))
             .map(|_| default)
         })
     }
-    let sb = read_or_new::<Superblock>(&mut storage, 0, SUPERBLOCK_SIZE)?;
-    let data_bitmap = read_or_new::<DataBitmap>(&mut storage, sb.data_bitmap, BLOCKS_BITMAP_SIZE)?;
-    let inode_bitmap = read_or_new::<InodeBitmap>(&mut storage, sb.inode_bitmap, INODES_BITMAP_SIZE)?;
-   
-    let mut fs = Fs {
-      superblock: sb,
-      data_bitmap: data_bitmap,
-      inode_bitmap: inode_bitmap,
-      storage: storage,
-      cur_dir: "/".to_owned(),
-      cur_inode_ind: 0,
-    };
+    let sb = read_or_new(&mut storage, 0, SUPERBLOCK_SIZE, superblock)?;
+    sb.check_layout()?;
+    // Bitmaps are kept as raw bytes, one bit per block or inode
+    fn read_or_new_bytes(storage: &mut Storage, offset: usize, default: Vec<u8>) -> Result<Vec<u8>> {
+      storage.read(offset, default.len())
+        .or_else(|_| storage.write(offset, &default).map(|_| default))
+        .map_err(Error::msg)
+    }
+    let mut empty = DataBitmap::new(sb.blocks_count);
+    empty.set(HOLE, true)?;
+    let data_bitmap = DataBitmap::from_bytes(read_or_new_bytes(&mut storage, sb.data_bitmap, empty.as_bytes().to_vec())?);
+    let mut empty = InodeBitmap::new(sb.inodes_count);
+    empty.set(UNUSED_INODE, true)?;
+    let inode_bitmap = InodeBitmap::from_bytes(read_or_new_bytes(&mut storage, sb.inode_bitmap, empty.as_bytes().to_vec())?);
 
-    let directory = Directory{ parent_inode_ind: None, files: vec![] };
-    fs.write_data(true, &directory)?;
+    let mut fs = Fs::with_bitmaps(storage, sb, data_bitmap, inode_bitmap);
+    fs.check_counters()?;
+    if fs.inode_bitmap.free_at(ROOT_INODE) {
+      let content = fs.new_dir_content(ROOT_INODE, ROOT_INODE);
+      fs.write_bytes(FileType::Directory, &content)?;
+    }
     Ok(fs)
   }
 
-  pub fn pwd(&self) -> &String {
-    &self.cur_dir
-  }
-
-  pub fn new_file<T: Serialize>(&mut self, filename: String, content: &T, is_directory: bool) -> Result<()> {
-    let mut cur_dir_inode = self.read_inode(self.cur_inode_ind)?;
-    let mut cur_dir: Directory = self.read_data(&cur_dir_inode)?;
-    if cur_dir.files.iter().find(|(_, name)| *name == filename).is_some() { 
-      return Err(anyhow!("File already exists: {}", filename)) 
-    };
-    let (data_inode_ind, _) = self.write_data(is_directory, &content)?;
-    cur_dir.files.push((data_inode_ind, filename)); 
-    self.update_data(&mut cur_dir_inode, &cur_dir)?;
-    self.update_inode(self.cur_inode_ind, &cur_dir_inode)?;
+  // The free counters of the superblock must agree with the bitmaps they summarize
+  fn check_counters(&self) -> Result<()> {
+    let free_blocks = self.data_bitmap.count_free();
+    if self.superblock.free_blocks_count != free_blocks {
+      return Err(anyhow!("Superblock counts {} free blocks, the bitmap {}", self.superblock.free_blocks_count, free_blocks))
+    }
+    let free_inodes = self.inode_bitmap.count_free();
+    if self.superblock.free_inodes_count != free_inodes {
+      return Err(anyhow!("Superblock counts {} free inodes, the bitmap {}", self.superblock.free_inodes_count, free_inodes))
+    }
     Ok(())
   }
 
-  pub fn touch(&mut self, filename: String, content: &[u8]) -> Result<()> {
-    if filename.chars().find(|&c| c == '/').is_some() { 
-      return Err(anyhow!("Illegal filename: {}", filename)) 
+  /// Total and free blocks and inodes, from the counters of the superblock
+  pub fn statfs(&self) -> Statfs {
+    Statfs {
+      block_size: self.superblock.block_size,
+      blocks: self.superblock.blocks_count,
+      free_blocks: self.superblock.free_blocks_count,
+      inodes: self.superblock.inodes_count,
+      free_inodes: self.superblock.free_inodes_count,
+      name_max: NAME_MAX,
     }
-    self.new_file(filename, &content, false)
   }
 
-  pub fn mkdir(&mut self, name: String) -> Result<()> {
-    if name.chars().find(|&c| c == '/').is_some() { 
-      return Err(anyhow!("Illegal directory name: {}", name)) 
+  fn metadata(&self, inode_ind: usize) -> Result<Metadata> {
+    let inode = self.read_inode(inode_ind)?;
+    let blocks = self.block_map(&inode)?.into_iter().filter(|&block| block != HOLE).count()
+      + self.index_blocks(&inode)?.len();
+    Ok(Metadata {
+      inode: inode_ind, file_type: inode.file_type(), size: inode.size,
+      allocated: blocks * self.superblock.block_size, mode: Some(inode.mode),
+      owner: Some((inode.uid, inode.gid)), mtime: inode.mtime,
+    })
+  }
+
+  fn handle_inode(&self, handle: Handle) -> Result<usize> {
+    let inode_ind = *self.handles.get(&handle.0).ok_or(anyhow!("Bad file handle"))?;
+    if self.inode_bitmap.free_at(inode_ind) { return Err(anyhow!("Stale file handle")) };
+    Ok(inode_ind)
+  }
+
+  // Walks `path` from the root, replacing every symbolic link on the way by its target
+  fn resolve_links(&self, path: &str, follow_last: bool) -> Result<usize> {
+    let mut resolved = path::resolve("/", path);
+    let mut links = 0;
+    'restart: loop {
+      let names: Vec<String> = path::components(&resolved).into_iter().map(str::to_owned).collect();
+      let mut inode_ind = ROOT_INODE;
+      for (i, name) in names.iter().enumerate() {
+        let dir = self.read_dir_inode(inode_ind)?;
+        let child_ind = self.find_record(&dir, name)?
+                          .ok_or(anyhow!("No such file or directory: {}", path))?.inode;
+        let child = self.read_inode(child_ind)?;
+        if child.is_symlink && (follow_last || i + 1 < names.len()) {
+          links += 1;
+          if links > MAX_SYMLINKS { return Err(anyhow!("Too many levels of symbolic links: {}", path)) };
+          let target = String::from_utf8_lossy(&self.read_bytes(&child)?).into_owned();
+          let parent = format!("/{}", names[..i].join("/"));
+          resolved = path::resolve(&parent, &format!("{}/{}", target, names[i + 1..].join("/")));
+          continue 'restart;
+        }
+        inode_ind = child_ind;
+      }
+      return Ok(inode_ind);
     }
-    let dirname = format!("{}/", name); 
-    let directory = Directory{ parent_inode_ind: Some(self.cur_inode_ind), files: vec![] };
-    self.new_file(dirname, &directory, true)
-  }
-
-  pub fn cat(&self, filename: String) -> Result<String> {
-    let cur_dir_inode = self.read_inode(self.cur_inode_ind)?;
-    let cur_dir: Directory = self.read_data(&cur_dir_inode)?;
-    let &(data_inode_ind, _) = cur_dir.files.iter()
-                                            .find(|(_, name)| *name == filename)
-                                            .ok_or(anyhow!("Unknown filename: {}", filename))?;
-    let data_inode = self.read_inode(data_inode_ind)?;
-    let content: String = self.read_data(&data_inode)?; 
-    Ok(content)
-  }
-
-  pub fn ls(&self) -> Result<Vec<String>> {
-    let cur_dir_inode = self.read_inode(self.cur_inode_ind)?;
-    let cur_dir: Directory = self.read_data(&cur_dir_inode)?;
-    let mut names: Vec<String> = cur_dir.files.iter().map(|(_, name)| format!("{}", name)).collect();
-    if cur_dir.parent_inode_ind.is_some() { names.push("..".to_owned()) };
-    Ok(names)
-  }
-
-  pub fn rm(&mut self, filename: String) -> Result<()> {
-    let mut cur_dir_inode = self.read_inode(self.cur_inode_ind)?;
-    let mut cur_dir: Directory = self.read_data(&cur_dir_inode)?;
-    let (i, &(data_inode_ind, _)) = cur_dir.files.iter().enumerate()
-                                            .find(|(_, (_, name))| *name == filename)
-                                            .ok_or(anyhow!("Unknown filename: {}", filename))?;
-    cur_dir.files.remove(i);  
-    self.update_data(&mut cur_dir_inode, &cur_dir)?;
-    self.update_inode(self.cur_inode_ind, &cur_dir_inode)?;
-    let data_inode = self.read_inode(data_inode_ind)?;
-    if data_inode.is_directory {
-      let dir: Directory = self.read_data(&data_inode)?;
-      for &(inode_ind, _) in dir.files.iter() {
-        self.free_inode(inode_ind)?;
+  }
+
+  fn read_dir_inode(&self, inode_ind: usize) -> Result<Inode> {
+    let inode = self.read_inode(inode_ind)?;
+    if !inode.is_directory { return Err(anyhow!("Is not a directory: inode {}", inode_ind)) };
+    Ok(inode)
+  }
+
+  // Runs `operation`, restoring the bitmaps and free counters if it fails. Operations allocate
+  // everything a new entry needs before linking it into its directory, and a directory update
+  // fails before writing anything when there is no room for it, so nothing on disk refers to
+  // blocks or inodes the restore frees again.
+  fn atomically<T, O: FnOnce(&mut Fs) -> Result<T>>(&mut self, operation: O) -> Result<T> {
+    let saved = (self.superblock.clone(), self.data_bitmap.clone(), self.inode_bitmap.clone(),
+                 self.reserved.clone(), self.windows.clone());
+    operation(self).or_else(|why| {
+      let (superblock, data_bitmap, inode_bitmap, reserved, windows) = saved;
+      self.superblock = superblock;
+      self.data_bitmap = data_bitmap;
+      self.inode_bitmap = inode_bitmap;
+      self.reserved = reserved;
+      self.windows = windows;
+      self.dump_superblock()?;
+      self.dump_data_bitmap()?;
+      self.dump_inode_bitmap()?;
+      Err(why)
+    })
+  }
+
+  fn new_file(&mut self, path: &str, content: &[u8], file_type: FileType) -> Result<()> {
+    self.atomically(|fs| {
+      let (parent, name) = path::split_new(path)?;
+      let parent_ind = fs.lookup(&parent)?;
+      let mut parent_inode = fs.read_dir_inode(parent_ind)?;
+      if fs.find_record(&parent_inode, &name)?.is_some() {
+        return Err(anyhow!("File already exists: {}", name))
+      };
+      let (data_inode_ind, _) = if file_type == FileType::Directory {
+        // `.` points at the inode `write_bytes` is about to take
+        let dir_ind = fs.inode_bitmap.find_free().ok_or(anyhow!("Could not locate free inode"))?;
+        fs.write_bytes(file_type, &fs.new_dir_content(dir_ind, parent_ind))?
+      } else {
+        fs.write_bytes(file_type, content)?
+      };
+      fs.add_record(parent_ind, &mut parent_inode, &DirRecord { inode: data_inode_ind, file_type, name })
+    })
+  }
+
+  fn free_tree(&mut self, inode_ind: usize) -> Result<()> {
+    let inode = self.read_inode(inode_ind)?;
+    if inode.is_directory {
+      for record in self.dir_records(&inode)?.into_iter().filter(|record| record.name != "." && record.name != "..") {
+        self.free_tree(record.inode)?;
       }
     }
-    self.free_inode(data_inode_ind)?;
-    Ok(())
+    self.free_inode(inode_ind)
+  }
+}
+
+impl Filesystem for Fs {
+  fn lookup(&self, path: &str) -> Result<usize> {
+    self.resolve_links(path, true)
+  }
+
+  fn stat(&self, path: &str) -> Result<Metadata> {
+    self.metadata(self.lookup(path)?)
+  }
+
+  fn lstat(&self, path: &str) -> Result<Metadata> {
+    self.metadata(self.resolve_links(path, false)?)
+  }
+
+  fn readdir(&self, path: &str) -> Result<Vec<DirEntry>> {
+    let dir = self.read_dir_inode(self.lookup(path)?)?;
+    self.dir_records(&dir)?.into_iter()
+      .filter(|record| record.name != "." && record.name != "..")
+      .map(|record| Ok(DirEntry::new(&record.name, self.metadata(record.inode)?)))
+      .collect()
+  }
+
+  fn read(&self, path: &str) -> Result<Vec<u8>> {
+    let inode = self.read_inode(self.lookup(path)?)?;
+    if inode.is_directory { return Err(anyhow!("Is a directory: {}", path)) };
+    self.read_bytes(&inode)
+  }
+
+  fn write(&mut self, path: &str, content: &[u8]) -> Result<()> {
+    self.atomically(|fs| {
+      let inode_ind = fs.lookup(path)?;
+      let mut inode = fs.read_inode(inode_ind)?;
+      if inode.is_directory { return Err(anyhow!("Is a directory: {}", path)) };
+      fs.update_bytes(&mut inode, content)?;
+      inode.mtime = now();
+      fs.update_inode(inode_ind, &inode)
+    })
+  }
+
+  fn open(&mut self, path: &str) -> Result<Handle> {
+    let inode_ind = self.lookup(path)?;
+    if self.read_inode(inode_ind)?.is_directory { return Err(anyhow!("Is a directory: {}", path)) };
+    let handle = self.next_handle;
+    self.next_handle += 1;
+    self.handles.insert(handle, inode_ind);
+    Ok(Handle(handle))
+  }
+
+  fn read_at(&self, handle: Handle, offset: usize, size: usize) -> Result<Vec<u8>> {
+    let inode = self.read_inode(self.handle_inode(handle)?)?;
+    let block_size = self.superblock.block_size;
+    let (from, to) = (std::cmp::min(offset, inode.size), std::cmp::min(inode.size, offset.saturating_add(size)));
+    if inode.inline_data { return Ok(inode.inline_bytes()[from..to].to_vec()) };
+    let mut bytes = vec![0; to - from];
+    let first = from / block_size;
+    for (block, &ind) in self.blocks_in(&inode, first..to.div_ceil(block_size))?.iter().enumerate().map(|(i, ind)| (first + i, ind)) {
+      if ind == HOLE { continue };
+      let block_start = block * block_size;
+      let (start, end) = (std::cmp::max(from, block_start), std::cmp::min(to, block_start + block_size));
+      let read = self.storage.read(self.superblock.data_blocks + ind * block_size + start - block_start, end - start)?;
+      bytes[start - from..end - from].copy_from_slice(&read);
+    }
+    Ok(bytes)
   }
 
-  pub fn cd(&mut self, name: String) -> Result<()> {
-    let dir_inode = self.read_inode(self.cur_inode_ind)?;
-    let cur_dir: Directory = self.read_data(&dir_inode)?;
-    if name == ".." {
-      match cur_dir.parent_inode_ind {
-        None => { return Err(anyhow!("Already at root")) },
-        Some(inode_ind) => {
-          self.cur_inode_ind = inode_ind;
-          let mut split: Vec<_> = self.cur_dir.split("/").collect();
-          split.remove(split.len() - 2);
-          let new_dir = split.join("/");
-          self.cur_dir = new_dir;
-          return Ok(());
+  fn write_at(&mut self, handle: Handle, offset: usize, data: &[u8]) -> Result<()> {
+    let inode_ind = self.handle_inode(handle)?;
+    self.atomically(|fs| {
+      let mut inode = fs.read_inode(inode_ind)?;
+      let block_size = fs.superblock.block_size;
+      let end = offset.checked_add(data.len()).ok_or_else(|| anyhow!("The file is too big"))?;
+      let new_size = std::cmp::max(inode.size, end);
+      if inode.inline_data {
+        let mut content = inode.inline_bytes();
+        if new_size <= INLINE_DATA_MAX {
+          content.resize(new_size, 0);
+          content[offset..end].copy_from_slice(data);
+          inode.set_inline_bytes(&content);
+          inode.mtime = now();
+          return fs.update_inode(inode_ind, &inode);
         }
+        // Only what is there yet moves to blocks, so that a gap up to `offset` stays a hole
+        inode.drop_inline_data();
+        fs.update_bytes(&mut inode, &content)?;
+      }
+      let blocks_needed = new_size.div_ceil(block_size);
+      if blocks_needed > fs.superblock.blocks_count || (!inode.extents && blocks_needed > INODE_LINKS) {
+        return Err(anyhow!("The file is too big"))
+      }
+      // Only the blocks the data lands in get allocated, the ones skipped over stay holes
+      let written = if data.is_empty() { 0..0 } else { offset / block_size..end.div_ceil(block_size) };
+      let mut blocks = fs.blocks_in(&inode, written.clone())?;
+      let missing: Vec<usize> = written.clone().filter(|&i| blocks[i - written.start] == HOLE).collect();
+      if let Some(&first) = missing.first() {
+        let goal = if first > written.start { blocks[first - written.start - 1] + 1 } else { fs.goal_before(&inode, first)? };
+        let found = fs.grow_blocks(inode_ind, goal, missing.len())?;
+        for (&i, block) in missing.iter().zip(found) { blocks[i - written.start] = block };
+        fs.map_blocks(&mut inode, written.start, &blocks)?;
       }
+      // Rewrites the blocks the data lands in, and the old last block if the file grows past it,
+      // zeroing the gap between the old end and `offset`
+      let last = inode.size / block_size;
+      let tail = if inode.size % block_size != 0 && new_size > inode.size && !written.contains(&last) {
+        vec![(last, fs.blocks_in(&inode, last..last + 1)?[0])]
+      } else { vec![] };
+      for (block, ind) in tail.into_iter().chain(written.clone().zip(blocks.iter().copied())) {
+        if ind == HOLE { continue };
+        let block_start = block * block_size;
+        let location = fs.superblock.data_blocks + ind * block_size;
+        let was_hole = missing.contains(&block);
+        let old_len = if was_hole { 0 } else { std::cmp::min(block_size, inode.size.saturating_sub(block_start)) };
+        let mut bytes = if old_len > 0 { fs.storage.read(location, old_len)? } else { vec![] };
+        bytes.resize(std::cmp::min(block_size, new_size - block_start), 0);
+        let (from, to) = (std::cmp::max(offset, block_start), std::cmp::min(end, block_start + bytes.len()));
+        if from < to { bytes[from - block_start..to - block_start].copy_from_slice(&data[from - offset..to - offset]) };
+        fs.storage.write(location, &bytes)?;
+      }
+      inode.size = new_size;
+      inode.mtime = now();
+      fs.update_inode(inode_ind, &inode)?;
+      fs.dump_data_bitmap()?;
+      fs.dump_superblock()
+    })
+  }
+
+  fn seek(&self, handle: Handle, offset: usize, whence: Whence) -> Result<Option<usize>> {
+    let inode = self.read_inode(self.handle_inode(handle)?)?;
+    if offset >= inode.size { return Ok(None) };
+    if inode.inline_data { return Ok(Some(if whence == Whence::Data { offset } else { inode.size })) };
+    let block_size = self.superblock.block_size;
+    let blocks = self.block_map(&inode)?;
+    let found = (offset / block_size..blocks.len()).find(|&i| (blocks[i] == HOLE) == (whence == Whence::Hole));
+    Ok(match (found, whence) {
+      (Some(block), _) => Some(std::cmp::max(offset, block * block_size)),
+      (None, Whence::Hole) => Some(inode.size),
+      (None, Whence::Data) => None,
+    })
+  }
+
+  fn punch_hole(&mut self, path: &str, offset: usize, len: usize) -> Result<()> {
+    self.atomically(|fs| {
+      let inode_ind = fs.lookup(path)?;
+      let mut inode = fs.read_inode(inode_ind)?;
+      if inode.is_directory { return Err(anyhow!("Is a directory: {}", path)) };
+      let block_size = fs.superblock.block_size;
+      let end = std::cmp::min(inode.size, offset.saturating_add(len));
+      if offset >= end { return Ok(()) };
+      if inode.inline_data {
+        let mut content = inode.inline_bytes();
+        content[offset..end].fill(0);
+        inode.set_inline_bytes(&content);
+        inode.mtime = now();
+        return fs.update_inode(inode_ind, &inode);
+      }
+      // Blocks entirely inside the hole are freed, counting the last one of the file whole
+      let last = if end == inode.size { inode.size.div_ceil(block_size) } else { end / block_size };
+      let first = std::cmp::min(offset.div_ceil(block_size), last);
+      let freed = fs.blocks_in(&inode, first..last)?;
+      // Splitting an extent may need a new node, so the tree is updated before any data is changed
+      fs.map_blocks(&mut inode, first, &vec![HOLE; last - first])?;
+      fs.release_blocks(&freed)?;
+      // Zeroes what is left of the hole in the blocks at either end
+      for block in [offset / block_size, (end - 1) / block_size] {
+        let ind = fs.blocks_in(&inode, block..block + 1)?[0];
+        if ind == HOLE { continue };
+        let block_start = block * block_size;
+        let (from, to) = (std::cmp::max(offset, block_start), std::cmp::min(end, block_start + block_size));
+        fs.storage.write(fs.superblock.data_blocks + ind * block_size + from - block_start, &vec![0; to - from])?;
+      }
+      inode.mtime = now();
+      fs.update_inode(inode_ind, &inode)?;
+      fs.dump_data_bitmap()?;
+      fs.dump_superblock()
+    })
+  }
+
+  fn close(&mut self, handle: Handle) -> Result<()> {
+    let inode_ind = self.handles.remove(&handle.0).ok_or(anyhow!("Bad file handle"))?;
+    if !self.handles.values().any(|&open| open == inode_ind) { self.release_window(inode_ind)? };
+    Ok(())
+  }
+
+  fn touch(&mut self, path: &str) -> Result<()> {
+    match self.lookup(path) {
+      Ok(inode_ind) => {
+        let mut inode = self.read_inode(inode_ind)?;
+        inode.mtime = now();
+        self.update_inode(inode_ind, &inode)
+      }
+      Err(_) => self.create(path, &[]),
     }
-    let dirname = format!("{}/", name); 
-    let &(data_inode_ind, _) = cur_dir.files.iter()
-                                            .find(|(_, name)| *name == dirname)
-                                            .ok_or(anyhow!("Unknown directory name: {}", dirname))?;
-    let data_inode = self.read_inode(data_inode_ind)?;
-    if !data_inode.is_directory { return Err(anyhow!("Is not a directory: {}", name)) };
-    self.cur_dir = format!("{}{}", self.cur_dir, dirname); 
-    self.cur_inode_ind = data_inode_ind;
+  }
+
+  fn set_mtime(&mut self, path: &str, mtime: u64) -> Result<()> {
+    let inode_ind = self.resolve_links(path, false)?;
+    let mut inode = self.read_inode(inode_ind)?;
+    inode.mtime = mtime;
+    self.update_inode(inode_ind, &inode)
+  }
+
+  fn set_mode(&mut self, path: &str, mode: u16) -> Result<()> {
+    let inode_ind = self.resolve_links(path, false)?;
+    let mut inode = self.read_inode(inode_ind)?;
+    inode.mode = mode & 0o7777;
+    self.update_inode(inode_ind, &inode)
+  }
+
+  fn set_owner(&mut self, path: &str, uid: u32, gid: u32) -> Result<()> {
+    let inode_ind = self.resolve_links(path, false)?;
+    let mut inode = self.read_inode(inode_ind)?;
+    inode.uid = uid;
+    inode.gid = gid;
+    self.update_inode(inode_ind, &inode)
+  }
+
+  fn create(&mut self, path: &str, content: &[u8]) -> Result<()> {
+    self.new_file(path, content, FileType::File)
+  }
+
+  fn mkdir(&mut self, path: &str) -> Result<()> {
+    self.new_file(path, &[], FileType::Directory)
+  }
+
+  fn symlink(&mut self, target: &str, path: &str) -> Result<()> {
+    if target.is_empty() { return Err(anyhow!("Symbolic link target is empty")) };
+    self.new_file(path, target.as_bytes(), FileType::Symlink)
+  }
+
+  fn readlink(&self, path: &str) -> Result<String> {
+    let inode = self.read_inode(self.resolve_links(path, false)?)?;
+    if !inode.is_symlink { return Err(anyhow!("Is not a symbolic link: {}", path)) };
+    Ok(String::from_utf8_lossy(&self.read_bytes(&inode)?).into_owned())
+  }
+
+  fn unlink(&mut self, path: &str) -> Result<()> {
+    self.atomically(|fs| {
+      let (parent, name) = path::split_parent(path)?;
+      let parent_ind = fs.lookup(&parent)?;
+      if name == "." || name == ".." { return Err(anyhow!("Cannot remove {}", path)) };
+      let mut parent_inode = fs.read_dir_inode(parent_ind)?;
+      let record = fs.remove_record(parent_ind, &mut parent_inode, &name)?;
+      fs.free_tree(record.inode)
+    })
+  }
+
+  fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+    self.atomically(|fs| {
+      path::split_new(to)?;
+      let from = path::resolve("/", from);
+      let to = path::resolve("/", to);
+      if to.starts_with(&format!("{}/", from)) {
+        return Err(anyhow!("Cannot move {} inside itself", from))
+      }
+      let (from_parent, from_name) = path::split_parent(&from)?;
+      let (to_parent, to_name) = path::split_parent(&to)?;
+      let from_parent_ind = fs.lookup(&from_parent)?;
+      let to_parent_ind = fs.lookup(&to_parent)?;
+
+      let from_inode = fs.read_dir_inode(from_parent_ind)?;
+      let moved = fs.find_record(&from_inode, &from_name)?
+                    .filter(|record| record.name != "." && record.name != "..")
+                    .ok_or(anyhow!("Unknown filename: {}", from_name))?;
+      let mut to_inode = fs.read_dir_inode(to_parent_ind)?;
+      if fs.find_record(&to_inode, &to_name)?.is_some() {
+        return Err(anyhow!("File already exists: {}", to_name))
+      }
+      // The new entry comes first, so that running out of space leaves the old one in place
+      fs.add_record(to_parent_ind, &mut to_inode, &DirRecord { name: to_name, ..moved.clone() })?;
+      let mut from_inode = fs.read_dir_inode(from_parent_ind)?;
+      fs.remove_record(from_parent_ind, &mut from_inode, &from_name)?;
+      if moved.file_type == FileType::Directory && from_parent_ind != to_parent_ind {
+        fs.set_parent_record(moved.inode, to_parent_ind)?;
+      }
+      Ok(())
+    })
+  }
+
+  fn sync(&mut self) -> Result<()> {
+    self.dump_data_bitmap()?;
+    self.dump_inode_bitmap()?;
+    self.dump_superblock()?;
+    self.storage.sync()?;
     Ok(())
   }
-} 
\ No newline at end of file
+
+  fn statfs(&self) -> Result<Statfs> {
+    Ok(Fs::statfs(self))
+  }
+}
+
+// Scratch files and images of the tests in every module
+#[cfg(test)]
+pub(crate) mod testing {
+  use super::Fs;
+  use std::path::{Path, PathBuf};
+
+  /// A path in the temporary directory, removed together with anything below it when dropped
+  pub(crate) struct TempPath(PathBuf);
+
+  impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+      let path = TempPath(std::env::temp_dir().join(format!("ext2-{}-{}", name, std::process::id())));
+      path.remove();
+      path
+    }
+
+    pub(crate) fn path(&self) -> &Path { &self.0 }
+
+    pub(crate) fn as_str(&self) -> &str { self.0.to_str().unwrap() }
+
+    fn remove(&self) {
+      if self.0.is_dir() { std::fs::remove_dir_all(&self.0).ok(); } else { std::fs::remove_file(&self.0).ok(); }
+    }
+  }
+
+  impl Drop for TempPath {
+    fn drop(&mut self) { self.remove() }
+  }
+
+  /// An empty image with the given features, deleted once the returned path is dropped
+  pub(crate) fn temp_fs(name: &str, feature_incompat: u32) -> (Fs, TempPath) {
+    let image = TempPath::new(&format!("{}.img", name));
+    (Fs::format(image.as_str(), feature_incompat).unwrap(), image)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use testing::{temp_fs, TempPath};
+  use walk::Walk;
+
+  #[test]
+  fn create_read_write() {
+    let (mut fs, _image) = temp_fs("create", 0);
+    fs.mkdir("/home").unwrap();
+    fs.create("/home/bashrc", b"export PATH=/home/bin").unwrap();
+    assert_eq!(fs.read("/home/bashrc").unwrap(), b"export PATH=/home/bin");
+    fs.write("/home/bashrc", b"").unwrap();
+    assert_eq!(fs.read("/home/bashrc").unwrap(), b"");
+    assert!(fs.create("/home/bashrc", b"").is_err());
+    assert!(fs.read("/home").is_err());
+    assert_eq!(fs.stat("/home").unwrap().file_type, FileType::Directory);
+  }
+
+  #[test]
+  fn touch() {
+    let (mut fs, _image) = temp_fs("touch", 0);
+    fs.touch("/empty").unwrap();
+    assert_eq!(fs.read("/empty").unwrap(), b"");
+    fs.write("/empty", b"line\r\nline\n").unwrap();
+    fs.touch("/empty").unwrap();
+    assert_eq!(fs.read("/empty").unwrap(), b"line\r\nline\n");
+    assert!(fs.stat("/empty").unwrap().mtime > 0);
+  }
+
+  #[test]
+  fn rename_and_unlink() {
+    let (mut fs, _image) = temp_fs("rename", 0);
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/b").unwrap();
+    fs.create("/a/file", b"content").unwrap();
+    fs.rename("/a", "/b/c").unwrap();
+    assert_eq!(fs.read("/b/c/file").unwrap(), b"content");
+    assert!(fs.rename("/b", "/b/c/d").is_err());
+    let names: Vec<_> = fs.readdir("/").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, vec!["b"]);
+    fs.unlink("/b").unwrap();
+    assert!(fs.lookup("/b/c/file").is_err());
+  }
+
+  #[test]
+  fn directory_records() {
+    let (mut fs, _image) = temp_fs("records", 0);
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/a/sub").unwrap();
+    for name in ["one", "two", "three"].iter() { fs.create(&format!("/a/{}", name), b"").unwrap() };
+    fs.unlink("/a/two").unwrap();
+    fs.create("/a/four", b"").unwrap();
+    let names: Vec<_> = fs.readdir("/a").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, vec!["sub", "one", "four", "three"]);
+    assert_eq!(fs.stat("/a").unwrap().size, BLOCK_SIZE);
+    for bad in ["/a/..", "/a/.", "/a/nul\0", ""].iter() {
+      assert!(fs.mkdir(bad).unwrap_err().downcast_ref::<path::NameError>().is_some());
+    }
+    assert!(fs.rename("/a/one", "/a/..").unwrap_err().downcast_ref::<path::NameError>().is_some());
+    assert_eq!(fs.readdir("/a").unwrap().len(), 4);
+
+    let sub = fs.read_inode(fs.lookup("/a/sub").unwrap()).unwrap();
+    let dots: Vec<_> = fs.dir_records(&sub).unwrap().into_iter().map(|r| (r.name, r.inode)).collect();
+    assert_eq!(dots, vec![(".".to_owned(), fs.lookup("/a/sub").unwrap()), ("..".to_owned(), fs.lookup("/a").unwrap())]);
+    fs.rename("/a/sub", "/moved").unwrap();
+    let moved = fs.read_inode(fs.lookup("/moved").unwrap()).unwrap();
+    assert_eq!(fs.find_record(&moved, "..").unwrap().unwrap().inode, ROOT_INODE);
+    assert!(fs.unlink("/moved/..").is_err());
+
+    // Entries that do not fit the first block split into two new ones under an index
+    let long = "x".repeat(NAME_MAX);
+    for i in 0..5 { fs.create(&format!("/a/{}{}", &long[1..], i), b"").unwrap() };
+    assert_eq!(fs.stat("/a").unwrap().size, 3 * BLOCK_SIZE);
+    assert!(fs.read_inode(fs.lookup("/a").unwrap()).unwrap().indexed);
+    assert!(fs.create(&format!("/a/{}", "x".repeat(NAME_MAX + 1)), b"").is_err());
+    assert_eq!(fs.readdir("/a").unwrap().len(), 8);
+  }
+
+  #[test]
+  fn inline_directories() {
+    let (mut fs, _image) = temp_fs("inline-dirs", INCOMPAT_INLINE_DATA);
+    fs.mkdir("/dir").unwrap();
+    assert_eq!(fs.stat("/dir").unwrap().allocated, 0);
+    for i in 0..5 { fs.create(&format!("/dir/file{}", i), b"").unwrap() };
+    assert_eq!(fs.stat("/dir").unwrap().size, BLOCK_SIZE);
+    let names: Vec<_> = fs.readdir("/dir").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, (0..5).map(|i| format!("file{}", i)).collect::<Vec<_>>());
+    fs.rename("/dir/file0", "/file0").unwrap();
+    assert_eq!(fs.readdir("/dir").unwrap().len(), 4);
+  }
+
+  #[test]
+  fn handles() {
+    let (mut fs, _image) = temp_fs("handles", 0);
+    fs.create("/file", b"hello").unwrap();
+    let handle = fs.open("/file").unwrap();
+    fs.write_at(handle, 1, b"ELL").unwrap();
+    fs.write_at(handle, BLOCK_SIZE + 2, b"far").unwrap();
+    assert_eq!(fs.read_at(handle, 0, 6).unwrap(), b"hELLo\0");
+    assert_eq!(fs.read_at(handle, BLOCK_SIZE, 10).unwrap(), b"\0\0far");
+    assert_eq!(fs.stat("/file").unwrap().size, BLOCK_SIZE + 5);
+    assert!(fs.write_at(handle, INODE_LINKS * BLOCK_SIZE, b"!").is_err());
+    assert_eq!(fs.write_at(handle, usize::MAX - 1, b"far").unwrap_err().to_string(), "The file is too big");
+    fs.close(handle).unwrap();
+    assert!(fs.close(handle).is_err());
+    assert!(fs.open("/").is_err());
+  }
+
+  #[test]
+  fn symlinks() {
+    let (mut fs, _image) = temp_fs("symlink", 0);
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/hosts", b"localhost").unwrap();
+    fs.symlink("etc", "/config").unwrap();
+    fs.symlink("../config/hosts", "/etc/alias").unwrap();
+    assert_eq!(fs.read("/config/hosts").unwrap(), b"localhost");
+    assert_eq!(fs.read("/etc/alias").unwrap(), b"localhost");
+    assert_eq!(fs.lstat("/config").unwrap().file_type, FileType::Symlink);
+    assert_eq!(fs.stat("/config").unwrap().file_type, FileType::Directory);
+    assert_eq!(fs.readlink("/etc/alias").unwrap(), "../config/hosts");
+    fs.symlink("loop", "/loop").unwrap();
+    assert!(fs.stat("/loop").is_err());
+    fs.unlink("/config").unwrap();
+    assert!(fs.read("/etc/alias").is_err());
+    assert!(fs.read("/etc/hosts").is_ok());
+  }
+
+  #[test]
+  fn statfs() {
+    let (mut fs, _image) = temp_fs("statfs", 0);
+    let before = fs.statfs();
+    assert_eq!((before.blocks, before.inodes, before.name_max), (BLOCKS_COUNT, INODES_COUNT, NAME_MAX));
+    fs.create("/file", &[1; BLOCK_SIZE + 1]).unwrap();
+    let after = fs.statfs();
+    assert_eq!(before.free_inodes - after.free_inodes, 1);
+    assert_eq!(before.free_blocks - after.free_blocks, 2);
+    fs.write("/file", b"short").unwrap();
+    assert_eq!(before.free_blocks - fs.statfs().free_blocks, 1);
+    fs.unlink("/file").unwrap();
+    assert_eq!(fs.statfs(), before);
+    fs.check_counters().unwrap();
+  }
+
+  #[test]
+  fn sparse_files() {
+    let (mut fs, _image) = temp_fs("sparse", 0);
+    let free = fs.statfs().free_blocks;
+    fs.create("/sparse", b"").unwrap();
+    let handle = fs.open("/sparse").unwrap();
+    fs.write_at(handle, 5 * BLOCK_SIZE + 10, b"tail").unwrap();
+    let meta = fs.stat("/sparse").unwrap();
+    assert_eq!((meta.size, meta.allocated), (5 * BLOCK_SIZE + 14, BLOCK_SIZE));
+    assert_eq!(fs.read_at(handle, 5 * BLOCK_SIZE - 2, 8).unwrap(), b"\0\0\0\0\0\0\0\0");
+    assert_eq!(&fs.read("/sparse").unwrap()[5 * BLOCK_SIZE + 8..], b"\0\0tail");
+    assert_eq!(fs.seek(handle, 0, Whence::Data).unwrap(), Some(5 * BLOCK_SIZE));
+    assert_eq!(fs.seek(handle, 7, Whence::Hole).unwrap(), Some(7));
+    assert_eq!(fs.seek(handle, 5 * BLOCK_SIZE + 3, Whence::Hole).unwrap(), Some(meta.size));
+    assert_eq!(fs.seek(handle, meta.size, Whence::Data).unwrap(), None);
+    fs.write_at(handle, BLOCK_SIZE, b"middle").unwrap();
+    assert_eq!(fs.seek(handle, 0, Whence::Data).unwrap(), Some(BLOCK_SIZE));
+    assert_eq!(fs.seek(handle, BLOCK_SIZE, Whence::Hole).unwrap(), Some(2 * BLOCK_SIZE));
+    fs.close(handle).unwrap();
+    assert_eq!(fs.statfs().free_blocks, free - 2);
+
+    fs.create("/full", &[7; 4 * BLOCK_SIZE]).unwrap();
+    fs.punch_hole("/full", 100, 2 * BLOCK_SIZE).unwrap();
+    let content = fs.read("/full").unwrap();
+    assert!(content[..100].iter().chain(&content[2 * BLOCK_SIZE + 100..]).all(|&b| b == 7));
+    assert!(content[100..2 * BLOCK_SIZE + 100].iter().all(|&b| b == 0));
+    assert_eq!(fs.stat("/full").unwrap().allocated, 3 * BLOCK_SIZE);
+    fs.punch_hole("/full", 3 * BLOCK_SIZE, 10 * BLOCK_SIZE).unwrap();
+    assert_eq!(fs.stat("/full").unwrap().size, 4 * BLOCK_SIZE);
+    assert_eq!(fs.stat("/full").unwrap().allocated, 2 * BLOCK_SIZE);
+    // Rewriting the whole file fills its holes again
+    fs.write("/full", &[1; 4 * BLOCK_SIZE]).unwrap();
+    assert_eq!(fs.stat("/full").unwrap().allocated, 4 * BLOCK_SIZE);
+    fs.unlink("/full").unwrap();
+    fs.unlink("/sparse").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+    fs.check_counters().unwrap();
+  }
+
+  #[test]
+  fn inline_data() {
+    let (mut fs, image) = temp_fs("inline", INCOMPAT_INLINE_DATA | INCOMPAT_EXTENTS);
+    let free = fs.statfs().free_blocks;
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/hostname", b"ext2\n").unwrap();
+    fs.symlink("/etc/hostname", "/hostname").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+    assert_eq!(fs.stat("/etc/hostname").unwrap().allocated, 0);
+    assert_eq!(fs.read("/hostname").unwrap(), b"ext2\n");
+
+    let handle = fs.open("/etc/hostname").unwrap();
+    fs.write_at(handle, 8, b"!").unwrap();
+    assert_eq!(fs.read_at(handle, 4, 10).unwrap(), b"\n\0\0\0!");
+    assert_eq!(fs.seek(handle, 2, Whence::Data).unwrap(), Some(2));
+    fs.punch_hole("/etc/hostname", 0, 2).unwrap();
+    assert_eq!(fs.read("/etc/hostname").unwrap(), b"\0\0t2\n\0\0\0!");
+    // Outgrowing the inode moves the content to blocks, skipping the gap
+    fs.write_at(handle, 3 * BLOCK_SIZE, b"far").unwrap();
+    assert_eq!(fs.stat("/etc/hostname").unwrap().allocated, 2 * BLOCK_SIZE);
+    assert_eq!(fs.read_at(handle, 0, 9).unwrap(), b"\0\0t2\n\0\0\0!");
+    assert_eq!(fs.seek(handle, BLOCK_SIZE, Whence::Data).unwrap(), Some(3 * BLOCK_SIZE));
+    fs.close(handle).unwrap();
+
+    for i in 0..20 { fs.create(&format!("/etc/{}", i), b"").unwrap() };
+    assert_eq!(fs.stat("/etc").unwrap().allocated, BLOCK_SIZE);
+    fs.write("/hostname", b"short").unwrap();
+    assert_eq!(fs.stat("/etc/hostname").unwrap().allocated, BLOCK_SIZE);
+    fs.unlink("/etc").unwrap();
+    fs.unlink("/hostname").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+    drop(fs);
+    Fs::new(image.as_str()).unwrap().check_counters().unwrap();
+  }
+
+  // Takes every free data block but `left`, as if the image was filled with files
+  fn occupy_blocks(fs: &mut Fs, left: usize) -> usize {
+    let mut taken = 0;
+    while fs.superblock.free_blocks_count > left {
+      let ind = fs.data_bitmap.find_free().unwrap();
+      fs.data_bitmap.set(ind, true).unwrap();
+      fs.superblock.free_blocks_count -= 1;
+      taken += 1;
+    }
+    taken
+  }
+
+  // Blocks taken by the files and directories reachable from the root, plus the ones `occupy_blocks`
+  // took and the one standing for holes
+  fn used_blocks(fs: &Fs, occupied: usize) -> usize {
+    let reachable: usize = Walk::new(fs, "/").map(|entry| entry.unwrap().entry.allocated / BLOCK_SIZE).sum();
+    reachable + occupied + 1
+  }
+
+  #[test]
+  fn fill_to_the_last_block() {
+    let (mut fs, _image) = temp_fs("fill", 0);
+    fs.mkdir("/fill").unwrap();
+    let before = fs.statfs();
+    let mut created = 0;
+    let why = loop {
+      match fs.create(&format!("/fill/{}", created), &[7; BLOCK_SIZE * 4]) {
+        Ok(()) => created += 1,
+        Err(why) => break why,
+      }
+    };
+    assert!(why.to_string().contains("free datablocks"), "{}", why);
+    fs.check_counters().unwrap();
+    assert_eq!(BLOCKS_COUNT - fs.statfs().free_blocks, used_blocks(&fs, 0));
+    assert!(fs.lookup(&format!("/fill/{}", created)).is_err());
+    while fs.create(&format!("/fill/small{}", created), &[7; 1]).is_ok() { created += 1 };
+    // What is left is too little for one more entry in /fill
+    let occupied = occupy_blocks(&mut fs, 0);
+    assert_eq!(BLOCKS_COUNT, used_blocks(&fs, occupied));
+    assert!(fs.mkdir("/dir").is_err());
+    assert!(fs.write("/fill/0", &[0; BLOCK_SIZE * 5]).is_err());
+    assert_eq!(fs.read("/fill/0").unwrap(), vec![7; BLOCK_SIZE * 4]);
+    fs.check_counters().unwrap();
+    fs.unlink("/fill").unwrap();
+    assert_eq!(fs.statfs().free_blocks, before.free_blocks + 1 - occupied);
+  }
+
+  #[test]
+  fn parent_without_room_rolls_back() {
+    let (mut fs, _image) = temp_fs("rollback", 0);
+    let name = "n".repeat(200);
+    // After `.` and `..`, which take 24 bytes, the first block of the root has room for 4 records
+    // of 212; the next one splits them into two new blocks under an index
+    let i = 4;
+    for j in 0..i { fs.create(&format!("/{}{:04}", name, j), b"").unwrap() };
+    assert_eq!(fs.stat("/").unwrap().size, BLOCK_SIZE);
+    let occupied = occupy_blocks(&mut fs, 2);
+    let stats = fs.statfs();
+    let child = format!("/{}{:04}", name, i);
+    assert!(fs.create(&child, &[1; BLOCK_SIZE]).is_err());
+    assert_eq!(fs.statfs(), stats);
+    assert!(fs.lookup(&child).is_err());
+    fs.check_counters().unwrap();
+    fs.create(&child, b"").unwrap();
+    assert_eq!(fs.statfs().free_blocks, 0);
+    assert_eq!(BLOCKS_COUNT, used_blocks(&fs, occupied));
+  }
+
+  #[test]
+  fn no_free_inode_releases_blocks() {
+    let (mut fs, _image) = temp_fs("inodes", 0);
+    while let Some(ind) = fs.inode_bitmap.find_free() {
+      fs.inode_bitmap.set(ind, true).unwrap();
+      fs.superblock.free_inodes_count -= 1;
+    }
+    let stats = fs.statfs();
+    assert!(fs.create("/file", &[1; BLOCK_SIZE * 3]).is_err());
+    assert_eq!(fs.statfs(), stats);
+    fs.check_counters().unwrap();
+  }
+
+  #[test]
+  fn remount() {
+    let image = TempPath::new("remount.img");
+    let mut fs = Fs::new(image.as_str()).unwrap();
+    fs.create("/kept", b"content").unwrap();
+    let stats = fs.statfs();
+    drop(fs);
+    let mut fs = Fs::new(image.as_str()).unwrap();
+    assert_eq!(fs.read("/kept").unwrap(), b"content");
+    assert_eq!(fs.statfs(), stats);
+    fs.superblock.free_blocks_count += 1;
+    fs.dump_superblock().unwrap();
+    drop(fs);
+    assert!(Fs::new(image.as_str()).is_err());
+  }
+
+  #[test]
+  fn open_leaves_other_files_alone() {
+    let notes = TempPath::new("notes.txt");
+    std::fs::write(notes.path(), b"not an image, just some notes\n").unwrap();
+    assert!(Fs::open_image(notes.as_str()).unwrap_err().to_string().contains("is not an image"));
+    assert_eq!(std::fs::read(notes.path()).unwrap(), b"not an image, just some notes\n");
+    std::fs::write(notes.path(), vec![0xff; 4096]).unwrap();
+    assert!(Fs::open_image(notes.as_str()).is_err());
+    assert_eq!(std::fs::read(notes.path()).unwrap(), vec![0xff; 4096]);
+    assert!(Fs::open_image(TempPath::new("missing.img").as_str()).is_err());
+
+    let (fs, image) = temp_fs("truncated", 0);
+    drop(fs);
+    Fs::open_image(image.as_str()).unwrap();
+    let bytes = std::fs::read(image.path()).unwrap();
+    std::fs::write(image.path(), &bytes[..SUPERBLOCK_SIZE + 10]).unwrap();
+    assert!(Fs::open_image(image.as_str()).is_err());
+    assert_eq!(std::fs::metadata(image.path()).unwrap().len() as usize, SUPERBLOCK_SIZE + 10);
+  }
+}
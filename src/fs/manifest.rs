@@ -0,0 +1,191 @@
+use crate::filesystem::Filesystem;
+use crate::path;
+use crate::shell::tokenize;
+use crate::structure::Superblock;
+use crate::Fs;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+
+// Images built from a manifest, byte for byte the same whenever the manifest and the host files
+// it names are. Every line describes one entry, words being split and quoted the way the shell
+// does it:
+//
+//   dir  PATH MODE UID GID MTIME
+//   file PATH MODE UID GID MTIME SOURCE   content of the host file SOURCE
+//   text PATH MODE UID GID MTIME CONTENT  CONTENT itself
+//   link PATH MODE UID GID MTIME TARGET
+//
+// MODE is octal, MTIME in seconds since the Unix epoch. Empty lines and those starting with `#`
+// are skipped. Directories missing from the manifest are made with mode 755, owned by root, at
+// time 0, and so is the root unless it has a line of its own.
+
+/// Where the content of an entry comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+  Directory,
+  Host(PathBuf),
+  Text(String),
+  Symlink(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+  pub path: String,
+  pub source: Source,
+  pub mode: u16,
+  pub uid: u32,
+  pub gid: u32,
+  pub mtime: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+  pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+  /// Reads the manifest in `text`, host sources being relative to `base`
+  pub fn parse(text: &str, base: &Path) -> Result<Manifest> {
+    let mut entries: Vec<ManifestEntry> = vec![];
+    for (i, line) in text.lines().enumerate() {
+      let fail = |why: String| anyhow!("Line {}: {}", i + 1, why);
+      if line.trim().is_empty() || line.trim_start().starts_with('#') { continue };
+      let words = tokenize(line).map_err(fail)?;
+      let entry = parse_entry(&words, base).map_err(fail)?;
+      if entries.iter().any(|other| other.path == entry.path) { return Err(fail(format!("{} is listed twice", entry.path))) };
+      entries.push(entry);
+    }
+    Ok(Manifest { entries })
+  }
+
+  /// Reads the manifest file at `file`, host sources being relative to the directory it is in
+  pub fn load(file: &Path) -> Result<Manifest> {
+    let text = fs::read_to_string(file).map_err(|why| anyhow!("{}: {}", file.display(), why))?;
+    Manifest::parse(&text, file.parent().unwrap_or(Path::new("")))
+  }
+}
+
+fn parse_entry(words: &[String], base: &Path) -> Result<ManifestEntry, String> {
+  let (kind, fields, operand) = match words {
+    [kind, ..] if !["dir", "file", "text", "link"].contains(&kind.as_str()) => return Err(format!("Unknown entry type: {}", kind)),
+    [kind, fields @ ..] if kind == "dir" && fields.len() == 5 => (kind, fields, None),
+    [kind, fields @ .., operand] if kind != "dir" && fields.len() == 5 => (kind, fields, Some(operand.clone())),
+    _ => return Err(format!("Expected {} fields", if words[0] == "dir" { 6 } else { 7 })),
+  };
+  let path = fields[0].clone();
+  if !path.starts_with('/') { return Err(format!("Path is not absolute: {}", path)) };
+  let path = path::resolve("/", &path);
+  let number = |name: &str, value: &str, radix| u64::from_str_radix(value, radix).map_err(|_| format!("Invalid {}: {}", name, value));
+  let mode = number("mode", &fields[1], 8)?;
+  if mode > 0o7777 { return Err(format!("Invalid mode: {}", fields[1])) };
+  let id = |name: &str, value: &str| value.parse::<u32>().map_err(|_| format!("Invalid {}: {}", name, value));
+  let (uid, gid) = (id("owner", &fields[2])?, id("group", &fields[3])?);
+  let mtime = number("time", &fields[4], 10)?;
+  let source = match (kind.as_str(), operand) {
+    ("dir", _) => Source::Directory,
+    ("file", Some(source)) => Source::Host(base.join(source)),
+    ("text", Some(content)) => Source::Text(content),
+    ("link", Some(target)) => Source::Symlink(target),
+    _ => unreachable!(),
+  };
+  if path == "/" && source != Source::Directory { return Err("The root must be a directory".to_owned()) };
+  Ok(ManifestEntry { path, source, mode: mode as u16, uid, gid, mtime })
+}
+
+/// Creates the image `filename` laid out by `superblock` and holding the entries of `manifest`,
+/// and returns its SHA-256. Entries are created in path order whatever the order of the manifest,
+/// which takes free inodes and blocks in the same order every time, and the unused parts of the
+/// image are zeroed.
+pub fn build(manifest: &Manifest, filename: &str, superblock: Superblock) -> Result<[u8; 32]> {
+  let mut fs = Fs::format_with(filename, superblock)?;
+  let mut entries: Vec<&ManifestEntry> = manifest.entries.iter().collect();
+  entries.sort_by_key(|entry| path::components(&entry.path));
+  let mut made = vec!["/".to_owned()];
+  for entry in entries.iter().filter(|entry| entry.path != "/") {
+    let fail = |why: anyhow::Error| why.context(format!("Could not add {}", entry.path));
+    // Missing parents sort before their entries, so only ones the manifest leaves out are made here
+    let components = path::components(&entry.path);
+    let mut dir = String::new();
+    for name in &components[..components.len() - 1] {
+      dir = format!("{}/{}", dir, name);
+      if fs.lstat(&dir).is_err() {
+        fs.mkdir(&dir).map_err(fail)?;
+        made.push(dir.clone());
+      }
+    }
+    match &entry.source {
+      Source::Directory => fs.mkdir(&entry.path),
+      Source::Host(source) => fs::read(source).map_err(|why| anyhow!("{}: {}", source.display(), why))
+        .and_then(|content| fs.create(&entry.path, &content)),
+      Source::Text(content) => fs.create(&entry.path, content.as_bytes()),
+      Source::Symlink(target) => fs.symlink(target, &entry.path),
+    }.map_err(fail)?;
+  }
+  // Adding entries changes the times of their directories, so attributes come once all are there
+  for dir in made.iter().filter(|dir| entries.iter().all(|entry| entry.path != **dir)) {
+    fs.set_mode(dir, 0o755)?;
+    fs.set_owner(dir, 0, 0)?;
+    fs.set_mtime(dir, 0)?;
+  }
+  for entry in entries.iter() {
+    fs.set_mode(&entry.path, entry.mode)?;
+    fs.set_owner(&entry.path, entry.uid, entry.gid)?;
+    fs.set_mtime(&entry.path, entry.mtime)?;
+  }
+  fs.zero_free()?;
+  fs.digest()
+}
+
+/// `digest` as lowercase hex digits
+pub fn hex(digest: &[u8]) -> String {
+  digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::FileType;
+  use crate::testing::TempPath;
+
+  #[test]
+  fn reproducible_builds() {
+    let source = TempPath::new("manifest-motd");
+    fs::write(source.path(), b"welcome\n").unwrap();
+    let manifest = format!("# base system
+      dir  /etc          750 0    0    1600000000
+      file /etc/motd     644 0    0    1600000001 {}
+      text '/home/me/a b' 600 1000 1000 1600000002 \"hello world\"
+      link /motd         777 0    0    1600000003 etc/motd
+    ", source.as_str());
+    let manifest = Manifest::parse(&manifest, Path::new("/")).unwrap();
+    assert_eq!(manifest.entries[2].source, Source::Text("hello world".to_owned()));
+
+    let image = TempPath::new("manifest.img");
+    let geometry = || Superblock::new(1024, 64, 16, 0).unwrap();
+    let digest = build(&manifest, image.as_str(), geometry()).unwrap();
+    let first = fs::read(image.path()).unwrap();
+    // The order of the lines does not matter
+    let reversed = Manifest { entries: manifest.entries.iter().rev().cloned().collect() };
+    assert_eq!(build(&reversed, image.as_str(), geometry()).unwrap(), digest);
+    assert_eq!(fs::read(image.path()).unwrap(), first);
+
+    let fs = Fs::open_image(image.as_str()).unwrap();
+    assert_eq!(fs.read("/etc/motd").unwrap(), b"welcome\n");
+    assert_eq!(fs.read("/motd").unwrap(), b"welcome\n");
+    let home = fs.stat("/home").unwrap();
+    assert_eq!((home.mode, home.mtime), (Some(0o755), 0));
+    let file = fs.read_inode(fs.lookup("/home/me/a b").unwrap()).unwrap();
+    assert_eq!((file.mode, file.uid, file.gid, file.mtime), (0o600, 1000, 1000, 1_600_000_002));
+    assert_eq!(fs.stat("/etc").unwrap().mode, Some(0o750));
+    assert_eq!(fs.lstat("/motd").unwrap().file_type, FileType::Symlink);
+
+    assert!(Manifest::parse("dir /etc 999 0 0 0", Path::new("/")).is_err());
+    assert!(Manifest::parse("file /etc 644 0 0 0", Path::new("/")).is_err());
+    assert_eq!(Manifest::parse("flie /x 644 0 0 0 src", Path::new("/")).unwrap_err().to_string(),
+               "Line 1: Unknown entry type: flie");
+    assert_eq!(Manifest::parse("dir /a 755 0 0 0\ndir /a/ 755 0 0 0", Path::new("/")).unwrap_err().to_string(),
+               "Line 2: /a is listed twice");
+  }
+}
@@ -0,0 +1,433 @@
+use crate::structure::*;
+use crate::Fs;
+
+use std::collections::HashMap;
+use std::ops::Range;
+use anyhow::{anyhow, Result};
+
+// Runs of consecutive blocks in `blocks`, the n-th of them being block `first + n` of the file;
+// holes are left out
+fn to_extents(first: usize, blocks: &[usize]) -> Vec<Extent> {
+  let mut extents: Vec<Extent> = vec![];
+  for (logical, &start) in blocks.iter().enumerate().map(|(i, start)| (first + i, start)).filter(|&(_, &start)| start != HOLE) {
+    match extents.last_mut() {
+      Some(last) if last.start + last.len == start && last.logical + last.len == logical => last.len += 1,
+      _ => extents.push(Extent { logical, start, len: 1 }),
+    }
+  }
+  extents
+}
+
+// `entries` in order, with the neighbours that continue each other joined
+fn joined(mut entries: Vec<Extent>) -> Vec<Extent> {
+  entries.sort_by_key(|extent| extent.logical);
+  let mut joined: Vec<Extent> = vec![];
+  for extent in entries {
+    match joined.last_mut() {
+      Some(last) if last.logical + last.len == extent.logical && last.start + last.len == extent.start => last.len += extent.len,
+      _ => joined.push(extent),
+    }
+  }
+  joined
+}
+
+// The parts of `extents` inside the file blocks `from..to`
+fn clipped(extents: &[Extent], from: usize, to: usize) -> Vec<Extent> {
+  extents.iter().filter(|extent| extent.logical < to && extent.logical + extent.len > from).map(|extent| {
+    let (first, end) = (std::cmp::max(from, extent.logical), std::cmp::min(to, extent.logical + extent.len));
+    Extent { logical: first, start: extent.start + first - extent.logical, len: end - first }
+  }).collect()
+}
+
+// Entry of an index node for the node at `block`, covering the file blocks from its first entry
+// to the end of its last
+fn index_entry(node: &ExtentNode, block: usize) -> Extent {
+  let (first, last) = (node.entries[0], node.entries[node.entries.len() - 1]);
+  Extent { logical: first.logical, start: block, len: last.logical + last.len - first.logical }
+}
+
+// `entries` spread evenly over as few nodes of `depth` as hold them
+fn split(entries: &[Extent], depth: usize, capacity: usize) -> Vec<ExtentNode> {
+  if entries.is_empty() { return vec![] };
+  let size = entries.len().div_ceil(entries.len().div_ceil(capacity));
+  entries.chunks(size).map(|chunk| ExtentNode { depth, entries: chunk.to_vec() }).collect()
+}
+
+// Nodes of an extent tree written and freed by an update. They only reach the disk once every
+// block the update needs is found, so that running out of space leaves the tree as it was.
+#[derive(Default)]
+struct TreeChanges {
+  written: HashMap<usize, ExtentNode>,
+  freed: Vec<usize>,
+}
+
+// Where the blocks of a file are. Inodes either point at their first `INODE_LINKS` blocks
+// directly, or, on images with the extents feature, keep the root of an extent tree in the same
+// slots. The root holds up to `INLINE_EXTENTS` entries; once they are not enough, the extents move
+// to leaf blocks and the root indexes them, with as many levels as needed. A change to the blocks
+// only rewrites the nodes covering them, splitting those that overflow and dropping those left
+// empty. Inodes with inline data have no blocks at all.
+impl Fs {
+  /// Entries that fit in a node taking a whole block of the image
+  pub(crate) fn extents_per_node(&self) -> usize {
+    extents_per_block(self.superblock.block_size)
+  }
+
+  fn read_node(&self, block: usize) -> Result<ExtentNode> {
+    let bytes = self.storage.read(self.superblock.data_blocks + block * self.superblock.block_size, self.superblock.block_size)?;
+    Ok(bincode::deserialize(&bytes)?)
+  }
+
+  fn write_node(&mut self, block: usize, node: &ExtentNode) -> Result<()> {
+    let mut bytes = bincode::serialize(node)?;
+    bytes.resize(self.superblock.block_size, 0);
+    self.storage.write(self.superblock.data_blocks + block * self.superblock.block_size, &bytes)?;
+    Ok(())
+  }
+
+  // The child `entry` of `node` points at
+  fn read_child(&self, node: &ExtentNode, entry: &Extent) -> Result<ExtentNode> {
+    let child = self.read_node(entry.start)?;
+    if child.depth + 1 != node.depth { return Err(anyhow!("Corrupted extent tree at block {}", entry.start)) };
+    Ok(child)
+  }
+
+  // Leaf extents below `node`, and the blocks of the nodes passed on the way
+  fn collect(&self, node: &ExtentNode, leaves: &mut Vec<Extent>, nodes: &mut Vec<usize>) -> Result<()> {
+    if node.depth == 0 {
+      leaves.extend(&node.entries);
+      return Ok(());
+    }
+    for entry in node.entries.iter() {
+      nodes.push(entry.start);
+      self.collect(&self.read_child(node, entry)?, leaves, nodes)?;
+    }
+    Ok(())
+  }
+
+  // Sets `blocks[i]` to the data block of file block `from + i`, for the extents below `node`
+  // that reach into the blocks `blocks` covers
+  fn collect_range(&self, node: &ExtentNode, from: usize, blocks: &mut [usize]) -> Result<()> {
+    let to = from + blocks.len();
+    for entry in node.entries.iter().filter(|entry| entry.logical < to && entry.logical + entry.len > from) {
+      if node.depth > 0 {
+        self.collect_range(&self.read_child(node, entry)?, from, blocks)?;
+        continue;
+      }
+      for extent in clipped(&[*entry], from, to) {
+        for i in 0..extent.len { blocks[extent.logical - from + i] = extent.start + i };
+      }
+    }
+    Ok(())
+  }
+
+  /// Number of blocks of the file, checked against what the inode can hold
+  pub(crate) fn block_count(&self, inode: &Inode) -> Result<usize> {
+    if inode.inline_data { return Ok(0) };
+    let count = inode.size.div_ceil(self.superblock.block_size);
+    if count > self.superblock.blocks_count || (!inode.extents && count > INODE_LINKS) {
+      return Err(anyhow!("Corrupted inode: {} bytes are more than it can hold", inode.size))
+    }
+    Ok(count)
+  }
+
+  /// Data block of every block of the file in order, `HOLE` for those without one
+  pub(crate) fn block_map(&self, inode: &Inode) -> Result<Vec<usize>> {
+    let count = self.block_count(inode)?;
+    if inode.inline_data { return Ok(vec![]) };
+    if !inode.extents { return Ok(inode.direct[..count].to_vec()) };
+    let (mut leaves, mut nodes) = (vec![], vec![]);
+    self.collect(&ExtentNode::from_slots(&inode.direct), &mut leaves, &mut nodes)?;
+    let mut blocks = vec![HOLE; count];
+    for extent in leaves {
+      for i in 0..extent.len {
+        *blocks.get_mut(extent.logical + i).ok_or(anyhow!("Extent past the end of the file"))? = extent.start + i;
+      }
+    }
+    Ok(blocks)
+  }
+
+  /// Data blocks of the file blocks in `range`, reading only the part of the extent tree that
+  /// covers them. Blocks past the end of the file are holes.
+  pub(crate) fn blocks_in(&self, inode: &Inode, range: Range<usize>) -> Result<Vec<usize>> {
+    let count = self.block_count(inode)?;
+    let mut blocks = vec![HOLE; range.len()];
+    if inode.inline_data { return Ok(blocks) };
+    if !inode.extents {
+      let end = std::cmp::min(range.end, count);
+      if range.start < end { blocks[..end - range.start].copy_from_slice(&inode.direct[range.start..end]) };
+      return Ok(blocks);
+    }
+    self.collect_range(&ExtentNode::from_slots(&inode.direct), range.start, &mut blocks)?;
+    Ok(blocks)
+  }
+
+  /// The block after the last data block of the file before block `logical`, where the file
+  /// should go on from there
+  pub(crate) fn goal_before(&self, inode: &Inode, logical: usize) -> Result<usize> {
+    if inode.inline_data { return Ok(0) };
+    if !inode.extents {
+      return Ok(crate::alloc::goal(&inode.direct[..std::cmp::min(logical, INODE_LINKS)]))
+    }
+    let mut node = ExtentNode::from_slots(&inode.direct);
+    loop {
+      let before = node.entries.iter().rev().find(|entry| entry.logical < logical).copied();
+      match before {
+        None => return Ok(0),
+        Some(entry) if node.depth == 0 => return Ok(entry.start + std::cmp::min(entry.len, logical - entry.logical)),
+        Some(entry) => node = self.read_child(&node, &entry)?,
+      }
+    }
+  }
+
+  /// Blocks taken by the extent tree of the file itself
+  pub(crate) fn index_blocks(&self, inode: &Inode) -> Result<Vec<usize>> {
+    if !inode.extents || inode.inline_data { return Ok(vec![]) };
+    let (mut leaves, mut nodes) = (vec![], vec![]);
+    self.collect(&ExtentNode::from_slots(&inode.direct), &mut leaves, &mut nodes)?;
+    Ok(nodes)
+  }
+
+  /// Makes `blocks` the blocks of the file from block `first` on, where `HOLE` unmaps a block.
+  /// Data blocks no longer mapped are left for the caller to release.
+  pub(crate) fn map_blocks(&mut self, inode: &mut Inode, first: usize, blocks: &[usize]) -> Result<()> {
+    let end = first.checked_add(blocks.len()).ok_or_else(|| anyhow!("The file is too big"))?;
+    if !inode.extents {
+      if end > INODE_LINKS { return Err(anyhow!("The file is too big")) };
+      inode.direct[first..end].copy_from_slice(blocks);
+      return Ok(());
+    }
+    let root = ExtentNode::from_slots(&inode.direct);
+    let mut changes = TreeChanges::default();
+    let mut depth = root.depth;
+    let mut entries = self.remap(root, first, end, &to_extents(first, blocks), &mut changes)?;
+    // The root moves down into blocks of its own once it overflows, and indexes them
+    while entries.len() > INLINE_EXTENTS {
+      let nodes = split(&entries, depth, self.extents_per_node());
+      entries = self.store_nodes(nodes, &mut changes)?;
+      depth += 1;
+    }
+    // and takes the entries of its only child back once they fit
+    while depth > 0 && entries.len() <= 1 {
+      if let Some(&entry) = entries.first() {
+        let child = match changes.written.get(&entry.start) {
+          Some(child) => child.clone(),
+          None => self.read_node(entry.start)?,
+        };
+        if child.entries.len() > INLINE_EXTENTS { break };
+        changes.written.remove(&entry.start);
+        changes.freed.push(entry.start);
+        entries = child.entries;
+      }
+      depth -= 1;
+    }
+    for (block, node) in changes.written.iter() {
+      self.write_node(*block, node)?;
+    }
+    self.release_blocks(&changes.freed)?;
+    inode.direct = ExtentNode { depth, entries }.to_slots();
+    Ok(())
+  }
+
+  // Replaces whatever `node` maps in the file blocks `from..to` with `new`, which lies inside
+  // them, returning the entries of the node afterwards. They may be too many for one node, or
+  // none at all. Below an index node, each child takes the blocks from its first one up to those
+  // of the next child, the first child all of those before too.
+  fn remap(&mut self, node: ExtentNode, from: usize, to: usize, new: &[Extent], changes: &mut TreeChanges) -> Result<Vec<Extent>> {
+    if node.depth == 0 {
+      let mut entries = new.to_vec();
+      for extent in node.entries {
+        entries.extend(clipped(&[extent], 0, from));
+        entries.extend(clipped(&[extent], to, usize::MAX));
+      }
+      return Ok(joined(entries));
+    }
+    let mut entries = vec![];
+    for (i, entry) in node.entries.iter().enumerate() {
+      let owned_from = if i == 0 { 0 } else { entry.logical };
+      let owned_to = node.entries.get(i + 1).map_or(usize::MAX, |next| next.logical);
+      let (lo, hi) = (std::cmp::max(from, owned_from), std::cmp::min(to, owned_to));
+      let inside = clipped(new, lo, hi);
+      let reached = lo < hi && (!inside.is_empty() || (entry.logical < hi && entry.logical + entry.len > lo));
+      if !reached { entries.push(*entry); continue };
+      let child = self.read_child(&node, entry)?;
+      let depth = child.depth;
+      let child_entries = self.remap(child, lo, hi, &inside, changes)?;
+      let mut nodes = split(&child_entries, depth, self.extents_per_node()).into_iter();
+      // The first node left keeps the block of the child, the others get new ones
+      match nodes.next() {
+        None => changes.freed.push(entry.start),
+        Some(first) => {
+          entries.push(index_entry(&first, entry.start));
+          changes.written.insert(entry.start, first);
+        }
+      }
+      entries.extend(self.store_nodes(nodes.collect(), changes)?);
+    }
+    Ok(entries)
+  }
+
+  // Takes a block near the data of each of `nodes` to write it to, returning the index entries
+  fn store_nodes(&mut self, nodes: Vec<ExtentNode>, changes: &mut TreeChanges) -> Result<Vec<Extent>> {
+    let mut entries = vec![];
+    for node in nodes {
+      let block = self.find_blocks(node.entries[0].start, 1)?[0];
+      self.take_blocks(&[block])?;
+      entries.push(index_entry(&node, block));
+      changes.written.insert(block, node);
+    }
+    Ok(entries)
+  }
+
+  /// Makes `blocks` the blocks of the file, building its extent tree anew. The old tree is left
+  /// taken for the caller to release, so that the new one never goes over it.
+  pub(crate) fn set_block_map(&mut self, inode: &mut Inode, blocks: &[usize]) -> Result<()> {
+    inode.direct = if inode.extents { ExtentNode::default().to_slots() } else { [HOLE; INODE_LINKS] };
+    self.map_blocks(inode, 0, blocks)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::{temp_fs, TempPath};
+
+  fn pattern(blocks: usize) -> Vec<u8> {
+    (0..blocks * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE + i) as u8).collect()
+  }
+
+  // Fills the image with single blocks and frees every other one, so that no two blocks a file
+  // gets afterwards follow each other. Returns the free blocks left.
+  fn checkerboard(fs: &mut Fs) -> usize {
+    let block_size = fs.superblock().block_size;
+    fs.mkdir("/fill").unwrap();
+    let mut count = 0;
+    while fs.create(&format!("/fill/{}", count), &vec![1; block_size]).is_ok() { count += 1 };
+    for i in (0..count).step_by(2) { fs.unlink(&format!("/fill/{}", i)).unwrap() };
+    fs.statfs().free_blocks
+  }
+
+  fn tree(fs: &Fs, path: &str) -> (ExtentNode, Vec<usize>) {
+    let inode = fs.read_inode(fs.lookup(path).unwrap()).unwrap();
+    (ExtentNode::from_slots(&inode.direct), fs.index_blocks(&inode).unwrap())
+  }
+
+  #[test]
+  fn extents_past_the_direct_slots() {
+    let (mut fs, _image) = temp_fs("mapping-extents", INCOMPAT_EXTENTS);
+    fs.create("/big", &pattern(20)).unwrap();
+    assert_eq!(fs.read("/big").unwrap(), pattern(20));
+    let inode = fs.read_inode(fs.lookup("/big").unwrap()).unwrap();
+    assert!(inode.extents);
+    assert_eq!(ExtentNode::from_slots(&inode.direct).entries.len(), 1);
+    fs.punch_hole("/big", 5 * BLOCK_SIZE, 10 * BLOCK_SIZE).unwrap();
+    let inode = fs.read_inode(fs.lookup("/big").unwrap()).unwrap();
+    assert_eq!(ExtentNode::from_slots(&inode.direct).entries.len(), 2);
+    let mut expected = pattern(20);
+    expected[5 * BLOCK_SIZE..15 * BLOCK_SIZE].fill(0);
+    assert_eq!(fs.read("/big").unwrap(), expected);
+    fs.write("/big", &pattern(2)).unwrap();
+    assert_eq!(fs.read("/big").unwrap(), pattern(2));
+
+    let (mut plain, _plain_image) = temp_fs("mapping-plain", 0);
+    assert_eq!(plain.create("/big", &pattern(20)).unwrap_err().to_string(), "The file is too big");
+    assert!(Fs::format(TempPath::new("mapping-unknown.img").as_str(), 0x10000).is_err());
+  }
+
+  #[test]
+  fn sizes_past_the_blocks_are_corrupt() {
+    for &features in [0, INCOMPAT_EXTENTS].iter() {
+      let (mut fs, _image) = temp_fs(&format!("mapping-corrupt-{}", features), features);
+      fs.create("/file", &pattern(2)).unwrap();
+      let handle = fs.open("/file").unwrap();
+      let past = fs.superblock().blocks_count * BLOCK_SIZE;
+      assert_eq!(fs.write_at(handle, past, b"x").unwrap_err().to_string(), "The file is too big");
+      fs.close(handle).unwrap();
+      let inode_ind = fs.lookup("/file").unwrap();
+      let mut inode = fs.read_inode(inode_ind).unwrap();
+      inode.size = if features == 0 { 13 * BLOCK_SIZE } else { usize::MAX };
+      fs.update_inode(inode_ind, &inode).unwrap();
+      assert!(fs.read("/file").unwrap_err().to_string().starts_with("Corrupted inode"));
+      let handle = fs.open("/file").unwrap();
+      assert!(fs.write_at(handle, 0, b"x").is_err());
+    }
+  }
+
+  #[test]
+  fn tree_grows_into_index_blocks() {
+    let (mut fs, _image) = temp_fs("mapping-tree", INCOMPAT_EXTENTS);
+    let free = checkerboard(&mut fs);
+
+    // The blocks of the file land in separate holes, so it needs more leaves than the root can index
+    fs.create("/scattered", &pattern(200)).unwrap();
+    let inode = fs.read_inode(fs.lookup("/scattered").unwrap()).unwrap();
+    let extents = crate::alloc::extents(&fs.block_map(&inode).unwrap());
+    assert!(extents > INLINE_EXTENTS * fs.extents_per_node());
+    assert_eq!(ExtentNode::from_slots(&inode.direct).depth, 2);
+    let nodes = fs.index_blocks(&inode).unwrap();
+    assert_eq!(nodes.len(), extents.div_ceil(fs.extents_per_node()) + 1);
+    assert_eq!(fs.statfs().free_blocks, free - 200 - nodes.len());
+    assert_eq!(fs.read("/scattered").unwrap(), pattern(200));
+
+    fs.write("/scattered", &pattern(3)).unwrap();
+    assert_eq!(fs.read("/scattered").unwrap(), pattern(3));
+    assert_eq!(ExtentNode::from_slots(&fs.read_inode(fs.lookup("/scattered").unwrap()).unwrap().direct).depth, 0);
+    fs.unlink("/scattered").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+  }
+
+  #[test]
+  fn chunked_writes_keep_the_tree() {
+    let (mut fs, _image) = temp_fs("mapping-chunks", INCOMPAT_EXTENTS);
+    let free = checkerboard(&mut fs);
+    fs.create("/grown", b"").unwrap();
+    let handle = fs.open("/grown").unwrap();
+    let content = pattern(200);
+    for block in 0..200 {
+      let (_, before) = tree(&fs, "/grown");
+      fs.write_at(handle, block * BLOCK_SIZE, &content[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]).unwrap();
+      // Each chunk adds to the leaf at the end, and only a split takes new nodes
+      let (root, after) = tree(&fs, "/grown");
+      assert!(before.iter().all(|node| after.contains(node)));
+      assert!(after.len() <= before.len() + root.depth);
+    }
+    fs.close(handle).unwrap();
+    assert_eq!(fs.read("/grown").unwrap(), content);
+    let (root, nodes) = tree(&fs, "/grown");
+    assert_eq!(root.depth, 2);
+
+    // Punching holes splits extents in place, emptied leaves go away
+    let mut expected = content.clone();
+    for block in (0..200).step_by(3) {
+      fs.punch_hole("/grown", block * BLOCK_SIZE, BLOCK_SIZE).unwrap();
+      expected[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].fill(0);
+    }
+    assert_eq!(fs.read("/grown").unwrap(), expected);
+    assert!(tree(&fs, "/grown").1.iter().all(|node| nodes.contains(node)));
+    fs.punch_hole("/grown", 0, 150 * BLOCK_SIZE).unwrap();
+    expected[..150 * BLOCK_SIZE].fill(0);
+    assert_eq!(fs.read("/grown").unwrap(), expected);
+    assert!(tree(&fs, "/grown").1.len() < nodes.len());
+
+    fs.write("/grown", b"").unwrap();
+    assert_eq!(tree(&fs, "/grown"), (ExtentNode::default(), vec![]));
+    fs.unlink("/grown").unwrap();
+    assert_eq!(fs.statfs().free_blocks, free);
+    fs.check_counters().unwrap();
+  }
+
+  #[test]
+  fn nodes_fill_large_blocks() {
+    let image = TempPath::new("mapping-large.img");
+    let mut fs = Fs::format_with(image.as_str(), Superblock::new(4096, 256, 512, INCOMPAT_EXTENTS).unwrap()).unwrap();
+    assert_eq!(fs.extents_per_node(), 170);
+    checkerboard(&mut fs);
+    // 50 extents are more than a node of 1024 bytes holds, and fit in one of 4096
+    fs.create("/file", &vec![7; 50 * 4096]).unwrap();
+    let (root, nodes) = tree(&fs, "/file");
+    assert_eq!((root.depth, root.entries.len(), nodes.len()), (1, 1, 1));
+    assert_eq!(root.entries[0].len, 50);
+    assert_eq!(fs.read("/file").unwrap(), vec![7; 50 * 4096]);
+  }
+}
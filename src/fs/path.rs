@@ -0,0 +1,156 @@
+use crate::structure::NAME_MAX;
+
+use std::fmt;
+use anyhow::{anyhow, Result};
+
+// Helpers for slash-separated paths inside an image. Every path handed to a
+// `Filesystem` is absolute; relative paths are resolved by the caller.
+
+/// Why a name cannot be given to a new entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+  Empty,
+  TooLong(usize),
+  Slash,
+  Nul,
+  Reserved(String),
+}
+
+impl fmt::Display for NameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      NameError::Empty => write!(f, "Invalid file name: empty"),
+      NameError::TooLong(len) => write!(f, "Invalid file name: {} bytes, at most {} allowed", len, NAME_MAX),
+      NameError::Slash => write!(f, "Invalid file name: contains '/'"),
+      NameError::Nul => write!(f, "Invalid file name: contains a NUL byte"),
+      NameError::Reserved(name) => write!(f, "Invalid file name: {} is reserved", name),
+    }
+  }
+}
+
+impl std::error::Error for NameError {}
+
+/// Checks `name` against the rules of ext2 for directory entries: 1 to `NAME_MAX` bytes, no `/`
+/// or NUL, and neither `.` nor `..`
+pub fn check_name(name: &str) -> std::result::Result<(), NameError> {
+  match name {
+    "" => Err(NameError::Empty),
+    "." | ".." => Err(NameError::Reserved(name.to_owned())),
+    _ if name.len() > NAME_MAX => Err(NameError::TooLong(name.len())),
+    _ if name.contains('/') => Err(NameError::Slash),
+    _ if name.contains('\0') => Err(NameError::Nul),
+    _ => Ok(()),
+  }
+}
+
+/// Resolves `path` against the absolute directory `cwd`, collapsing `.`, `..` and repeated slashes
+pub fn resolve(cwd: &str, path: &str) -> String {
+  let joined = if path.starts_with('/') { path.to_owned() } else { format!("{}/{}", cwd, path) };
+  let mut parts: Vec<&str> = vec![];
+  for part in joined.split('/') {
+    match part {
+      "" | "." => {},
+      ".." => { parts.pop(); },
+      name => parts.push(name),
+    }
+  }
+  format!("/{}", parts.join("/"))
+}
+
+/// Names of the directories leading to the entry `path` points at, root excluded
+pub fn components(path: &str) -> Vec<&str> {
+  path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+/// Splits `path` into its parent directory and the name of the entry itself
+pub fn split_parent(path: &str) -> Result<(String, String)> {
+  let resolved = resolve("/", path);
+  let ind = resolved.rfind('/').unwrap_or(0);
+  let name = &resolved[ind + 1..];
+  if name.is_empty() { return Err(anyhow!("Root directory has no parent")) };
+  Ok((resolve("/", &resolved[..ind]), name.to_owned()))
+}
+
+/// Like `split_parent` for an entry about to be created, whose name is checked as given rather
+/// than resolved, so that `/dir/..` is refused instead of naming the root
+pub fn split_new(path: &str) -> Result<(String, String)> {
+  let trimmed = path.trim_end_matches('/');
+  check_name(&trimmed[trimmed.rfind('/').map_or(0, |ind| ind + 1)..])?;
+  split_parent(path)
+}
+
+/// Whether `name` matches the shell pattern `pattern`: `*` stands for any text,
+/// `?` for any character, `[...]` for any listed character or range, `[!...]` for any other
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let name: Vec<char> = name.chars().collect();
+  fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+      None => name.is_empty(),
+      Some('*') => (0..=name.len()).any(|skip| matches(&pattern[1..], &name[skip..])),
+      Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+      Some('[') => match (pattern.iter().skip(2).position(|&c| c == ']'), name.first()) {
+        (Some(end), Some(&c)) => {
+          let class = &pattern[1..end + 2];
+          let (negated, class) = match class.first() { Some('!') => (true, &class[1..]), _ => (false, class) };
+          let mut found = false;
+          let mut i = 0;
+          while i < class.len() {
+            if i + 2 < class.len() && class[i + 1] == '-' { found |= class[i] <= c && c <= class[i + 2]; i += 3 }
+            else { found |= class[i] == c; i += 1 }
+          }
+          found != negated && matches(&pattern[end + 3..], &name[1..])
+        }
+        (None, Some('[')) => matches(&pattern[1..], &name[1..]),
+        _ => false,
+      },
+      Some(&c) => name.first() == Some(&c) && matches(&pattern[1..], &name[1..]),
+    }
+  }
+  matches(&pattern, &name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_paths() {
+    assert_eq!(resolve("/", "home"), "/home");
+    assert_eq!(resolve("/home", "../etc//passwd"), "/etc/passwd");
+    assert_eq!(resolve("/home", "/usr/./bin/"), "/usr/bin");
+    assert_eq!(resolve("/", ".."), "/");
+  }
+
+  #[test]
+  fn split_paths() {
+    assert_eq!(split_parent("/home/bashrc").unwrap(), ("/home".to_owned(), "bashrc".to_owned()));
+    assert_eq!(split_parent("/home").unwrap(), ("/".to_owned(), "home".to_owned()));
+    assert!(split_parent("/").is_err());
+  }
+
+  #[test]
+  fn names() {
+    assert_eq!(check_name("file.txt"), Ok(()));
+    assert_eq!(check_name(&"x".repeat(NAME_MAX)), Ok(()));
+    assert_eq!(check_name(&"x".repeat(NAME_MAX + 1)), Err(NameError::TooLong(NAME_MAX + 1)));
+    assert_eq!(check_name(""), Err(NameError::Empty));
+    assert_eq!(check_name(".."), Err(NameError::Reserved("..".to_owned())));
+    assert_eq!(check_name("a/b"), Err(NameError::Slash));
+    assert_eq!(check_name("a\0b"), Err(NameError::Nul));
+    assert_eq!(split_new("/home/").unwrap(), ("/".to_owned(), "home".to_owned()));
+    let error = split_new("/home/..").unwrap_err();
+    assert_eq!(error.downcast_ref::<NameError>(), Some(&NameError::Reserved("..".to_owned())));
+    assert!(split_new("/").is_err());
+  }
+
+  #[test]
+  fn globs() {
+    assert!(glob_match("*.rs", "lib.rs"));
+    assert!(!glob_match("*.rs", "lib.rs.bak"));
+    assert!(glob_match("l?b.[r-s]s", "lib.rs"));
+    assert!(glob_match("[!.]*", "file"));
+    assert!(!glob_match("[!.]*", ".hidden"));
+    assert!(glob_match("a[", "a["));
+  }
+}
@@ -0,0 +1,254 @@
+use crate::filesystem::FileType;
+use crate::structure::*;
+use crate::transfer::CHUNK_SIZE;
+use crate::Fs;
+
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+
+// Growing and shrinking images. The format has no block groups: the bitmaps, the inode table and
+// the data area follow each other at offsets fixed by the two counts, so any change of either
+// lays the whole image out again. A shrink first moves the blocks and inodes past the new counts
+// below them, file by file the way `defrag` does, which leaves the image whole if it stops there.
+// The areas then move to their new offsets and the bitmaps are cut or extended. That last part
+// overwrites the old layout as it goes, so an interrupted resize loses the image.
+
+/// What a resize moved out of the part of the image it removed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Resized {
+  pub blocks: usize,
+  pub inodes: usize,
+}
+
+impl Fs {
+  /// Lays the image out again with `blocks_count` data blocks and `inodes_count` inodes, both
+  /// multiples of 8, keeping the block size and features. Fails before changing anything if the
+  /// blocks or inodes in use do not fit, or if files are open.
+  pub fn resize(&mut self, blocks_count: usize, inodes_count: usize) -> Result<Resized> {
+    let old = self.superblock.clone();
+    let new = Superblock::new(old.block_size, blocks_count, inodes_count, old.feature_incompat)?;
+    if !self.handles.is_empty() { return Err(anyhow!("Close all files before resizing")) };
+    let used_blocks = old.blocks_count - old.free_blocks_count;
+    if used_blocks > blocks_count {
+      return Err(anyhow!("{} blocks are in use, more than the {} left after resizing", used_blocks, blocks_count))
+    }
+    let used_inodes = old.inodes_count - old.free_inodes_count;
+    if used_inodes > inodes_count {
+      return Err(anyhow!("{} inodes are in use, more than the {} left after resizing", used_inodes, inodes_count))
+    }
+    // Holes included, no file may span more blocks than the image has
+    let longest = (ROOT_INODE..old.inodes_count).filter(|&ind| !self.inode_bitmap.free_at(ind))
+      .try_fold(0, |longest, ind| self.read_inode(ind).map(|inode| std::cmp::max(longest, inode.size.div_ceil(old.block_size))))?;
+    if longest > blocks_count {
+      return Err(anyhow!("A file spans {} blocks, more than the {} left after resizing", longest, blocks_count))
+    }
+    let mut resized = Resized::default();
+    if blocks_count < old.blocks_count { resized.blocks = self.evacuate_blocks(blocks_count)? };
+    if inodes_count < old.inodes_count { resized.inodes = self.evacuate_inodes(inodes_count)? };
+    self.relayout(new)?;
+    Ok(resized)
+  }
+
+  // Moves every block from `limit` on below it, returning how many data blocks moved
+  fn evacuate_blocks(&mut self, limit: usize) -> Result<usize> {
+    // Holding back the blocks past the limit keeps new blocks and extent trees below it
+    for ind in limit..self.superblock.blocks_count { self.reserved.set(ind, true)? };
+    let moved = self.move_blocks_below(limit);
+    self.reserved = DataBitmap::new(self.superblock.blocks_count);
+    moved
+  }
+
+  fn move_blocks_below(&mut self, limit: usize) -> Result<usize> {
+    let mut count = 0;
+    for inode_ind in ROOT_INODE..self.superblock.inodes_count {
+      if self.inode_bitmap.free_at(inode_ind) { continue };
+      let inode = self.read_inode(inode_ind)?;
+      let blocks = self.block_map(&inode)?;
+      let outside = blocks.iter().filter(|&&block| block >= limit).count();
+      if outside == 0 && self.index_blocks(&inode)?.iter().all(|&node| node < limit) { continue };
+      let mut found = self.find_blocks(0, outside)?.into_iter();
+      let moved: Vec<usize> = blocks.iter().map(|&block| if block >= limit { found.next().unwrap() } else { block }).collect();
+      self.atomically(|fs| fs.move_blocks(inode_ind, inode, &blocks, &moved))?;
+      count += outside;
+    }
+    Ok(count)
+  }
+
+  // Moves every inode from `limit` on to a free one below it and points the directory entries
+  // at the new one, returning how many moved. Until the old inode is freed both share the blocks.
+  fn evacuate_inodes(&mut self, limit: usize) -> Result<usize> {
+    let used: Vec<usize> = (ROOT_INODE..self.superblock.inodes_count).filter(|&ind| !self.inode_bitmap.free_at(ind)).collect();
+    // Directory and name of the entries of every inode, `.` and `..` aside
+    let mut entries: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+    for &dir_ind in used.iter() {
+      let dir = self.read_inode(dir_ind)?;
+      if !dir.is_directory { continue };
+      for record in self.dir_records(&dir)?.into_iter().filter(|record| record.name != "." && record.name != "..") {
+        entries.entry(record.inode).or_default().push((dir_ind, record.name));
+      }
+    }
+    let mut count = 0;
+    for from in used.into_iter().filter(|&ind| ind >= limit) {
+      let to = self.inode_bitmap.find_free().filter(|&ind| ind < limit).ok_or(anyhow!("Could not locate free inode"))?;
+      let inode = self.read_inode(from)?;
+      self.update_inode(to, &inode)?;
+      self.inode_bitmap.set(to, true)?;
+      self.superblock.free_inodes_count -= 1;
+      self.dump_inode_bitmap()?;
+      self.dump_superblock()?;
+      self.storage.sync()?;
+      for (dir_ind, name) in entries.get(&from).cloned().unwrap_or_default() {
+        self.repoint_record(dir_ind, &name, to)?;
+      }
+      if inode.is_directory {
+        self.repoint_record(to, ".", to)?;
+        for record in self.dir_records(&inode)?.into_iter().filter(|record| record.name != "." && record.name != "..") {
+          if record.file_type == FileType::Directory { self.set_parent_record(record.inode, to)? };
+        }
+        for (dir_ind, _) in entries.values_mut().flatten().filter(|(dir_ind, _)| *dir_ind == from) { *dir_ind = to };
+      }
+      self.storage.sync()?;
+      self.inode_bitmap.set(from, false)?;
+      self.superblock.free_inodes_count += 1;
+      self.dump_inode_bitmap()?;
+      self.dump_superblock()?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  // Moves the inode table and the data area to where `new` puts them, the first `min` of the
+  // old and new counts of each, and writes the bitmaps and superblock of the new layout
+  fn relayout(&mut self, new: Superblock) -> Result<()> {
+    let old = self.superblock.clone();
+    let (old_size, new_size) = (old.image_size(), new.image_size());
+    // Images opened with `Fs::new` only take as much of the file as has been written
+    let len = std::cmp::max(self.storage.file_len()?, std::cmp::max(old_size, new_size));
+    self.storage.set_len(len)?;
+    let kept_inodes = std::cmp::min(old.inodes_count, new.inodes_count) * INODE_SIZE;
+    let kept_blocks = std::cmp::min(old.blocks_count, new.blocks_count) * old.block_size;
+    // The area moving up goes first, so neither runs over the other before it has moved
+    if new.data_blocks > old.data_blocks {
+      self.move_area(old.data_blocks, new.data_blocks, kept_blocks)?;
+      self.move_area(old.inode_table, new.inode_table, kept_inodes)?;
+    } else {
+      self.move_area(old.inode_table, new.inode_table, kept_inodes)?;
+      self.move_area(old.data_blocks, new.data_blocks, kept_blocks)?;
+    }
+    for ind in old.inodes_count..new.inodes_count {
+      self.storage.write(new.inode_table + ind * INODE_SIZE, &[0; INODE_SIZE])?;
+    }
+    let mut bits = self.data_bitmap.as_bytes().to_vec();
+    bits.resize(new.blocks_count / 8, 0);
+    self.data_bitmap = DataBitmap::from_bytes(bits);
+    let mut bits = self.inode_bitmap.as_bytes().to_vec();
+    bits.resize(new.inodes_count / 8, 0);
+    self.inode_bitmap = InodeBitmap::from_bytes(bits);
+    self.superblock = Superblock {
+      free_blocks_count: self.data_bitmap.count_free(),
+      free_inodes_count: self.inode_bitmap.count_free(),
+      ..new
+    };
+    self.reserved = DataBitmap::new(self.superblock.blocks_count);
+    self.windows.clear();
+    self.dump_superblock()?;
+    self.dump_data_bitmap()?;
+    self.dump_inode_bitmap()?;
+    self.storage.set_len(new_size)?;
+    self.storage.sync()?;
+    Ok(())
+  }
+
+  // Copies `len` bytes from `from` to `to`, from the end when moving up so that the ranges may overlap
+  fn move_area(&mut self, from: usize, to: usize, len: usize) -> Result<()> {
+    if from == to { return Ok(()) };
+    let mut chunks: Vec<usize> = (0..len).step_by(CHUNK_SIZE).collect();
+    if to > from { chunks.reverse() };
+    for offset in chunks {
+      let bytes = self.storage.read(from + offset, std::cmp::min(CHUNK_SIZE, len - offset))?;
+      self.storage.write(to + offset, &bytes)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesystem::Filesystem;
+  use crate::testing::TempPath;
+  use crate::walk::Walk;
+
+  // Path and content of every entry, directories having none
+  fn tree(fs: &Fs) -> Vec<(String, Vec<u8>)> {
+    Walk::new(fs, "/").map(|item| {
+      let item = item.unwrap();
+      let content = if item.entry.file_type == FileType::File { fs.read(&item.path).unwrap() } else { vec![] };
+      (item.path, content)
+    }).collect()
+  }
+
+  fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251 + seed) as u8).collect()
+  }
+
+  #[test]
+  fn grows_and_shrinks() {
+    let path = TempPath::new("resize.img");
+    let image = path.as_str();
+    let mut fs = Fs::format_with(image, Superblock::new(1024, 32, 16, INCOMPAT_EXTENTS).unwrap()).unwrap();
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/motd", &pattern(3000, 1)).unwrap();
+    fs.symlink("etc/motd", "/motd").unwrap();
+    let before = tree(&fs);
+
+    assert_eq!(fs.resize(256, 64).unwrap(), Resized::default());
+    assert_eq!(tree(&fs), before);
+    assert_eq!(std::fs::metadata(path.path()).unwrap().len() as usize, fs.superblock().image_size());
+    let stats = fs.statfs();
+    assert_eq!((stats.blocks, stats.inodes), (256, 64));
+    // The new room is usable, for blocks and inodes past the old counts alike
+    fs.mkdir("/big").unwrap();
+    for i in 0..20 { fs.create(&format!("/big/{}", i), &pattern(4096, i)).unwrap() };
+    fs.mkdir("/big/sub").unwrap();
+    fs.create("/big/sub/deep", &pattern(20 * 1024, 7)).unwrap();
+    let sub = fs.lookup("/big/sub").unwrap();
+    assert!(sub >= 16);
+
+    for i in 0..20 { fs.unlink(&format!("/big/{}", i)).unwrap() };
+    let before = tree(&fs);
+    let resized = fs.resize(48, 16).unwrap();
+    assert!(resized.blocks > 0 && resized.inodes > 0);
+    assert_eq!(tree(&fs), before);
+    let sub = fs.lookup("/big/sub").unwrap();
+    assert!(sub < 16);
+    let dir = fs.read_inode(sub).unwrap();
+    assert_eq!(fs.find_record(&dir, "..").unwrap().unwrap().inode, fs.lookup("/big").unwrap());
+    assert_eq!(fs.find_record(&dir, ".").unwrap().unwrap().inode, sub);
+
+    drop(fs);
+    let mut fs = Fs::open_image(image).unwrap();
+    assert_eq!(tree(&fs), before);
+    assert_eq!(std::fs::metadata(path.path()).unwrap().len() as usize, fs.superblock().image_size());
+    fs.create("/after", &pattern(2048, 3)).unwrap();
+    assert_eq!(fs.read("/after").unwrap(), pattern(2048, 3));
+  }
+
+  #[test]
+  fn too_much_data_fails_cleanly() {
+    let path = TempPath::new("resize-full.img");
+    let mut fs = Fs::format_with(path.as_str(), Superblock::new(1024, 64, 16, 0).unwrap()).unwrap();
+    fs.create("/a", &pattern(10 * 1024, 1)).unwrap();
+    fs.create("/b", &pattern(10 * 1024, 2)).unwrap();
+    let image = std::fs::read(path.path()).unwrap();
+    // The files, the root and block 0 take 22 blocks
+    assert_eq!(fs.resize(16, 16).unwrap_err().to_string(), "22 blocks are in use, more than the 16 left after resizing");
+    let handle = fs.open("/a").unwrap();
+    assert!(fs.resize(128, 16).is_err());
+    fs.close(handle).unwrap();
+    assert_eq!(std::fs::read(path.path()).unwrap(), image);
+    assert_eq!(fs.read("/b").unwrap(), pattern(10 * 1024, 2));
+    assert_eq!(fs.resize(24, 8).unwrap(), Resized::default());
+    assert_eq!(fs.read("/b").unwrap(), pattern(10 * 1024, 2));
+  }
+}
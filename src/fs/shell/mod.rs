@@ -0,0 +1,8 @@
+// Command line parsing and execution for the ext2server shell
+
+mod parse;
+mod run;
+mod text;
+
+pub use parse::*;
+pub use run::*;
@@ -0,0 +1,596 @@
+use crate::filesystem::FileType;
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+// Name, arguments and description of every command, used by `help` and in usage errors
+const COMMANDS: &[(&str, &str, &str)] = &[
+  ("pwd", "", "prints active directory"),
+  ("ls", "[-laRSt] [path]...", "lists `path` or the active directory: -l long, -a all, -R recursive, -S/-t by size/time"),
+  ("exit", "", "exits the application"),
+  ("help", "", "prints this message"),
+  ("cd", "[dest]", "sets active directory to `dest`, or to the root"),
+  ("touch", "name...", "creates empty files or updates their modification time"),
+  ("write", "name <<EOF", "writes the next lines up to `EOF` into the file"),
+  ("write", "name size", "writes the next `size` bytes as they are into the file"),
+  ("mkdir", "[-p] name...", "creates directories, with missing parents if -p is given"),
+  ("cat", "[name]...", "prints the content of the files, or the input"),
+  ("echo", "[-n] [word]...", "prints the words, without the final newline if -n is given"),
+  ("head", "[-n count] [name]...", "prints the first `count` (10) lines of the files or the input"),
+  ("tail", "[-n count] [name]...", "prints the last `count` (10) lines of the files or the input"),
+  ("wc", "[-lwc] [name]...", "counts lines, words and bytes of the files or the input"),
+  ("grep", "[-rivn] regex [name]...", "prints matching lines of the files or the input, -r descends into directories"),
+  ("sort", "[-rnu] [name]...", "prints the lines of the files or the input in order"),
+  ("diff", "name name", "prints the lines that differ between two files"),
+  ("ln", "-s target name", "creates a symbolic link `name` pointing to `target`"),
+  ("tree", "[-L depth] [path]", "prints the directory tree below `path` or the active directory"),
+  ("du", "[-bhs] [path]...", "prints the space taken below every directory of `path`, file sizes if -b, only the total if -s"),
+  ("df", "[-h]", "prints used and free blocks and inodes, block counts as sizes if -h is given"),
+  ("find", "[path]... [test]...", "prints entries below `path` passing every -name glob, -type f|d|l, -size [+-]n[ckM]"),
+  ("rm", "[-rf] name...", "removes files, directories too if -r is given"),
+  ("mv", "src... dest", "moves or renames files and directories"),
+  ("tar", "-c [-f archive] [-C dir] path...", "archives the paths, relative to `dir`, into `archive` or the output"),
+  ("tar", "-x [-f archive] [-C dir]", "extracts `archive` or the input into `dir` or the active directory"),
+  ("tar", "-t [-f archive]", "lists the entries of `archive` or the input"),
+  ("put", "[-r] local remote", "uploads a host file or directory (ext2client only)"),
+  ("get", "[-r] remote local", "downloads a file or directory to the host (ext2client only)"),
+];
+
+pub fn help() -> String {
+  let lines: Vec<String> = COMMANDS.iter().map(|(name, args, description)| {
+    format!("{:<30}- {}", format!("{:<6}{}", name, args), description)
+  }).collect();
+  lines.join("\n")
+}
+
+fn usage(name: &str) -> String {
+  let usages: Vec<String> = COMMANDS.iter()
+    .filter(|(command, _, _)| *command == name)
+    .map(|(command, args, _)| format!("usage: {} {}", command, args).trim_end().to_owned())
+    .collect();
+  usages.join("\n")
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Token {
+  Word(String),
+  Pipe,
+  Redirect { append: bool },
+}
+
+/// Splits a command line into words and operators the way a POSIX shell does: words are
+/// separated by whitespace, single quotes keep everything literally, double quotes keep
+/// everything but `\"` and `\\`, and a backslash outside of quotes escapes the next character.
+/// Unquoted `|`, `>` and `>>` are operators.
+pub fn lex(line: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = vec![];
+  let mut token: Option<String> = None;
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {
+        if let Some(word) = token.take() { tokens.push(Token::Word(word)) };
+      }
+      '|' | '>' => {
+        if let Some(word) = token.take() { tokens.push(Token::Word(word)) };
+        if c == '|' { tokens.push(Token::Pipe) }
+        else { tokens.push(Token::Redirect { append: chars.next_if_eq(&'>').is_some() }) }
+      }
+      '\'' => {
+        let word = token.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push(c),
+            None => return Err("Unterminated single quote".to_owned()),
+          }
+        }
+      }
+      '"' => {
+        let word = token.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c @ ('"' | '\\')) => word.push(c),
+              Some(c) => { word.push('\\'); word.push(c) },
+              None => return Err("Unterminated double quote".to_owned()),
+            },
+            Some(c) => word.push(c),
+            None => return Err("Unterminated double quote".to_owned()),
+          }
+        }
+      }
+      '\\' => match chars.next() {
+        Some(c) => token.get_or_insert_with(String::new).push(c),
+        None => return Err("Trailing backslash".to_owned()),
+      },
+      c => token.get_or_insert_with(String::new).push(c),
+    }
+  }
+  if let Some(word) = token { tokens.push(Token::Word(word)) };
+  Ok(tokens)
+}
+
+/// Splits a command line into words, see `lex`. Operators are kept as words of their own.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+  Ok(lex(line)?.into_iter().map(|token| match token {
+    Token::Word(word) => word,
+    Token::Pipe => "|".to_owned(),
+    Token::Redirect { append: false } => ">".to_owned(),
+    Token::Redirect { append: true } => ">>".to_owned(),
+  }).collect())
+}
+
+/// Quotes `word` so that `tokenize` turns it back into a single word
+pub fn quote(word: &str) -> String {
+  let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:@%+=,".contains(c);
+  if !word.is_empty() && word.chars().all(plain) { return word.to_owned() };
+  format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// Options and operands of a command line
+struct Options<'a> {
+  flags: Vec<(char, Option<&'a str>)>,
+  operands: Vec<&'a str>,
+}
+
+impl<'a> Options<'a> {
+  fn has(&self, flag: char) -> bool {
+    self.flags.iter().any(|(f, _)| *f == flag)
+  }
+
+  /// Value of the last occurrence of `flag`
+  fn value(&self, flag: char) -> Option<&'a str> {
+    self.flags.iter().rev().find(|(f, _)| *f == flag).and_then(|(_, value)| *value)
+  }
+
+  fn owned_operands(&self) -> Vec<String> {
+    self.operands.iter().map(|s| (*s).to_owned()).collect()
+  }
+}
+
+/// Separates the single-letter options of `name` from its operands, getopt-style: `spec` lists
+/// the accepted letters, those followed by `:` take a value (`-n 20` or `-n20`). Options come
+/// first and may be combined (`-rf`); `--` ends them, a lone `-` is an operand.
+fn options<'a>(name: &str, args: &'a [String], spec: &str) -> Result<Options<'a>, String> {
+  let mut flags = vec![];
+  let mut rest = args.iter();
+  let mut operands = vec![];
+  while let Some(arg) = rest.next() {
+    if arg == "--" { break };
+    match arg.strip_prefix('-') {
+      Some(letters) if !letters.is_empty() => {
+        for (i, letter) in letters.char_indices() {
+          let position = spec.find(letter).filter(|_| letter != ':')
+            .ok_or_else(|| format!("{}: invalid option -- '{}'\n{}", name, letter, usage(name)))?;
+          if !spec[position + 1..].starts_with(':') { flags.push((letter, None)); continue };
+          let value = match &letters[i + 1..] {
+            "" => rest.next().map(|value| value.as_str())
+                    .ok_or_else(|| format!("{}: option requires an argument -- '{}'\n{}", name, letter, usage(name)))?,
+            attached => attached,
+          };
+          flags.push((letter, Some(value)));
+          break;
+        }
+      }
+      _ => { operands.push(arg.as_str()); break },
+    }
+  }
+  operands.extend(rest.map(|arg| arg.as_str()));
+  Ok(Options { flags, operands })
+}
+
+// Where the content of a `write` comes from
+#[derive(Debug, PartialEq)]
+pub enum WriteInput {
+  Heredoc(String),
+  Bytes(usize),
+}
+
+impl FromStr for WriteInput {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<WriteInput, Self::Err> {
+    match s.strip_prefix("<<") {
+      Some("") => Err("Missing heredoc terminator".to_owned()),
+      Some(terminator) => Ok(WriteInput::Heredoc(terminator.to_owned())),
+      None => s.parse().map(WriteInput::Bytes).map_err(|_| format!("Invalid byte count: {}", s)),
+    }
+  }
+}
+
+// How `ls` prints directories
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct Listing {
+  pub long: bool,
+  pub all: bool,
+  pub recursive: bool,
+  pub order: Order,
+}
+
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub enum Order {
+  #[default]
+  Directory,
+  /// Largest first
+  Size,
+  /// Most recently modified first
+  Time,
+}
+
+// A condition of `find`
+#[derive(Debug, PartialEq)]
+pub enum FindTest {
+  Name(String),
+  Type(FileType),
+  /// Size in `unit`s, rounded up, compared to `count`: more, less or exactly
+  Size { cmp: Ordering, count: usize, unit: usize },
+}
+
+impl FindTest {
+  fn parse(predicate: &str, arg: &str) -> Result<FindTest, String> {
+    match predicate {
+      "-name" => Ok(FindTest::Name(arg.to_owned())),
+      "-type" => match arg {
+        "f" => Ok(FindTest::Type(FileType::File)),
+        "d" => Ok(FindTest::Type(FileType::Directory)),
+        "l" => Ok(FindTest::Type(FileType::Symlink)),
+        _ => Err(format!("find: unknown argument to -type: {}", arg)),
+      },
+      "-size" => {
+        let (cmp, rest) = match arg.chars().next() {
+          Some('+') => (Ordering::Greater, &arg[1..]),
+          Some('-') => (Ordering::Less, &arg[1..]),
+          _ => (Ordering::Equal, arg),
+        };
+        let (digits, unit) = match rest.char_indices().last() {
+          Some((i, 'c')) => (&rest[..i], 1),
+          Some((i, 'k')) => (&rest[..i], 1024),
+          Some((i, 'M')) => (&rest[..i], 1024 * 1024),
+          _ => (rest, 512),
+        };
+        let count = digits.parse().map_err(|_| format!("find: invalid argument to -size: {}", arg))?;
+        Ok(FindTest::Size { cmp, count, unit })
+      }
+      _ => Err(format!("find: unknown predicate: {}", predicate)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarMode {
+  Create,
+  Extract,
+  List,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Pwd,
+  Ls(Listing, Vec<String>),
+  Help,
+  Exit,
+  Cd(Option<String>),
+  Touch(Vec<String>),
+  Write(String, WriteInput),
+  Mkdir { parents: bool, names: Vec<String> },
+  Cat(Vec<String>),
+  Echo { newline: bool, words: Vec<String> },
+  Head { count: usize, names: Vec<String> },
+  Tail { count: usize, names: Vec<String> },
+  Wc { lines: bool, words: bool, bytes: bool, names: Vec<String> },
+  Grep { pattern: String, recursive: bool, ignore_case: bool, invert: bool, line_numbers: bool, names: Vec<String> },
+  Sort { reverse: bool, numeric: bool, unique: bool, names: Vec<String> },
+  Diff(String, String),
+  Ln { target: String, name: String },
+  Tree { depth: Option<usize>, path: Option<String> },
+  /// Counts allocated blocks, or the sizes of the files if `apparent`
+  Du { human: bool, summarize: bool, apparent: bool, names: Vec<String> },
+  Df { human: bool },
+  Find { paths: Vec<String>, tests: Vec<FindTest> },
+  Rm { recursive: bool, force: bool, names: Vec<String> },
+  Mv(Vec<String>, String),
+  /// Archive read from or written to the file `archive`, or the input or output
+  Tar { mode: TarMode, archive: Option<String>, dir: Option<String>, names: Vec<String> },
+  Empty,
+  // Wire commands of ext2client put/get, see `transfer`
+  Download(String),
+  Entries(String),
+  Mkdirp(String),
+}
+
+impl FromStr for Command {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Command, Self::Err> {
+    let words = lex(s)?.into_iter().map(|token| match token {
+      Token::Word(word) => Ok(word),
+      _ => Err("Operators are only allowed in pipelines".to_owned()),
+    }).collect::<Result<Vec<_>, _>>()?;
+    Command::from_words(&words)
+  }
+}
+
+impl Command {
+  pub fn from_words(tokens: &[String]) -> Result<Command, String> {
+    let (name, args) = match tokens.split_first() {
+      None => return Ok(Command::Empty),
+      Some((name, args)) => (name.as_str(), args),
+    };
+    let usage_err = || Err(usage(name));
+    let count = |opts: &Options| match opts.value('n') {
+      None => Ok(10),
+      Some(value) => value.parse().map_err(|_| format!("{}: invalid number of lines: {}", name, value)),
+    };
+    match name {
+      "pwd" | "exit" | "help" => {
+        if !options(name, args, "")?.operands.is_empty() { return usage_err() };
+        Ok(match name { "pwd" => Command::Pwd, "exit" => Command::Exit, _ => Command::Help })
+      }
+      "ls" => {
+        let opts = options(name, args, "laRSt")?;
+        let order = opts.flags.iter().rev().find_map(|(flag, _)| match flag {
+          'S' => Some(Order::Size),
+          't' => Some(Order::Time),
+          _ => None,
+        }).unwrap_or_default();
+        let listing = Listing { long: opts.has('l'), all: opts.has('a'), recursive: opts.has('R'), order };
+        Ok(Command::Ls(listing, opts.owned_operands()))
+      }
+      "cd" => match options(name, args, "")?.operands.as_slice() {
+        [] => Ok(Command::Cd(None)),
+        [dest] => Ok(Command::Cd(Some((*dest).to_owned()))),
+        _ => usage_err(),
+      },
+      "touch" => match options(name, args, "")? {
+        opts if opts.operands.is_empty() => usage_err(),
+        opts => Ok(Command::Touch(opts.owned_operands())),
+      },
+      "cat" => Ok(Command::Cat(options(name, args, "")?.owned_operands())),
+      "echo" => match args.split_first() {
+        Some((flag, words)) if flag == "-n" => Ok(Command::Echo { newline: false, words: words.to_vec() }),
+        _ => Ok(Command::Echo { newline: true, words: args.to_vec() }),
+      },
+      "write" => match options(name, args, "")?.operands.as_slice() {
+        [file, "<<", terminator] => Ok(Command::Write((*file).to_owned(), WriteInput::Heredoc((*terminator).to_owned()))),
+        [file, input] => input.parse().map(|input| Command::Write((*file).to_owned(), input)),
+        _ => usage_err(),
+      },
+      "mkdir" => match options(name, args, "p")? {
+        opts if opts.operands.is_empty() => usage_err(),
+        opts => Ok(Command::Mkdir { parents: opts.has('p'), names: opts.owned_operands() }),
+      },
+      "rm" => match options(name, args, "rf")? {
+        opts if opts.operands.is_empty() => usage_err(),
+        opts => Ok(Command::Rm { recursive: opts.has('r'), force: opts.has('f'), names: opts.owned_operands() }),
+      },
+      "mv" => match options(name, args, "")?.owned_operands().split_last() {
+        Some((dest, sources)) if !sources.is_empty() => Ok(Command::Mv(sources.to_vec(), dest.clone())),
+        _ => usage_err(),
+      },
+      "head" | "tail" => {
+        let opts = options(name, args, "n:")?;
+        let (count, names) = (count(&opts)?, opts.owned_operands());
+        Ok(if name == "head" { Command::Head { count, names } } else { Command::Tail { count, names } })
+      }
+      "wc" => {
+        let opts = options(name, args, "lwc")?;
+        let all = opts.flags.is_empty();
+        Ok(Command::Wc {
+          lines: all || opts.has('l'),
+          words: all || opts.has('w'),
+          bytes: all || opts.has('c'),
+          names: opts.owned_operands(),
+        })
+      }
+      "grep" => match options(name, args, "rivn")? {
+        opts if opts.operands.is_empty() => usage_err(),
+        opts => Ok(Command::Grep {
+          pattern: opts.operands[0].to_owned(),
+          recursive: opts.has('r'),
+          ignore_case: opts.has('i'),
+          invert: opts.has('v'),
+          line_numbers: opts.has('n'),
+          names: opts.owned_operands().split_off(1),
+        }),
+      },
+      "sort" => {
+        let opts = options(name, args, "rnu")?;
+        Ok(Command::Sort { reverse: opts.has('r'), numeric: opts.has('n'), unique: opts.has('u'), names: opts.owned_operands() })
+      }
+      "diff" => match options(name, args, "")?.operands.as_slice() {
+        [a, b] => Ok(Command::Diff((*a).to_owned(), (*b).to_owned())),
+        _ => usage_err(),
+      },
+      "ln" => match options(name, args, "s")? {
+        opts if !opts.has('s') => Err("ln: only symbolic links (-s) are supported".to_owned()),
+        opts => match opts.operands.as_slice() {
+          [target, link] => Ok(Command::Ln { target: (*target).to_owned(), name: (*link).to_owned() }),
+          _ => usage_err(),
+        },
+      },
+      "tree" => {
+        let opts = options(name, args, "L:")?;
+        let depth = match opts.value('L') {
+          None => None,
+          Some(value) => Some(value.parse().map_err(|_| format!("tree: invalid depth: {}", value))?),
+        };
+        match opts.operands.as_slice() {
+          [] => Ok(Command::Tree { depth, path: None }),
+          [path] => Ok(Command::Tree { depth, path: Some((*path).to_owned()) }),
+          _ => usage_err(),
+        }
+      }
+      "du" => {
+        let opts = options(name, args, "bhs")?;
+        Ok(Command::Du { human: opts.has('h'), summarize: opts.has('s'), apparent: opts.has('b'), names: opts.owned_operands() })
+      }
+      "df" => match options(name, args, "h")? {
+        opts if opts.operands.is_empty() => Ok(Command::Df { human: opts.has('h') }),
+        _ => usage_err(),
+      },
+      "find" => {
+        let split = args.iter().position(|arg| arg.starts_with('-') && arg.len() > 1).unwrap_or(args.len());
+        let mut tests = vec![];
+        let mut predicates = args[split..].iter();
+        while let Some(predicate) = predicates.next() {
+          let arg = predicates.next().ok_or_else(|| format!("find: missing argument to {}", predicate))?;
+          tests.push(FindTest::parse(predicate, arg)?);
+        }
+        Ok(Command::Find { paths: args[..split].to_vec(), tests })
+      }
+      "tar" => {
+        let opts = options(name, args, "cxtf:C:")?;
+        let mode = match (opts.has('c'), opts.has('x'), opts.has('t')) {
+          (true, false, false) => TarMode::Create,
+          (false, true, false) => TarMode::Extract,
+          (false, false, true) => TarMode::List,
+          _ => return Err(format!("tar: exactly one of -c, -x and -t is needed\n{}", usage(name))),
+        };
+        if (mode == TarMode::Create) == opts.operands.is_empty() { return usage_err() };
+        let (archive, dir) = (opts.value('f').map(str::to_owned), opts.value('C').map(str::to_owned));
+        Ok(Command::Tar { mode, archive, dir, names: opts.owned_operands() })
+      }
+      "download" | "entries" | "mkdirp" => match args {
+        [file] if name == "download" => Ok(Command::Download(file.clone())),
+        [file] if name == "entries" => Ok(Command::Entries(file.clone())),
+        [file] => Ok(Command::Mkdirp(file.clone())),
+        _ => Err(format!("Malformed {} request", name)),
+      },
+      _ => Err(format!("Unknown command: {}", name)),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Redirect {
+  pub path: String,
+  pub append: bool,
+}
+
+// Commands connected with `|`, the output of the last one optionally redirected into a file
+#[derive(Debug, PartialEq)]
+pub struct Pipeline {
+  pub stages: Vec<Command>,
+  pub redirect: Option<Redirect>,
+}
+
+impl Pipeline {
+  /// The command itself if the pipeline consists of it alone
+  pub fn single(&self) -> Option<&Command> {
+    match self.stages.as_slice() {
+      [command] if self.redirect.is_none() => Some(command),
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for Pipeline {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Pipeline, Self::Err> {
+    let mut stages = vec![];
+    let mut words = vec![];
+    let mut redirect = None;
+    let mut tokens = lex(s)?.into_iter();
+    while let Some(token) = tokens.next() {
+      if redirect.is_some() { return Err("Redirection must end the pipeline".to_owned()) };
+      match token {
+        Token::Word(word) => words.push(word),
+        Token::Pipe if words.is_empty() => return Err("Missing command before |".to_owned()),
+        Token::Pipe => stages.push(Command::from_words(&words.split_off(0))?),
+        Token::Redirect { append } => match tokens.next() {
+          Some(Token::Word(path)) => redirect = Some(Redirect { path, append }),
+          _ => return Err("Missing redirection target".to_owned()),
+        },
+      }
+    }
+    if words.is_empty() && !stages.is_empty() { return Err("Missing command after |".to_owned()) };
+    stages.push(Command::from_words(&words)?);
+    Ok(Pipeline { stages, redirect })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tokenize_words() {
+    assert_eq!(tokenize("  ls   -l  ").unwrap(), vec!["ls", "-l"]);
+    assert_eq!(tokenize(r#"cat 'my file' "a \"b\"" c\ d e\\f"#).unwrap(),
+               vec!["cat", "my file", "a \"b\"", "c d", "e\\f"]);
+    assert_eq!(tokenize(r#"touch '' "x"'y'"#).unwrap(), vec!["touch", "", "xy"]);
+    assert!(tokenize("cat 'open").is_err());
+    assert!(tokenize("cat \"open").is_err());
+    assert!(tokenize("cat trailing\\").is_err());
+  }
+
+  #[test]
+  fn lex_operators() {
+    assert_eq!(lex("ls|grep 'a|b'>>out").unwrap(), vec![
+      Token::Word("ls".into()), Token::Pipe, Token::Word("grep".into()), Token::Word("a|b".into()),
+      Token::Redirect { append: true }, Token::Word("out".into()),
+    ]);
+    assert_eq!(tokenize("echo a\\>b > c").unwrap(), vec!["echo", "a>b", ">", "c"]);
+  }
+
+  #[test]
+  fn parse_pipelines() {
+    let pipeline: Pipeline = "cat a | cat > 'b c'".parse().unwrap();
+    assert_eq!(pipeline.stages, vec![Command::Cat(vec!["a".into()]), Command::Cat(vec![])]);
+    assert_eq!(pipeline.redirect, Some(Redirect { path: "b c".into(), append: false }));
+    assert_eq!("".parse::<Pipeline>().unwrap().single(), Some(&Command::Empty));
+    assert!("ls |".parse::<Pipeline>().is_err());
+    assert!("| ls".parse::<Pipeline>().is_err());
+    assert!("ls >".parse::<Pipeline>().is_err());
+    assert!("ls > a | cat".parse::<Pipeline>().is_err());
+    assert!("ls | rm".parse::<Pipeline>().is_err());
+  }
+
+  #[test]
+  fn quote_roundtrip() {
+    for word in &["plain.txt", "with space", "it's", "", "$HOME", "a\\b", "a|b>c"] {
+      assert_eq!(tokenize(&quote(word)).unwrap(), vec![word.to_string()]);
+    }
+  }
+
+  #[test]
+  fn parse_commands() {
+    assert_eq!("rm -rf a 'b c'".parse(), Ok(Command::Rm { recursive: true, force: true, names: vec!["a".into(), "b c".into()] }));
+    assert_eq!("rm -- -r".parse(), Ok(Command::Rm { recursive: false, force: false, names: vec!["-r".into()] }));
+    assert_eq!("mv a b dir".parse(), Ok(Command::Mv(vec!["a".into(), "b".into()], "dir".into())));
+    assert_eq!("write f <<EOF".parse(), Ok(Command::Write("f".into(), WriteInput::Heredoc("EOF".into()))));
+    assert_eq!("write f << END".parse(), Ok(Command::Write("f".into(), WriteInput::Heredoc("END".into()))));
+    assert_eq!("write f 12".parse(), Ok(Command::Write("f".into(), WriteInput::Bytes(12))));
+    assert_eq!("cd".parse(), Ok(Command::Cd(None)));
+    assert_eq!("find / -type d -size +2k".parse(), Ok(Command::Find { paths: vec!["/".into()], tests: vec![
+      FindTest::Type(FileType::Directory), FindTest::Size { cmp: Ordering::Greater, count: 2, unit: 1024 },
+    ]}));
+    assert_eq!("find -name".parse::<Command>(), Err("find: missing argument to -name".to_owned()));
+    assert_eq!("tree -L 2".parse(), Ok(Command::Tree { depth: Some(2), path: None }));
+    assert_eq!("ls -lt -S dir".parse(), Ok(Command::Ls(
+      Listing { long: true, all: false, recursive: false, order: Order::Size }, vec!["dir".into()],
+    )));
+    assert_eq!("".parse(), Ok(Command::Empty));
+    assert_eq!("rm".parse::<Command>(), Err("usage: rm [-rf] name...".to_owned()));
+    assert_eq!("rm -x a".parse::<Command>(), Err("rm: invalid option -- 'x'\nusage: rm [-rf] name...".to_owned()));
+    assert_eq!("tail -: a".parse::<Command>(), Err("tail: invalid option -- ':'\nusage: tail [-n count] [name]...".to_owned()));
+    assert_eq!("pwd extra".parse::<Command>(), Err("usage: pwd".to_owned()));
+    assert_eq!("head -n 3 a".parse(), Ok(Command::Head { count: 3, names: vec!["a".into()] }));
+    assert_eq!("tail -n3".parse(), Ok(Command::Tail { count: 3, names: vec![] }));
+    assert_eq!("head -n".parse::<Command>(), Err("head: option requires an argument -- 'n'\nusage: head [-n count] [name]...".to_owned()));
+    assert_eq!("wc -l".parse(), Ok(Command::Wc { lines: true, words: false, bytes: false, names: vec![] }));
+    assert_eq!("grep -rn 'a b' dir".parse(), Ok(Command::Grep {
+      pattern: "a b".into(), recursive: true, ignore_case: false, invert: false, line_numbers: true, names: vec!["dir".into()],
+    }));
+    assert_eq!("tar -cf a.tar -C /etc hosts".parse(), Ok(Command::Tar {
+      mode: TarMode::Create, archive: Some("a.tar".into()), dir: Some("/etc".into()), names: vec!["hosts".into()],
+    }));
+    assert_eq!("tar -x".parse(), Ok(Command::Tar { mode: TarMode::Extract, archive: None, dir: None, names: vec![] }));
+    assert!("tar -c".parse::<Command>().is_err());
+    assert!("tar -ct a".parse::<Command>().is_err());
+    assert_eq!("frobnicate".parse::<Command>(), Err("Unknown command: frobnicate".to_owned()));
+  }
+}
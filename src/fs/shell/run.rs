@@ -0,0 +1,622 @@
+use super::parse::{help, Command, FindTest, Listing, Order, Pipeline, Redirect, TarMode, WriteInput};
+use super::text;
+use crate::filesystem::{DirEntry, FileType, Filesystem};
+use crate::path;
+use crate::tar::{self, Kind, TarReader, TarWriter};
+use crate::walk::Walk;
+use crate::transfer;
+
+use std::io::BufRead;
+use anyhow::{anyhow, Error, Result};
+use regex::bytes::RegexBuilder;
+
+/// Reads the content of a `write`: raw bytes of a known size, or lines up to the terminator.
+/// Heredoc lines always end with `\n` in the file, whatever line endings the client sends.
+pub fn read_input<R: BufRead>(reader: &mut R, input: &WriteInput) -> Result<Vec<u8>> {
+  let mut content = vec![];
+  match input {
+    WriteInput::Bytes(size) => { transfer::copy_chunked(reader, &mut content, *size, |_| ())?; },
+    WriteInput::Heredoc(terminator) => loop {
+      let mut line = vec![];
+      if reader.read_until(b'\n', &mut line)? == 0 {
+        return Err(anyhow!("Input ended before the terminator: {}", terminator))
+      }
+      if line.ends_with(b"\n") { line.pop(); }
+      if line.ends_with(b"\r") { line.pop(); }
+      if line == terminator.as_bytes() { break };
+      content.extend(line);
+      content.push(b'\n');
+    },
+  }
+  Ok(content)
+}
+
+// Runs shell commands against a file system. Every command reads its input and
+// produces its output as a byte stream, failures are collected separately.
+pub struct Shell<'a, F: Filesystem> {
+  fs: &'a mut F,
+  pub cwd: String,
+}
+
+impl<'a, F: Filesystem> Shell<'a, F> {
+  pub fn new(fs: &'a mut F) -> Self {
+    Shell { fs, cwd: "/".to_owned() }
+  }
+
+  pub fn fs(&mut self) -> &mut F { self.fs }
+
+  pub fn resolve(&self, name: &str) -> String {
+    path::resolve(&self.cwd, name)
+  }
+
+  /// Feeds `input` to the first command and the output of every command to the next one.
+  /// Returns the output of the last command, or nothing if it is redirected into a file.
+  pub fn run(&mut self, pipeline: &Pipeline, input: Vec<u8>, errors: &mut Vec<Error>) -> Vec<u8> {
+    let mut data = input;
+    for command in pipeline.stages.iter() {
+      let mut output = vec![];
+      self.run_command(command, &data, &mut output, errors);
+      data = output;
+    }
+    match &pipeline.redirect {
+      None => data,
+      Some(redirect) => {
+        if let Err(why) = self.redirect(redirect, &data) { errors.push(why) };
+        vec![]
+      }
+    }
+  }
+
+  fn redirect(&mut self, redirect: &Redirect, data: &[u8]) -> Result<()> {
+    let dest = self.resolve(&redirect.path);
+    match self.fs.stat(&dest) {
+      Ok(meta) if redirect.append => {
+        let handle = self.fs.open(&dest)?;
+        let written = self.fs.write_at(handle, meta.size, data);
+        self.fs.close(handle)?;
+        written
+      }
+      Ok(_) => self.fs.write(&dest, data),
+      Err(_) => self.fs.create(&dest, data),
+    }
+  }
+
+  fn run_command(&mut self, command: &Command, input: &[u8], output: &mut Vec<u8>, errors: &mut Vec<Error>) {
+    let mut report = |result: Result<()>| if let Err(why) = result { errors.push(why) };
+    match command {
+      Command::Empty | Command::Exit => {},
+      Command::Pwd => output.extend(format!("{}\n", self.cwd).as_bytes()),
+      Command::Help => output.extend(format!("{}\n", help()).as_bytes()),
+      Command::Echo { newline, words } => {
+        output.extend(words.join(" ").as_bytes());
+        if *newline { output.push(b'\n') };
+      }
+      Command::Ls(listing, names) => {
+        let names = if names.is_empty() { vec![".".to_owned()] } else { names.clone() };
+        for (i, name) in names.iter().enumerate() {
+          let dest = self.resolve(name);
+          report(self.fs.stat(&dest).and_then(|meta| match meta.file_type {
+            FileType::File | FileType::Symlink => { self.print_entry(&DirEntry::new(name, meta), &dest, listing.long, output); Ok(()) },
+            FileType::Directory => {
+              if names.len() > 1 || listing.recursive {
+                output.extend(format!("{}{}:\n", if i > 0 { "\n" } else { "" }, name).as_bytes())
+              };
+              self.list(name, &dest, listing, output)
+            }
+          }))
+        }
+      }
+      Command::Cd(dest) => {
+        let dest = dest.as_ref().map_or_else(|| "/".to_owned(), |dest| self.resolve(dest));
+        report(self.fs.stat(&dest).and_then(|meta| match meta.file_type {
+          FileType::Directory => { self.cwd = dest; Ok(()) },
+          _ => Err(anyhow!("Is not a directory: {}", dest)),
+        }))
+      }
+      Command::Touch(names) => for name in names.iter() {
+        report(self.fs.touch(&self.resolve(name)))
+      },
+      Command::Write(name, _) => {
+        let dest = self.resolve(name);
+        report(match self.fs.stat(&dest) {
+          Ok(_) => self.fs.write(&dest, input),
+          Err(_) => self.fs.create(&dest, input),
+        })
+      }
+      Command::Mkdir { parents, names } => for name in names.iter() {
+        report(self.make_dir(&self.resolve(name), *parents))
+      },
+      Command::Cat(names) if names.is_empty() => output.extend_from_slice(input),
+      Command::Cat(names) => for name in names.iter() {
+        report(self.fs.read(&self.resolve(name)).map(|content| output.extend(content)))
+      },
+      Command::Rm { recursive, force, names } => for name in names.iter() {
+        report(self.remove(&self.resolve(name), *recursive, *force))
+      },
+      Command::Mv(sources, dest) => for result in self.move_all(sources, dest) {
+        report(result)
+      },
+      Command::Head { count, names } | Command::Tail { count, names } => {
+        let sources = self.sources(names, input);
+        let many = sources.len() > 1;
+        for (i, (name, content)) in sources.into_iter().enumerate() {
+          report(content.map(|content| {
+            if many { output.extend(format!("{}==> {} <==\n", if i > 0 { "\n" } else { "" }, name).as_bytes()) };
+            let part = match command { Command::Head { .. } => text::head(&content, *count), _ => text::tail(&content, *count) };
+            output.extend(part);
+          }))
+        }
+      }
+      Command::Wc { lines, words, bytes, names } => {
+        let sources = self.sources(names, input);
+        let many = sources.len() > 1;
+        let mut total = text::Counts::default();
+        let mut print = |counts: text::Counts, name: &str| {
+          let mut columns = vec![];
+          if *lines { columns.push(format!("{:>7}", counts.lines)) };
+          if *words { columns.push(format!("{:>7}", counts.words)) };
+          if *bytes { columns.push(format!("{:>7}", counts.bytes)) };
+          if !names.is_empty() { columns.push(name.to_owned()) };
+          output.extend(format!("{}\n", columns.join(" ")).as_bytes());
+        };
+        for (name, content) in sources {
+          match content {
+            Ok(content) => { let counts = text::count(&content); total = total + counts; print(counts, &name) },
+            Err(why) => errors.push(why),
+          }
+        }
+        if many { print(total, "total") };
+      }
+      Command::Grep { pattern, recursive, ignore_case, invert, line_numbers, names } => {
+        let regex = match RegexBuilder::new(pattern).case_insensitive(*ignore_case).build() {
+          Ok(regex) => regex,
+          Err(why) => return report(Err(anyhow!("grep: {}", why))),
+        };
+        let sources = if *recursive { self.walk_files(names) } else { self.sources(names, input) };
+        let prefix = *recursive || sources.len() > 1;
+        for (name, content) in sources {
+          report(content.map(|content| for (i, line) in text::lines(&content).into_iter().enumerate() {
+            if regex.is_match(line.strip_suffix(b"\n").unwrap_or(line)) == *invert { continue };
+            if prefix { output.extend(format!("{}:", name).as_bytes()) };
+            if *line_numbers { output.extend(format!("{}:", i + 1).as_bytes()) };
+            output.extend(line);
+            if !line.ends_with(b"\n") { output.push(b'\n') };
+          }))
+        }
+      }
+      Command::Sort { reverse, numeric, unique, names } => {
+        let mut content = vec![];
+        for (_, part) in self.sources(names, input) {
+          match part {
+            Ok(part) => { content.extend(&part); if !part.is_empty() && !part.ends_with(b"\n") { content.push(b'\n') } },
+            Err(why) => errors.push(why),
+          }
+        }
+        output.extend(text::sort(&content, *reverse, *numeric, *unique));
+      }
+      Command::Diff(a, b) => report(self.fs.read(&self.resolve(a))
+        .and_then(|a| Ok(text::diff(&a, &self.fs.read(&self.resolve(b))?)))
+        .map(|difference| output.extend(difference))),
+      Command::Ln { target, name } => report(self.fs.symlink(target, &self.resolve(name))),
+      Command::Tree { depth, path } => {
+        let given = path.clone().unwrap_or_else(|| ".".to_owned());
+        let root = self.resolve(&given);
+        let walk = Walk::new(self.fs, &root);
+        let walk = match depth { Some(depth) => walk.max_depth(*depth), None => walk };
+        let mut entries = vec![];
+        for item in walk {
+          match item { Ok(entry) => entries.push(entry), Err(why) => report(Err(why)) }
+        }
+        if entries.is_empty() { return };
+        // An entry is the last one of its directory if no sibling follows before the walk leaves the directory
+        let mut last = vec![true; entries.len()];
+        let mut sibling_follows: Vec<bool> = vec![];
+        for (i, entry) in entries.iter().enumerate().rev() {
+          sibling_follows.resize(entry.depth + 1, false);
+          last[i] = !sibling_follows[entry.depth];
+          sibling_follows[entry.depth] = true;
+        }
+        output.extend(format!("{}\n", given).as_bytes());
+        let (mut dirs, mut files) = (0, 0);
+        let mut open: Vec<bool> = vec![];
+        for (entry, &is_last) in entries.iter().zip(last.iter()).skip(1) {
+          open.resize(entry.depth, false);
+          let indent: String = open[1..].iter().map(|&open| if open { "│   " } else { "    " }).collect();
+          let connector = if is_last { "└── " } else { "├── " };
+          let name = match entry.entry.file_type {
+            FileType::Symlink => format!("{} -> {}", entry.entry.name, self.fs.readlink(&entry.path).unwrap_or_default()),
+            _ => entry.entry.name.clone(),
+          };
+          output.extend(format!("{}{}{}\n", indent, connector, name).as_bytes());
+          open.push(!is_last);
+          if entry.entry.file_type == FileType::Directory { dirs += 1 } else { files += 1 };
+        }
+        output.extend(format!("\n{} directories, {} files\n", dirs, files).as_bytes());
+      }
+      Command::Du { human, summarize, apparent, names } => {
+        let names = if names.is_empty() { vec![".".to_owned()] } else { names.clone() };
+        let size = |bytes: usize| if *human { format_size(bytes) } else { bytes.to_string() };
+        let usage = |entry: &DirEntry| if *apparent { entry.size } else { entry.allocated };
+        for name in names.iter() {
+          let root = self.resolve(name);
+          // Directories above the current entry: displayed path, depth and size so far
+          let mut open: Vec<(String, usize, usize)> = vec![];
+          let close = |open: &mut Vec<(String, usize, usize)>, output: &mut Vec<u8>| {
+            let (shown, depth, total) = open.pop().unwrap();
+            if !summarize || depth == 0 { output.extend(format!("{}\t{}\n", size(total), shown).as_bytes()) };
+            if let Some(parent) = open.last_mut() { parent.2 += total };
+          };
+          for item in Walk::new(self.fs, &root) {
+            let entry = match item { Ok(entry) => entry, Err(why) => { report(Err(why)); continue } };
+            while open.last().is_some_and(|&(_, depth, _)| depth >= entry.depth) { close(&mut open, output) };
+            match (entry.entry.file_type, open.last_mut()) {
+              (FileType::Directory, _) => open.push((shown(name, &root, &entry.path), entry.depth, usage(&entry.entry))),
+              (_, Some(parent)) => parent.2 += usage(&entry.entry),
+              (_, None) => output.extend(format!("{}\t{}\n", size(usage(&entry.entry)), name).as_bytes()),
+            }
+          }
+          while !open.is_empty() { close(&mut open, output) };
+        }
+      }
+      Command::Df { human } => report(self.fs.statfs().map(|stats| {
+        let count = |blocks: usize| if *human { format_size(blocks * stats.block_size) } else { blocks.to_string() };
+        let used = |free: usize, total: usize| (total - free, ((total - free) * 100).checked_div(total).unwrap_or(0));
+        let (used_blocks, blocks_percent) = used(stats.free_blocks, stats.blocks);
+        let (used_inodes, inodes_percent) = used(stats.free_inodes, stats.inodes);
+        output.extend(format!("{:<8}{:>8}{:>8}{:>8}{:>5}\n", "", "Total", "Used", "Free", "Use%").as_bytes());
+        output.extend(format!("{:<8}{:>8}{:>8}{:>8}{:>4}%\n", "blocks",
+          count(stats.blocks), count(used_blocks), count(stats.free_blocks), blocks_percent).as_bytes());
+        output.extend(format!("{:<8}{:>8}{:>8}{:>8}{:>4}%\n", "inodes",
+          stats.inodes, used_inodes, stats.free_inodes, inodes_percent).as_bytes());
+      })),
+      Command::Find { paths, tests } => {
+        let paths = if paths.is_empty() { vec![".".to_owned()] } else { paths.clone() };
+        for name in paths.iter() {
+          let root = self.resolve(name);
+          for item in Walk::new(self.fs, &root) {
+            let entry = match item { Ok(entry) => entry, Err(why) => { report(Err(why)); continue } };
+            let passes = tests.iter().all(|test| match test {
+              FindTest::Name(pattern) => path::glob_match(pattern, &entry.entry.name),
+              FindTest::Type(file_type) => entry.entry.file_type == *file_type,
+              FindTest::Size { cmp, count, unit } => entry.entry.size.div_ceil(*unit).cmp(count) == *cmp,
+            });
+            if passes { output.extend(format!("{}\n", shown(name, &root, &entry.path)).as_bytes()) };
+          }
+        }
+      }
+      Command::Tar { mode, archive, dir, names } => {
+        let archived = match (mode, archive) {
+          (TarMode::Create, _) => vec![],
+          (_, Some(archive)) => match self.fs.read(&self.resolve(archive)) {
+            Ok(content) => content,
+            Err(why) => return errors.push(why),
+          },
+          (_, None) => input.to_vec(),
+        };
+        let dir = self.resolve(dir.as_deref().unwrap_or("."));
+        match mode {
+          TarMode::Create => if let Err(why) = self.create_archive(&dir, names, archive, output, errors) { errors.push(why) },
+          TarMode::Extract => match tar::import(self.fs, archived.as_slice(), &dir) {
+            Ok(copied) => errors.extend(copied.skipped.iter().map(|skipped| anyhow!("tar: skipped {}", skipped))),
+            Err(why) => errors.push(why),
+          },
+          TarMode::List => {
+            let mut reader = TarReader::new(archived.as_slice());
+            loop {
+              match reader.next_entry() {
+                Ok(Some(entry)) => {
+                  let slash = if entry.kind == Kind::Directory { "/" } else { "" };
+                  output.extend(format!("{}{}\n", entry.path, slash).as_bytes());
+                }
+                Ok(None) => break,
+                Err(why) => return errors.push(why),
+              }
+            }
+          }
+        }
+      }
+      Command::Download(_) | Command::Entries(_) | Command::Mkdirp(_) =>
+        report(Err(anyhow!("Transfer requests cannot be combined with other commands"))),
+    }
+  }
+
+  // Archives the paths, relative to `dir`, into the file `archive` or the output
+  fn create_archive(&mut self, dir: &str, names: &[String], archive: &Option<String>, output: &mut Vec<u8>,
+                    errors: &mut Vec<Error>) -> Result<()> {
+    let mut tar = TarWriter::new(vec![]);
+    for name in names.iter() {
+      let mut copied = Default::default();
+      if let Err(why) = tar.append_tree(&*self.fs, &path::resolve(dir, name), &mut copied) { errors.push(why) };
+    }
+    let content = tar.finish()?;
+    match archive {
+      None => { output.extend(content); Ok(()) },
+      Some(archive) => {
+        let archive = self.resolve(archive);
+        if self.fs.stat(&archive).is_ok() { self.fs.write(&archive, &content) } else { self.fs.create(&archive, &content) }
+      }
+    }
+  }
+
+  // Contents of the named files, or the input if there are none
+  fn sources(&self, names: &[String], input: &[u8]) -> Vec<(String, Result<Vec<u8>>)> {
+    if names.is_empty() { return vec![("-".to_owned(), Ok(input.to_vec()))] };
+    names.iter().map(|name| (name.clone(), self.fs.read(&self.resolve(name)))).collect()
+  }
+
+  // Contents of the named files and of every file below the named directories, current one by default
+  fn walk_files(&self, names: &[String]) -> Vec<(String, Result<Vec<u8>>)> {
+    let names = if names.is_empty() { vec![".".to_owned()] } else { names.to_vec() };
+    let mut files = vec![];
+    for name in names.iter() {
+      let root = self.resolve(name);
+      for item in Walk::new(self.fs, &root) {
+        match item {
+          Ok(entry) if entry.entry.file_type == FileType::File => {
+            let shown = shown(name, &root, &entry.path);
+            let shown = shown.strip_prefix("./").map_or(shown.clone(), str::to_owned);
+            files.push((shown, self.fs.read(&entry.path)))
+          }
+          Ok(_) => {},
+          Err(why) => files.push((name.clone(), Err(why))),
+        }
+      }
+    }
+    files
+  }
+
+  // Prints the entries of `dir`, shown to the user as `shown`, and of its subdirectories if recursive
+  fn list(&self, shown: &str, dir: &str, listing: &Listing, output: &mut Vec<u8>) -> Result<()> {
+    let mut entries = self.fs.readdir(dir)?;
+    match listing.order {
+      Order::Directory => {},
+      Order::Size => entries.sort_by_key(|entry| std::cmp::Reverse(entry.size)),
+      Order::Time => entries.sort_by_key(|entry| std::cmp::Reverse(entry.mtime)),
+    }
+    let dots = if listing.all {
+      vec![DirEntry::new(".", self.fs.stat(dir)?), DirEntry::new("..", self.fs.stat(&path::resolve(dir, ".."))?)]
+    } else {
+      vec![]
+    };
+    for entry in dots.iter().chain(entries.iter()) {
+      self.print_entry(entry, &format!("{}/{}", dir.trim_end_matches('/'), entry.name), listing.long, output);
+    }
+    if !listing.recursive { return Ok(()) };
+    for entry in entries.iter().filter(|entry| entry.file_type == FileType::Directory) {
+      let shown = format!("{}/{}", shown.trim_end_matches('/'), entry.name);
+      output.extend(format!("\n{}:\n", shown).as_bytes());
+      self.list(&shown, &format!("{}/{}", dir.trim_end_matches('/'), entry.name), listing, output)?;
+    }
+    Ok(())
+  }
+
+  fn remove(&mut self, path: &str, recursive: bool, force: bool) -> Result<()> {
+    match self.fs.lstat(path) {
+      Err(_) if force => Ok(()),
+      Err(why) => Err(why),
+      Ok(meta) if meta.file_type == FileType::Directory && !recursive =>
+        Err(anyhow!("Cannot remove {}: Is a directory", path)),
+      Ok(_) => self.fs.unlink(path),
+    }
+  }
+
+  fn make_dir(&mut self, path: &str, parents: bool) -> Result<()> {
+    if !parents { return self.fs.mkdir(path) };
+    let mut prefix = String::new();
+    for name in path::components(path) {
+      prefix = format!("{}/{}", prefix, name);
+      match self.fs.stat(&prefix) {
+        Ok(meta) if meta.file_type == FileType::Directory => {},
+        Ok(_) => return Err(anyhow!("Is not a directory: {}", prefix)),
+        Err(_) => self.fs.mkdir(&prefix)?,
+      }
+    }
+    Ok(())
+  }
+
+  // `name` alone, with `/` after directories and `@` after links, or `drwxr-xr-x inode size mtime name` in the long format
+  fn print_entry(&self, entry: &DirEntry, path: &str, long: bool, output: &mut Vec<u8>) {
+    let line = match (long, entry.file_type) {
+      (false, FileType::Directory) => format!("{}/", entry.name),
+      (false, FileType::Symlink) => format!("{}@", entry.name),
+      (false, FileType::File) => entry.name.clone(),
+      (true, file_type) => {
+        let target = match file_type {
+          FileType::Symlink => format!(" -> {}", self.fs.readlink(path).unwrap_or_default()),
+          _ => String::new(),
+        };
+        format!("{}{} {:>5} {:>8} {} {}{}",
+          match file_type { FileType::Directory => 'd', FileType::Symlink => 'l', FileType::File => '-' },
+          entry.mode.map_or_else(|| "?????????".to_owned(), permissions),
+          entry.inode, entry.size, format_time(entry.mtime), entry.name, target)
+      }
+    };
+    output.extend(format!("{}\n", line).as_bytes());
+  }
+
+  // Moves every source into `dest` if it is a directory, or renames the only source to `dest`
+  fn move_all(&mut self, sources: &[String], dest: &str) -> Vec<Result<()>> {
+    let dest = self.resolve(dest);
+    let into_dir = self.fs.stat(&dest).map(|meta| meta.file_type == FileType::Directory).unwrap_or(false);
+    if sources.len() > 1 && !into_dir { return vec![Err(anyhow!("Target is not a directory: {}", dest))] };
+    sources.iter().map(|source| {
+      let source = self.resolve(source);
+      let target = if into_dir { format!("{}/{}", dest, path::split_parent(&source)?.1) } else { dest.clone() };
+      self.fs.rename(&source, &target)
+    }).collect()
+  }
+}
+
+// `path` found while walking from `root` as the user would see it, given they named the root `given`
+fn shown(given: &str, root: &str, path: &str) -> String {
+  match path[root.len()..].trim_start_matches('/') {
+    "" => given.to_owned(),
+    rest => format!("{}/{}", given.trim_end_matches('/'), rest),
+  }
+}
+
+// Size in bytes with a binary unit, like `1.5K` or `12M`, rounded up
+fn format_size(bytes: usize) -> String {
+  if bytes < 1024 { return bytes.to_string() };
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < 3 { value /= 1024.0; unit += 1 };
+  let suffix = ["", "K", "M", "G"][unit];
+  if value < 10.0 { format!("{:.1}{}", (value * 10.0).ceil() / 10.0, suffix) } else { format!("{}{}", value.ceil(), suffix) }
+}
+
+// `rwxr-x---` for the permission bits 0o750
+fn permissions(mode: u16) -> String {
+  (0..9).map(|bit| if mode & (0o400 >> bit) != 0 { b"rwx"[bit % 3] as char } else { '-' }).collect()
+}
+
+// `YYYY-MM-DD HH:MM` in UTC, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_time(secs: u64) -> String {
+  let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let day_of_era = z - era * 146097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rest / 3600, rest % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::temp_fs;
+
+  fn run<F: Filesystem>(shell: &mut Shell<F>, line: &str) -> String {
+    let mut errors = vec![];
+    let output = shell.run(&line.parse().unwrap(), vec![], &mut errors);
+    assert!(errors.is_empty(), "{}: {:?}", line, errors);
+    String::from_utf8(output).unwrap()
+  }
+
+  // Reads the input of the `write` on `line` from `sent`, returning it with whatever is left unread
+  fn input(line: &str, sent: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut reader = sent;
+    match line.parse() {
+      Ok(Command::Write(_, input)) => read_input(&mut reader, &input).map(|content| (content, reader.to_vec())),
+      other => panic!("{}: {:?}", line, other),
+    }
+  }
+
+  #[test]
+  fn write_input() {
+    assert_eq!(input("write f <<END", b"one\r\ntwo\r\nEND\r\nls\n").unwrap(), (b"one\ntwo\n".to_vec(), b"ls\n".to_vec()));
+    assert_eq!(input("write f << END", b"one\n END \nEND \nEND\n").unwrap(), (b"one\n END \nEND \n".to_vec(), vec![]));
+    assert_eq!(input("write f <<END", b"\nEND").unwrap().0, b"\n");
+    assert!(input("write f <<END", b"one\ntwo\n").is_err());
+    assert!(input("write f << END", b"one\nEN").is_err());
+    assert_eq!(input("write f 5", b"12\r\n45678").unwrap(), (b"12\r\n4".to_vec(), b"5678".to_vec()));
+    assert!(input("write f 5", b"123").is_err());
+  }
+
+  #[test]
+  fn redirections() {
+    let (mut fs, _image) = temp_fs("shell-redirect", 0);
+    let mut shell = Shell::new(&mut fs);
+    assert_eq!(run(&mut shell, "echo first line > log"), "");
+    run(&mut shell, "echo second | cat >> log");
+    run(&mut shell, "cat log >> copy");
+    assert_eq!(run(&mut shell, "cat copy"), "first line\nsecond\n");
+    assert_eq!(run(&mut shell, "ls | cat | cat"), "log\ncopy\n");
+  }
+
+  #[test]
+  fn errors_do_not_stop_the_pipeline() {
+    let (mut fs, _image) = temp_fs("shell-errors", 0);
+    let mut shell = Shell::new(&mut fs);
+    run(&mut shell, "echo a > a");
+    let mut errors = vec![];
+    let output = shell.run(&"cat a missing a".parse().unwrap(), vec![], &mut errors);
+    assert_eq!(output, b"a\na\n");
+    assert_eq!(errors.len(), 1);
+    run(&mut shell, "mkdir -p x/y");
+    run(&mut shell, "cd x/y");
+    assert_eq!(run(&mut shell, "pwd"), "/x/y\n");
+  }
+
+  #[test]
+  fn listings() {
+    let (mut fs, _image) = temp_fs("shell-ls", 0);
+    let mut shell = Shell::new(&mut fs);
+    run(&mut shell, "mkdir -p a/b");
+    run(&mut shell, "echo small > a/small");
+    run(&mut shell, "echo a bigger one > a/big");
+    assert_eq!(run(&mut shell, "ls -S a"), "b/\nbig\nsmall\n");
+    assert_eq!(run(&mut shell, "ls -a a/b"), "./\n../\n");
+    assert_eq!(run(&mut shell, "ls -R"), ".:\na/\n\n./a:\nb/\nsmall\nbig\n\n./a/b:\n");
+    let long = run(&mut shell, "ls -l a/small");
+    let inode = shell.fs().stat("/a/small").unwrap().inode;
+    assert!(long.starts_with(&format!("-rw-r--r-- {:>5}        6 20", inode)), "{}", long);
+    assert!(long.ends_with(" a/small\n"), "{}", long);
+  }
+
+  #[test]
+  fn formatting() {
+    assert_eq!(permissions(0o750), "rwxr-x---");
+    assert_eq!(format_time(0), "1970-01-01 00:00");
+    assert_eq!(format_time(951782400 + 3660), "2000-02-29 01:01");
+    assert_eq!(format_size(1000), "1000");
+    assert_eq!(format_size(1536), "1.5K");
+    assert_eq!(format_size(20 * 1024 * 1024 + 1), "21M");
+  }
+
+  #[test]
+  fn tree_walks() {
+    let (mut fs, _image) = temp_fs("shell-tree", 0);
+    let mut shell = Shell::new(&mut fs);
+    run(&mut shell, "mkdir -p a/b c");
+    run(&mut shell, "echo 0123456789 > a/b/ten");
+    run(&mut shell, "echo hi > c/hi");
+    run(&mut shell, "ln -s ../a/b/ten c/link");
+    assert_eq!(run(&mut shell, "tree"), ".\n├── a\n│   └── b\n│       └── ten\n└── c\n    ├── hi\n    └── link -> ../a/b/ten\n\n3 directories, 3 files\n");
+    assert_eq!(run(&mut shell, "find . -name '*i*'"), "./c/hi\n./c/link\n");
+    assert_eq!(run(&mut shell, "find a c -type f -size +2c"), "a/b/ten\nc/hi\n");
+    assert_eq!(run(&mut shell, "find / -type l"), "/c/link\n");
+    assert_eq!(run(&mut shell, "cat c/link"), "0123456789\n");
+    let (a, b) = (shell.fs().stat("/a").unwrap().size, shell.fs().stat("/a/b").unwrap().size);
+    assert_eq!(run(&mut shell, "du -b a"), format!("{}\ta/b\n{}\ta\n", b + 11, a + b + 11));
+    assert_eq!(run(&mut shell, "du -sb a/b/ten"), "11\ta/b/ten\n");
+    assert_eq!(run(&mut shell, "du -s a"), "3072\ta\n");
+    assert!(run(&mut shell, "df").contains("inodes      1024       8    1016   0%"));
+  }
+
+  #[test]
+  fn text_builtins() {
+    let (mut fs, _image) = temp_fs("shell-text", 0);
+    let mut shell = Shell::new(&mut fs);
+    run(&mut shell, "mkdir -p src/sub");
+    run(&mut shell, "echo 'fn main() {}' > src/main.rs");
+    run(&mut shell, "echo '// TODO: main' > src/sub/lib.rs");
+    assert_eq!(run(&mut shell, "grep -r main src"), "src/sub/lib.rs:// TODO: main\nsrc/main.rs:fn main() {}\n");
+    assert_eq!(run(&mut shell, "cat src/main.rs src/sub/lib.rs | grep -in todo"), "2:// TODO: main\n");
+    assert_eq!(run(&mut shell, "echo -n c | wc -c"), "      1\n");
+    run(&mut shell, "echo 10 > n");
+    run(&mut shell, "echo 3 >> n");
+    assert_eq!(run(&mut shell, "sort -n n | head -n 1"), "3\n");
+    assert_eq!(run(&mut shell, "diff src/main.rs src/sub/lib.rs"), "1c1\n< fn main() {}\n---\n> // TODO: main\n");
+  }
+
+  #[test]
+  fn archives() {
+    let (mut fs, _image) = temp_fs("shell-tar", 0);
+    let mut shell = Shell::new(&mut fs);
+    run(&mut shell, "mkdir -p src/sub dest");
+    run(&mut shell, "echo hi > src/sub/hi");
+    run(&mut shell, "ln -s sub/hi src/link");
+    assert_eq!(run(&mut shell, "tar -c src | tar -t"), "src/\nsrc/sub/\nsrc/sub/hi\nsrc/link\n");
+    run(&mut shell, "tar -cf a.tar -C src sub");
+    assert_eq!(run(&mut shell, "tar -tf a.tar"), "sub/\nsub/hi\n");
+    run(&mut shell, "tar -c src | tar -x -C dest");
+    assert_eq!(run(&mut shell, "cat dest/src/link"), "hi\n");
+    run(&mut shell, "tar -xf a.tar");
+    assert_eq!(run(&mut shell, "cat sub/hi"), "hi\n");
+  }
+}
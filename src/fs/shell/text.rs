@@ -0,0 +1,207 @@
+// Line-oriented text processing behind the head, tail, wc, sort and diff builtins.
+// A line is everything up to and including `\n`; the last line may lack it.
+
+/// Splits `data` into lines, each keeping its trailing newline
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+  data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+fn strip_newline(line: &[u8]) -> &[u8] {
+  line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+pub fn head(data: &[u8], count: usize) -> Vec<u8> {
+  lines(data).into_iter().take(count).flatten().copied().collect()
+}
+
+pub fn tail(data: &[u8], count: usize) -> Vec<u8> {
+  let lines = lines(data);
+  lines[lines.len().saturating_sub(count)..].concat()
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Counts {
+  pub lines: usize,
+  pub words: usize,
+  pub bytes: usize,
+}
+
+impl std::ops::Add for Counts {
+  type Output = Counts;
+
+  fn add(self, other: Counts) -> Counts {
+    Counts { lines: self.lines + other.lines, words: self.words + other.words, bytes: self.bytes + other.bytes }
+  }
+}
+
+pub fn count(data: &[u8]) -> Counts {
+  Counts {
+    lines: data.iter().filter(|&&b| b == b'\n').count(),
+    words: data.split(|b| b.is_ascii_whitespace()).filter(|word| !word.is_empty()).count(),
+    bytes: data.len(),
+  }
+}
+
+// Leading number of a line for `sort -n`; lines without one sort first, like in coreutils
+fn numeric_key(line: &[u8]) -> f64 {
+  let text = String::from_utf8_lossy(line);
+  let text = text.trim_start();
+  let end = text.char_indices()
+    .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && c == '-')))
+    .map_or(text.len(), |(i, _)| i);
+  text[..end].parse().unwrap_or(0.0)
+}
+
+pub fn sort(data: &[u8], reverse: bool, numeric: bool, unique: bool) -> Vec<u8> {
+  let mut lines: Vec<&[u8]> = lines(data).into_iter().map(strip_newline).collect();
+  if numeric {
+    lines.sort_by(|a, b| numeric_key(a).partial_cmp(&numeric_key(b)).unwrap_or(std::cmp::Ordering::Equal).then(a.cmp(b)));
+  } else {
+    lines.sort();
+  }
+  if unique { lines.dedup() };
+  if reverse { lines.reverse() };
+  lines.into_iter().flat_map(|line| line.iter().chain(b"\n")).copied().collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Edit {
+  Keep,
+  Delete,
+  Insert,
+}
+
+// Shortest edit script turning `a` into `b`, found through their longest common subsequence.
+// Lines shared by both ends are kept right away, the rest is split in halves Hirschberg-style,
+// which takes space linear in the number of lines.
+fn edits(a: &[&[u8]], b: &[&[u8]]) -> Vec<Edit> {
+  let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+  let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+  let mut script: Vec<Edit> = (0..prefix).map(|_| Edit::Keep).collect();
+  split_edits(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix], &mut script);
+  script.extend((0..suffix).map(|_| Edit::Keep));
+  script
+}
+
+fn split_edits(a: &[&[u8]], b: &[&[u8]], script: &mut Vec<Edit>) {
+  match a {
+    [] => return script.extend(b.iter().map(|_| Edit::Insert)),
+    _ if b.is_empty() => return script.extend(a.iter().map(|_| Edit::Delete)),
+    [line] => {
+      match b.iter().position(|other| other == line) {
+        Some(j) => {
+          script.extend((0..j).map(|_| Edit::Insert));
+          script.push(Edit::Keep);
+          script.extend((j + 1..b.len()).map(|_| Edit::Insert));
+        }
+        None => {
+          script.push(Edit::Delete);
+          script.extend(b.iter().map(|_| Edit::Insert));
+        }
+      }
+      return;
+    }
+    _ => {}
+  }
+  // The first half of `a` goes with the part of `b` that leaves the longest subsequence in total
+  let middle = a.len() / 2;
+  let forward = common_lengths(a[..middle].iter(), b.iter(), b.len());
+  let backward = common_lengths(a[middle..].iter().rev(), b.iter().rev(), b.len());
+  let cut = (0..=b.len()).max_by_key(|&j| (forward[j] + backward[b.len() - j], std::cmp::Reverse(j))).unwrap_or(0);
+  split_edits(&a[..middle], &b[..cut], script);
+  split_edits(&a[middle..], &b[cut..], script);
+}
+
+// Lengths of the longest common subsequences of `a` and every prefix of `b`, one row at a time
+fn common_lengths<'a, A, B>(a: A, b: B, len: usize) -> Vec<usize>
+  where A: Iterator<Item = &'a &'a [u8]>, B: Iterator<Item = &'a &'a [u8]> + Clone {
+  let mut row = vec![0; len + 1];
+  for x in a {
+    let mut diagonal = 0;
+    for (j, y) in b.clone().enumerate() {
+      let above = row[j + 1];
+      row[j + 1] = if x == y { diagonal + 1 } else { std::cmp::max(row[j], above) };
+      diagonal = above;
+    }
+  }
+  row
+}
+
+// 1-based line range of the 0-based, half-open `from..to`
+fn range(from: usize, to: usize) -> String {
+  if to - from == 1 { format!("{}", to) } else { format!("{},{}", from + 1, to) }
+}
+
+/// Line-based difference between `a` and `b` in the normal format of diff(1)
+pub fn diff(a: &[u8], b: &[u8]) -> Vec<u8> {
+  let a: Vec<&[u8]> = lines(a).into_iter().map(strip_newline).collect();
+  let b: Vec<&[u8]> = lines(b).into_iter().map(strip_newline).collect();
+  let script = edits(&a, &b);
+  let mut output = vec![];
+  let (mut i, mut j, mut k) = (0, 0, 0);
+  while k < script.len() {
+    if script[k] == Edit::Keep { i += 1; j += 1; k += 1; continue };
+    let (from_a, from_b) = (i, j);
+    while k < script.len() && script[k] != Edit::Keep {
+      if script[k] == Edit::Delete { i += 1 } else { j += 1 };
+      k += 1;
+    }
+    let header = match (i > from_a, j > from_b) {
+      (true, true) => format!("{}c{}\n", range(from_a, i), range(from_b, j)),
+      (true, false) => format!("{}d{}\n", range(from_a, i), from_b),
+      _ => format!("{}a{}\n", from_a, range(from_b, j)),
+    };
+    output.extend(header.as_bytes());
+    for line in &a[from_a..i] { output.extend(b"< "); output.extend(*line); output.push(b'\n') }
+    if i > from_a && j > from_b { output.extend(b"---\n") };
+    for line in &b[from_b..j] { output.extend(b"> "); output.extend(*line); output.push(b'\n') }
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn head_and_tail() {
+    assert_eq!(head(b"1\n2\n3", 2), b"1\n2\n");
+    assert_eq!(head(b"1\n2\n3", 5), b"1\n2\n3");
+    assert_eq!(tail(b"1\n2\n3", 2), b"2\n3");
+    assert_eq!(tail(b"1\n2\n3\n", 0), b"");
+  }
+
+  #[test]
+  fn counts() {
+    assert_eq!(count(b"one two\n three\n"), Counts { lines: 2, words: 3, bytes: 15 });
+    assert_eq!(count(b""), Counts::default());
+  }
+
+  #[test]
+  fn sorting() {
+    assert_eq!(sort(b"b\na\nc\na", false, false, false), b"a\na\nb\nc\n");
+    assert_eq!(sort(b"10\n9\n-1\nx\n", false, true, false), b"-1\nx\n9\n10\n");
+    assert_eq!(sort(b"b\na\nb\n", true, false, true), b"b\na\n");
+  }
+
+  #[test]
+  fn differences() {
+    assert_eq!(diff(b"a\nb\nc\n", b"a\nb\nc\n"), b"");
+    assert_eq!(String::from_utf8(diff(b"a\nb\nc\nd\n", b"a\nx\nc\nd\ne\n")).unwrap(),
+               "2c2\n< b\n---\n> x\n4a5\n> e\n");
+    assert_eq!(String::from_utf8(diff(b"a\nb\nc\n", b"c\n")).unwrap(), "1,2d0\n< a\n< b\n");
+  }
+
+  #[test]
+  fn long_differences() {
+    let a: Vec<String> = (0..3000).map(|i| i.to_string()).collect();
+    let b: Vec<String> = (0..3000).map(|i| if i % 3 == 0 { format!("x{}", i) } else { i.to_string() }).collect();
+    let a: Vec<&[u8]> = a.iter().map(|line| line.as_bytes()).collect();
+    let b: Vec<&[u8]> = b.iter().map(|line| line.as_bytes()).collect();
+    let script = edits(&a, &b);
+    let count = |edit: Edit| script.iter().filter(|e| **e == edit).count();
+    assert_eq!((count(Edit::Keep), count(Edit::Delete), count(Edit::Insert)), (2000, 1000, 1000));
+    assert_eq!(String::from_utf8(diff(b"a\nb\nc\nd\ne\n", b"b\nc\ne\nf\n")).unwrap(),
+               "1d0\n< a\n4d2\n< d\n5a4\n> f\n");
+  }
+}
@@ -10,7 +10,7 @@ pub struct Storage {
 
 impl Storage {
   pub fn new(filename: &str) -> io::Result<Self> {
-    let file = OpenOptions::new().read(true).write(true).create(true).open(filename)?;
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(filename)?;
     Ok(Storage {
       file: RefCell::new(file),
     })
@@ -23,10 +23,24 @@ impl Storage {
 
   pub fn read(&self, offset: usize, size: usize) -> io::Result<Vec<u8>> {
     self.file.borrow_mut().seek(SeekFrom::Start(offset as u64))?;
-    let mut buffer = vec![0u8; size as usize];
+    let mut buffer = vec![0u8; size];
     self.file.borrow_mut().read(buffer.as_mut_slice()).and_then(|total| {
       if total == size { Ok(buffer)}
       else { Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of file")) }
     })
   }
+
+  /// Bytes in the underlying file
+  pub fn file_len(&self) -> io::Result<usize> {
+    Ok(self.file.borrow().metadata()?.len() as usize)
+  }
+
+  /// Grows or shrinks the underlying file to `size` bytes
+  pub fn set_len(&mut self, size: usize) -> io::Result<()> {
+    self.file.borrow_mut().set_len(size as u64)
+  }
+
+  pub fn sync(&mut self) -> io::Result<()> {
+    self.file.borrow_mut().sync_all()
+  }
 }
\ No newline at end of file
@@ -1,71 +1,237 @@
 use serde::{Deserialize, Serialize};
-use serde_big_array::big_array;
 use std::fmt::Debug;
 use std::mem::size_of;
+use std::convert::TryInto;
 use anyhow::{anyhow, Result};
+use crate::filesystem::FileType;
 
 pub const INODES_COUNT: usize = 1024;
-pub const INODE_SIZE: usize = size_of::<Inode>();
-pub const INODES_BITMAP_SIZE: usize = INODES_COUNT / 8;
+/// Bytes of every record in the inode table. Fixed rather than derived from `Inode`, so that new
+/// fields go at the end of the record and images keep their layout; those of another size are refused.
+pub const INODE_SIZE: usize = 128;
 pub const INODE_LINKS: usize = 12;
+/// Inode of unused directory records, taken when an image is created and never handed out
+pub const UNUSED_INODE: usize = 0;
+pub const ROOT_INODE: usize = 1;
+/// Longest file name in bytes
+pub const NAME_MAX: usize = 255;
 
 pub const BLOCKS_COUNT: usize = 1024;
 pub const BLOCK_SIZE: usize = 1024;
-pub const BLOCKS_BITMAP_SIZE: usize = BLOCKS_COUNT / 8;
+/// Block sizes an image can be formatted with. Nodes of extent trees are laid out for the smallest.
+pub const BLOCK_SIZES: [usize; 3] = [1024, 2048, 4096];
+/// Block pointer of a part of a file without a block of its own, which reads as zeros.
+/// Data block 0 is taken when an image is created and never handed out.
+pub const HOLE: usize = 0;
 
 pub const SUPERBLOCK_SIZE: usize = size_of::<Superblock>();
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Files map their blocks through extent trees instead of direct pointers
+pub const INCOMPAT_EXTENTS: u32 = 0x40;
+/// Small files and directories keep their content in the inode itself
+pub const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+/// Incompatible features this implementation understands; images with others are refused
+pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_EXTENTS | INCOMPAT_INLINE_DATA;
+
+/// Names of the incompatible features, as `ext2mkfs -O` takes them
+pub const INCOMPAT_NAMES: [(&str, u32); 2] = [("extents", INCOMPAT_EXTENTS), ("inline_data", INCOMPAT_INLINE_DATA)];
+
+/// Bytes of image per inode when the number of inodes is not given
+pub const BYTES_PER_INODE: usize = 8192;
+
+/// Most bytes of content kept in the block slots of an inode
+pub const INLINE_DATA_MAX: usize = INODE_LINKS * size_of::<usize>();
+
+/// Extents in the root node kept in the block slots of an inode, after the depth and the count
+pub const INLINE_EXTENTS: usize = (INODE_LINKS - 2) / 3;
+/// Extents in a node taking a whole block: a depth and a count, then three words per extent
+pub fn extents_per_block(block_size: usize) -> usize {
+  (block_size - 16) / 24
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Superblock {
   pub block_size: usize,
   pub inode_size: usize,
   pub blocks_count: usize,
   pub inodes_count: usize,
+  /// Kept equal to the number of clear bits in the data bitmap
+  pub free_blocks_count: usize,
+  /// Kept equal to the number of clear bits in the inode bitmap
+  pub free_inodes_count: usize,
   pub data_bitmap: usize,
   pub inode_bitmap: usize,
   pub inode_table: usize,
   pub data_blocks: usize,
+  /// Features an implementation must understand to use the image at all
+  pub feature_incompat: u32,
 }
 
 impl Default for Superblock {
   fn default() -> Self {
-    Superblock {
-      block_size: BLOCK_SIZE,
+    Superblock::new(BLOCK_SIZE, BLOCKS_COUNT, INODES_COUNT, 0).unwrap()
+  }
+}
+
+impl Superblock {
+  /// Layout of an image with `blocks_count` data blocks of `block_size` bytes and `inodes_count`
+  /// inodes, both counts being multiples of 8 for the bitmaps to cover them exactly
+  pub fn new(block_size: usize, blocks_count: usize, inodes_count: usize, feature_incompat: u32) -> Result<Self> {
+    if !BLOCK_SIZES.contains(&block_size) {
+      return Err(anyhow!("Unsupported block size {}, expected one of {:?}", block_size, BLOCK_SIZES))
+    }
+    if blocks_count < 8 || !blocks_count.is_multiple_of(8) || blocks_count > u32::MAX as usize {
+      return Err(anyhow!("Invalid number of blocks {}, expected a multiple of 8", blocks_count))
+    }
+    if inodes_count < 8 || !inodes_count.is_multiple_of(8) || inodes_count > u32::MAX as usize {
+      return Err(anyhow!("Invalid number of inodes {}, expected a multiple of 8", inodes_count))
+    }
+    let data_bitmap = SUPERBLOCK_SIZE;
+    let inode_bitmap = data_bitmap + blocks_count / 8;
+    let inode_table = inode_bitmap + inodes_count / 8;
+    Ok(Superblock {
+      block_size,
       inode_size: INODE_SIZE,
-      blocks_count: BLOCKS_COUNT,
-      inodes_count: INODES_COUNT,
-      data_bitmap: SUPERBLOCK_SIZE,
-      inode_bitmap: SUPERBLOCK_SIZE + BLOCKS_BITMAP_SIZE,
-      inode_table: SUPERBLOCK_SIZE + BLOCKS_BITMAP_SIZE + INODES_BITMAP_SIZE,
-      data_blocks: SUPERBLOCK_SIZE
-        + BLOCKS_BITMAP_SIZE
-        + INODES_BITMAP_SIZE
-        + INODES_COUNT * INODE_SIZE,
+      blocks_count,
+      inodes_count,
+      free_blocks_count: blocks_count - 1,
+      free_inodes_count: inodes_count - 1,
+      data_bitmap,
+      inode_bitmap,
+      inode_table,
+      data_blocks: inode_table + inodes_count * INODE_SIZE,
+      feature_incompat,
+    })
+  }
+
+  /// The layout with the most data blocks an image of `size` bytes has room for, with
+  /// `inodes_count` inodes, or one per `BYTES_PER_INODE` bytes if not given
+  pub fn fitting(size: usize, block_size: usize, inodes_count: Option<usize>, feature_incompat: u32) -> Result<Self> {
+    let inodes_count = inodes_count.unwrap_or_else(|| std::cmp::max(size / BYTES_PER_INODE, 8).div_ceil(8) * 8);
+    let metadata = SUPERBLOCK_SIZE + inodes_count / 8 + inodes_count * INODE_SIZE;
+    // Every data block takes a bit of the data bitmap besides itself
+    let blocks_count = size.saturating_sub(metadata) * 8 / (8 * block_size + 1) / 8 * 8;
+    if blocks_count < 8 { return Err(anyhow!("An image of {} bytes is too small", size)) };
+    Superblock::new(block_size, blocks_count, inodes_count, feature_incompat)
+  }
+
+  /// Bytes the whole image takes, up to the end of the last data block
+  pub fn image_size(&self) -> usize {
+    self.data_blocks + self.blocks_count * self.block_size
+  }
+
+  /// Checks that the superblock of an existing image lays it out the way `new` does, with
+  /// features this implementation understands. The free counters are left to the bitmaps.
+  pub fn check_layout(&self) -> Result<()> {
+    if self.inode_size != INODE_SIZE {
+      return Err(anyhow!("Unsupported inode size {}, expected {}", self.inode_size, INODE_SIZE))
+    }
+    let expected = Superblock::new(self.block_size, self.blocks_count, self.inodes_count, self.feature_incompat)?;
+    let unsupported = self.feature_incompat & !INCOMPAT_SUPPORTED;
+    if unsupported != 0 { return Err(anyhow!("Unsupported incompatible features: {:#x}", unsupported)) };
+    let offsets = (self.data_bitmap, self.inode_bitmap, self.inode_table, self.data_blocks);
+    if offsets != (expected.data_bitmap, expected.inode_bitmap, expected.inode_table, expected.data_blocks) {
+      return Err(anyhow!("The offsets of the superblock do not match its counts"))
     }
+    Ok(())
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// `size` in bytes, `K`, `M` and `G` standing for powers of 1024
+pub fn parse_size(size: &str) -> Result<usize> {
+  let (digits, unit) = match size.char_indices().last() {
+    Some((at, 'K')) | Some((at, 'k')) => (&size[..at], 1 << 10),
+    Some((at, 'M')) | Some((at, 'm')) => (&size[..at], 1 << 20),
+    Some((at, 'G')) | Some((at, 'g')) => (&size[..at], 1 << 30),
+    _ => (size, 1),
+  };
+  digits.parse::<usize>().ok().and_then(|count| count.checked_mul(unit)).ok_or(anyhow!("Invalid size: {}", size))
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Inode {
   pub size: usize,
   pub is_directory: bool, 
+  /// The content is the path the link points to
+  pub is_symlink: bool,
   pub direct: [usize; INODE_LINKS],
+  pub mtime: u64,
+  /// The block slots hold the root node of an extent tree rather than direct pointers
+  pub extents: bool,
+  /// The block slots hold the content itself, until it outgrows them
+  pub inline_data: bool,
+  /// The first block of the directory holds a hash index of the others
+  pub indexed: bool,
+  /// Permission bits
+  pub mode: u16,
+  pub uid: u32,
+  pub gid: u32,
 }
 
-impl Default for Inode {
-  fn default() -> Self {
-    Inode {
-      size: 0,
-      is_directory: false,
-      direct: [0; 12],
+impl Inode {
+  pub fn file_type(&self) -> FileType {
+    if self.is_directory { FileType::Directory }
+    else if self.is_symlink { FileType::Symlink }
+    else { FileType::File }
+  }
+
+  pub fn inline_bytes(&self) -> Vec<u8> {
+    self.direct.iter().flat_map(|slot| slot.to_le_bytes()).take(self.size).collect()
+  }
+
+  /// Keeps `bytes`, at most `INLINE_DATA_MAX` of them, in the block slots
+  pub fn set_inline_bytes(&mut self, bytes: &[u8]) {
+    let mut raw = [0; INLINE_DATA_MAX];
+    raw[..bytes.len()].copy_from_slice(bytes);
+    for (slot, chunk) in self.direct.iter_mut().zip(raw.chunks(size_of::<usize>())) {
+      *slot = usize::from_le_bytes(chunk.try_into().unwrap());
     }
+    self.size = bytes.len();
+  }
+
+  /// Turns an inode with inline data into an empty one with blocks
+  pub fn drop_inline_data(&mut self) {
+    self.inline_data = false;
+    self.direct = [0; INODE_LINKS];
+    self.size = 0;
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Directory {
-  pub parent_inode_ind: Option<usize>,
-  pub files: Vec<(usize, String)>
+// `len` blocks from `start` on, holding the blocks of a file from `logical` on. In index
+// nodes the entry covers as many blocks of the file, and `start` is the block of the child node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+  pub logical: usize,
+  pub start: usize,
+  pub len: usize,
+}
+
+// Node of an extent tree, with leaves at depth 0. The root lives in the block slots of the
+// inode and the other nodes take a block each.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtentNode {
+  pub depth: usize,
+  pub entries: Vec<Extent>,
+}
+
+impl ExtentNode {
+  pub fn from_slots(slots: &[usize; INODE_LINKS]) -> ExtentNode {
+    let count = std::cmp::min(slots[1], INLINE_EXTENTS);
+    let entries = slots[2..2 + 3 * count].chunks(3)
+      .map(|words| Extent { logical: words[0], start: words[1], len: words[2] })
+      .collect();
+    ExtentNode { depth: slots[0], entries }
+  }
+
+  pub fn to_slots(&self) -> [usize; INODE_LINKS] {
+    let mut slots = [0; INODE_LINKS];
+    slots[0] = self.depth;
+    slots[1] = self.entries.len();
+    for (i, extent) in self.entries.iter().take(INLINE_EXTENTS).enumerate() {
+      slots[2 + 3 * i..5 + 3 * i].copy_from_slice(&[extent.logical, extent.start, extent.len]);
+    }
+    slots
+  }
 }
 
 // Custom bitmap abstraction with handy methods
@@ -94,18 +260,71 @@ pub trait Bitmap<'a> {
     (byte << shift) & mask != mask
   }
 
+  // The 64 bits from `64 * i` on, the first one highest; bits past the end count as taken
+  fn word(&'a self, i: usize) -> u64 {
+    let mut bytes = [0xff; 8];
+    for (j, byte) in self.immutable().iter().skip(i * 8).take(8).enumerate() {
+      bytes[j] = *byte;
+    }
+    u64::from_be_bytes(bytes)
+  }
+
   fn find_free_from(&'a self, from: usize) -> Option<usize> {
-    (from..self.immutable().len() * 8).find(|&i| self.free_at(i))
+    let bits = self.immutable().len() * 8;
+    let mut i = from;
+    while i < bits {
+      let offset = i % 64;
+      let taken = (self.word(i / 64) << offset).leading_ones() as usize;
+      if taken < 64 - offset { return Some(i + taken).filter(|&found| found < bits) };
+      i += 64 - offset;
+    }
+    None
   }
 
   fn find_free(&'a self) -> Option<usize> { self.find_free_from(0) }
+
+  /// Number of free bits from `from` on, up to `max`
+  fn free_run(&'a self, from: usize, max: usize) -> usize {
+    let bits = self.immutable().len() * 8;
+    let (mut i, mut run) = (from, 0);
+    while run < max && i < bits {
+      let offset = i % 64;
+      let free = std::cmp::min((self.word(i / 64) << offset).leading_zeros() as usize, 64 - offset);
+      run += free;
+      if free < 64 - offset { break };
+      i += 64 - offset;
+    }
+    std::cmp::min(run, max)
+  }
+
+  /// Start and length of free bits for `wanted` new ones near `goal`: those from `goal` on if it
+  /// is free, else the first run of `wanted` after it, wrapping around, else the first shorter run
+  fn find_free_run(&'a self, goal: usize, wanted: usize) -> Option<(usize, usize)> {
+    let goal = if goal < self.immutable().len() * 8 { goal } else { 0 };
+    if self.free_at(goal) { return Some((goal, self.free_run(goal, wanted))) };
+    let mut shorter = None;
+    let (mut i, mut wrapped) = (goal, false);
+    loop {
+      let start = match self.find_free_from(i) {
+        Some(start) if !wrapped || start < goal => start,
+        _ if !wrapped => { wrapped = true; i = 0; continue },
+        _ => return shorter,
+      };
+      let run = self.free_run(start, wanted);
+      if run == wanted { return Some((start, run)) };
+      shorter.get_or_insert((start, run));
+      i = start + run;
+    }
+  }
+
+  fn count_free(&'a self) -> usize {
+    self.immutable().iter().map(|byte| byte.count_zeros() as usize).sum()
+  }
 }
 
-big_array! { BigArray; }
-#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
 pub struct InodeBitmap {
-  #[serde(with = "BigArray")]
-  inner: [u8; INODES_BITMAP_SIZE],
+  inner: Vec<u8>,
 }
 
 impl<'a> Bitmap<'a> for InodeBitmap {
@@ -113,8 +332,17 @@ impl<'a> Bitmap<'a> for InodeBitmap {
   fn immutable(&'a self) -> &'a [u8] { &self.inner }
 }
 
+impl InodeBitmap {
+  /// A bitmap of `bits` free bits, a multiple of 8
+  pub fn new(bits: usize) -> Self { InodeBitmap { inner: vec![0; bits / 8] } }
+
+  pub fn from_bytes(bytes: Vec<u8>) -> Self { InodeBitmap { inner: bytes } }
+
+  pub fn as_bytes(&self) -> &[u8] { &self.inner }
+}
+
 impl Default for InodeBitmap {
-  fn default() -> Self { InodeBitmap { inner: [0; INODES_BITMAP_SIZE] } }
+  fn default() -> Self { InodeBitmap::new(INODES_COUNT) }
 }
 
 impl Debug for InodeBitmap {
@@ -123,14 +351,13 @@ impl Debug for InodeBitmap {
   }
 }
 
-#[derive(Serialize, Deserialize,)]
+#[derive(Clone)]
 pub struct DataBitmap {
-  #[serde(with = "BigArray")]
-  inner: [u8; BLOCKS_BITMAP_SIZE],
+  inner: Vec<u8>,
 }
 
 impl Default for DataBitmap {
-  fn default() -> Self { DataBitmap { inner: [0; INODES_BITMAP_SIZE] } }
+  fn default() -> Self { DataBitmap::new(BLOCKS_COUNT) }
 }
 
 impl Debug for DataBitmap {
@@ -139,12 +366,31 @@ impl Debug for DataBitmap {
   }
 }
 
+impl DataBitmap {
+  /// A bitmap of `bits` free bits, a multiple of 8
+  pub fn new(bits: usize) -> Self { DataBitmap { inner: vec![0; bits / 8] } }
+
+  pub fn from_bytes(bytes: Vec<u8>) -> Self { DataBitmap { inner: bytes } }
+
+  pub fn as_bytes(&self) -> &[u8] { &self.inner }
+
+  /// Bits taken in either bitmap
+  pub fn merged(&self, other: &DataBitmap) -> DataBitmap {
+    let mut merged = self.clone();
+    for (byte, other) in merged.inner.iter_mut().zip(other.inner.iter()) {
+      *byte |= other;
+    }
+    merged
+  }
+}
+
 impl<'a> Bitmap<'a> for DataBitmap {
   fn mutable(&'a mut self) -> &'a mut [u8] { &mut self.inner }
   fn immutable(&'a self) -> &'a [u8] { &self.inner}
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
   use super::*;
 
@@ -180,5 +426,64 @@ mod tests {
     assert_eq!(bitmap1.find_free(), Some(3));
     assert_eq!(bitmap2.find_free(), Some(15));
     assert_eq!(bitmap3.find_free(), None);
+    assert_eq!(bitmap1.count_free(), INODES_COUNT - 4);
+    assert_eq!(bitmap3.count_free(), 0);
+    assert_eq!(bitmap1.find_free_from(8), Some(8));
+    assert_eq!(bitmap2.find_free_from(16), Some(16));
+  }
+
+  #[test]
+  fn free_runs() {
+    let mut bitmap: DataBitmap = Default::default();
+    for ind in (0..70).chain(75..80).chain(90..BLOCKS_COUNT) { bitmap.set(ind, true).unwrap() };
+    assert_eq!(bitmap.find_free_from(3), Some(70));
+    assert_eq!(bitmap.free_run(70, 100), 5);
+    assert_eq!(bitmap.free_run(80, 4), 4);
+    assert_eq!(bitmap.find_free_run(72, 10), Some((72, 3)));
+    assert_eq!(bitmap.find_free_run(0, 8), Some((80, 8)));
+    assert_eq!(bitmap.find_free_run(85, 20), Some((85, 5)));
+    assert_eq!(bitmap.find_free_run(88, 20), Some((88, 2)));
+    assert_eq!(bitmap.find_free_run(95, 20), Some((70, 5)));
+    bitmap.set(1023, false).unwrap();
+    assert_eq!(bitmap.free_run(1023, 5), 1);
+    assert_eq!(bitmap.find_free_from(1020), Some(1023));
+  }
+
+  #[test]
+  fn geometry() {
+    let sb = Superblock::fitting(1 << 20, 4096, None, INCOMPAT_EXTENTS).unwrap();
+    assert_eq!((sb.block_size, sb.inodes_count, sb.blocks_count % 8), (4096, 128, 0));
+    assert!(sb.image_size() <= 1 << 20);
+    assert!(sb.image_size() + 8 * 4096 > 1 << 20);
+    assert_eq!(sb.data_blocks, sb.inode_table + 128 * INODE_SIZE);
+    assert!(Superblock::fitting(4096, 4096, None, 0).is_err());
+    assert!(Superblock::new(512, 64, 64, 0).is_err());
+    assert!(Superblock::new(1024, 60, 64, 0).is_err());
+    assert!(sb.check_layout().is_ok());
+    assert_eq!(Superblock { inode_size: 120, ..sb.clone() }.check_layout().unwrap_err().to_string(),
+               "Unsupported inode size 120, expected 128");
+    assert!(Superblock { data_blocks: sb.data_blocks + 1, ..sb }.check_layout().is_err());
+  }
+
+  #[test]
+  fn inode_records() {
+    let inode = Inode { mode: 0o755, uid: 1000, gid: 1000, ..Inode::default() };
+    let bytes = bincode::serialize(&inode).unwrap();
+    assert!(bytes.len() <= INODE_SIZE);
+    // Fields added later go at the end, after the ones of the first records
+    assert_eq!(&bytes[bytes.len() - 10..], &[0o355, 1, 0xe8, 3, 0, 0, 0xe8, 3, 0, 0]);
+  }
+
+  #[test]
+  fn extent_slots() {
+    let node = ExtentNode { depth: 1, entries: vec![Extent { logical: 0, start: 7, len: 3 }, Extent { logical: 3, start: 20, len: 1 }] };
+    assert_eq!(ExtentNode::from_slots(&node.to_slots()), node);
+    assert_eq!(ExtentNode::from_slots(&[0; INODE_LINKS]), ExtentNode::default());
+    for &block_size in BLOCK_SIZES.iter() {
+      let count = extents_per_block(block_size);
+      let full = ExtentNode { depth: 0, entries: vec![node.entries[0]; count] };
+      assert!(bincode::serialize(&full).unwrap().len() <= block_size);
+      assert!(bincode::serialize(&full).unwrap().len() + 24 > block_size);
+    }
   }
 }
@@ -0,0 +1,539 @@
+use crate::filesystem::{FileType, Filesystem};
+use crate::host::Copied;
+use crate::path;
+use crate::transfer::CHUNK_SIZE;
+use crate::walk::Walk;
+
+use std::io::{self, Read, Write};
+use anyhow::{anyhow, Result};
+
+// Tar archives in the ustar format, with pax extended headers for paths and link targets too
+// long for it. GNU long names are understood when reading. Archives are streamed: entries are
+// written as the tree is walked, and read entries go straight into the image. Permission bits
+// are written as those of the entry if the backend keeps them, and as 755 for directories, 644
+// for files and 777 for links otherwise; owners likewise, root otherwise. Only ids are kept, the
+// user and group names are written for root alone and ignored when reading.
+
+const BLOCK: usize = 512;
+
+/// What an archive entry is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+  File,
+  Directory,
+  Symlink(String),
+  /// Another entry of the archive with the same content
+  HardLink(String),
+  /// Devices, FIFOs and whatever else, by their type flag
+  Other(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry {
+  /// Path inside the archive, without the `/` after directories
+  pub path: String,
+  pub kind: Kind,
+  /// Bytes of content following the header
+  pub size: usize,
+  pub mode: u16,
+  pub uid: u32,
+  pub gid: u32,
+  pub mtime: u64,
+}
+
+// Largest id the octal fields of the header hold, larger ones go into a pax header
+const MAX_OCTAL_ID: u32 = 0o7777777;
+
+// `value` as `len - 1` octal digits and a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+  let digits = format!("{:0width$o}", value, width = field.len() - 1);
+  let digits = &digits.as_bytes()[digits.len() + 1 - field.len()..];
+  field[..digits.len()].copy_from_slice(digits);
+}
+
+fn read_number(field: &[u8]) -> Result<u64> {
+  // GNU tar writes large numbers in base 256, flagged by the highest bit
+  if field.first().is_some_and(|&byte| byte & 0x80 != 0) {
+    return Ok(field[1..].iter().fold(0, |value, &byte| (value << 8) | byte as u64));
+  }
+  let text = String::from_utf8_lossy(field);
+  let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+  if text.is_empty() { return Ok(0) };
+  u64::from_str_radix(text, 8).map_err(|_| anyhow!("Invalid number in tar header: {:?}", text))
+}
+
+fn read_text(field: &[u8]) -> String {
+  let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+  String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// Sum of the header bytes, the checksum field counting as spaces
+fn checksum(header: &[u8]) -> u64 {
+  header.iter().enumerate().map(|(i, &byte)| if (148..156).contains(&i) { b' ' as u64 } else { byte as u64 }).sum()
+}
+
+// The longest start of `text` of at most `len` bytes
+fn truncate(text: &str, len: usize) -> &str {
+  let mut end = std::cmp::min(len, text.len());
+  while !text.is_char_boundary(end) { end -= 1 };
+  &text[..end]
+}
+
+// `key=value` record of a pax header, preceded by its own length
+fn pax_record(key: &str, value: &str) -> String {
+  let body = format!(" {}={}\n", key, value);
+  let mut len = body.len();
+  while (len.to_string().len() + body.len()) != len { len = len.to_string().len() + body.len() };
+  format!("{}{}", len, body)
+}
+
+// Prefix and name fields of the ustar header for `name`, split at a slash if it is too long for
+// the name field alone; none if it is too long for both
+fn split_name(name: &str) -> Option<(&str, &str)> {
+  if name.len() <= 100 { return Some(("", name)) };
+  (0..name.len() - 1).rev()
+    .filter(|&at| name.as_bytes()[at] == b'/')
+    .find(|&at| at <= 155 && name.len() - at - 1 <= 100)
+    .map(|at| (&name[..at], &name[at + 1..]))
+}
+
+fn padding(size: usize) -> usize {
+  (BLOCK - size % BLOCK) % BLOCK
+}
+
+/// Writes entries to an archive, which `finish` ends
+pub struct TarWriter<W: Write> {
+  writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+  pub fn new(writer: W) -> Self { TarWriter { writer } }
+
+  fn header(&mut self, entry: &TarEntry, name: &str, link: &str) -> Result<()> {
+    let (type_flag, size) = match &entry.kind {
+      Kind::File => (b'0', entry.size),
+      Kind::Directory => (b'5', 0),
+      Kind::Symlink(_) => (b'2', 0),
+      Kind::HardLink(_) => (b'1', 0),
+      Kind::Other(flag) => (*flag, entry.size),
+    };
+    let mut header = [0u8; BLOCK];
+    let (prefix, short) = split_name(name).unwrap_or(("", truncate(name, 100)));
+    header[..short.len()].copy_from_slice(short.as_bytes());
+    write_octal(&mut header[100..108], entry.mode as u64);
+    write_octal(&mut header[108..116], std::cmp::min(entry.uid, MAX_OCTAL_ID) as u64);
+    write_octal(&mut header[116..124], std::cmp::min(entry.gid, MAX_OCTAL_ID) as u64);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], entry.mtime);
+    header[156] = type_flag;
+    let link = truncate(link, 100);
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    if entry.uid == 0 { header[265..269].copy_from_slice(b"root") };
+    if entry.gid == 0 { header[297..301].copy_from_slice(b"root") };
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    header[148..156].copy_from_slice(sum.as_bytes());
+    self.writer.write_all(&header)?;
+    Ok(())
+  }
+
+  /// Writes the header of `entry`, followed by `content` for files
+  pub fn append_entry(&mut self, entry: &TarEntry, content: &[u8]) -> Result<()> {
+    let link = match &entry.kind { Kind::Symlink(link) | Kind::HardLink(link) => link.as_str(), _ => "" };
+    let name = if entry.kind == Kind::Directory { format!("{}/", entry.path) } else { entry.path.clone() };
+    let mut records = String::new();
+    if split_name(&name).is_none() { records.push_str(&pax_record("path", &name)) };
+    if link.len() > 100 { records.push_str(&pax_record("linkpath", link)) };
+    if entry.uid > MAX_OCTAL_ID { records.push_str(&pax_record("uid", &entry.uid.to_string())) };
+    if entry.gid > MAX_OCTAL_ID { records.push_str(&pax_record("gid", &entry.gid.to_string())) };
+    if !records.is_empty() {
+      let base = truncate(name.trim_end_matches('/').rsplit('/').next().unwrap_or(""), 88);
+      let pax = TarEntry {
+        path: format!("PaxHeaders/{}", base), kind: Kind::Other(b'x'), size: records.len(), mode: 0o644,
+        uid: 0, gid: 0, mtime: entry.mtime,
+      };
+      self.header(&pax, &pax.path, "")?;
+      self.writer.write_all(records.as_bytes())?;
+      self.writer.write_all(&[0; BLOCK][..padding(records.len())])?;
+    }
+    self.header(&TarEntry { size: content.len(), ..entry.clone() }, &name, link)?;
+    self.writer.write_all(content)?;
+    self.writer.write_all(&[0; BLOCK][..padding(content.len())])?;
+    Ok(())
+  }
+
+  /// Appends the entry at `path` of `fs` and everything below it, named after the last component of
+  /// `path`. Below the root the names start right with its entries.
+  pub fn append_tree<F: Filesystem>(&mut self, fs: &F, path: &str, copied: &mut Copied) -> Result<()> {
+    let path = path::resolve("/", path);
+    let parent_depth = path::components(&path).len().saturating_sub(1);
+    for item in Walk::new(fs, &path) {
+      let item = item?;
+      let name = path::components(&item.path)[parent_depth..].join("/");
+      if name.is_empty() { continue };
+      let (kind, content, default_mode) = match item.entry.file_type {
+        FileType::Directory => { copied.directories += 1; (Kind::Directory, vec![], 0o755) },
+        FileType::File => { copied.files += 1; (Kind::File, fs.read(&item.path)?, 0o644) },
+        FileType::Symlink => { copied.symlinks += 1; (Kind::Symlink(fs.readlink(&item.path)?), vec![], 0o777) },
+      };
+      let mode = item.entry.mode.unwrap_or(default_mode);
+      let (uid, gid) = item.entry.owner.unwrap_or((0, 0));
+      let entry = TarEntry { path: name, kind, size: content.len(), mode, uid, gid, mtime: item.entry.mtime };
+      self.append_entry(&entry, &content)?;
+    }
+    Ok(())
+  }
+
+  /// Ends the archive with two empty blocks
+  pub fn finish(mut self) -> Result<W> {
+    self.writer.write_all(&[0; 2 * BLOCK])?;
+    self.writer.flush()?;
+    Ok(self.writer)
+  }
+}
+
+/// Reads the entries of an archive one after the other
+pub struct TarReader<R: Read> {
+  reader: R,
+  /// Bytes of content and padding of the current entry not read yet
+  left: usize,
+  padding: usize,
+}
+
+impl<R: Read> TarReader<R> {
+  pub fn new(reader: R) -> Self { TarReader { reader, left: 0, padding: 0 } }
+
+  fn skip(&mut self, count: usize) -> Result<()> {
+    let skipped = io::copy(&mut (&mut self.reader).take(count as u64), &mut io::sink())?;
+    if skipped < count as u64 { return Err(anyhow!("Unexpected end of archive")) };
+    Ok(())
+  }
+
+  /// Reads the rest of the content of the current entry, at most `CHUNK_SIZE` bytes at a time
+  pub fn read_chunk(&mut self) -> Result<Vec<u8>> {
+    let mut chunk = vec![0; std::cmp::min(self.left, CHUNK_SIZE)];
+    self.reader.read_exact(&mut chunk).map_err(|_| anyhow!("Unexpected end of archive"))?;
+    self.left -= chunk.len();
+    Ok(chunk)
+  }
+
+  fn read_content(&mut self) -> Result<Vec<u8>> {
+    let mut content = vec![];
+    while self.left > 0 { content.extend(self.read_chunk()?) };
+    Ok(content)
+  }
+
+  /// The next entry, skipping whatever is left of the current one; its content is then read
+  /// with `read_chunk`
+  pub fn next_entry(&mut self) -> Result<Option<TarEntry>> {
+    let (mut long_path, mut long_link, mut pax_mtime, mut pax_size) = (None, None, None, None);
+    let (mut pax_uid, mut pax_gid) = (None, None);
+    loop {
+      self.skip(self.left + self.padding)?;
+      self.left = 0;
+      self.padding = 0;
+      let mut header = [0u8; BLOCK];
+      match self.reader.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => self.reader.read_exact(&mut header[1..]).map_err(|_| anyhow!("Unexpected end of archive"))?,
+      }
+      if header.iter().all(|&byte| byte == 0) { return Ok(None) };
+      if read_number(&header[148..156])? != checksum(&header) { return Err(anyhow!("Invalid checksum of a tar header")) };
+      let ustar = &header[257..263] == b"ustar\0";
+      let mut path = read_text(&header[..100]);
+      let prefix = read_text(&header[345..500]);
+      if ustar && !prefix.is_empty() { path = format!("{}/{}", prefix, path) };
+      let size = pax_size.take().map_or_else(|| read_number(&header[124..136]), Ok)? as usize;
+      self.left = size;
+      self.padding = padding(size);
+      let type_flag = header[156];
+      match type_flag {
+        b'x' => {
+          for (key, value) in parse_pax(&self.read_content()?)? {
+            match key.as_str() {
+              "path" => long_path = Some(value),
+              "linkpath" => long_link = Some(value),
+              "mtime" => pax_mtime = value.split('.').next().and_then(|secs| secs.parse().ok()),
+              "size" => pax_size = value.parse().ok(),
+              "uid" => pax_uid = value.parse().ok(),
+              "gid" => pax_gid = value.parse().ok(),
+              _ => {},
+            }
+          }
+          continue;
+        }
+        b'L' => { long_path = Some(read_text(&self.read_content()?)); continue },
+        b'K' => { long_link = Some(read_text(&self.read_content()?)); continue },
+        // Global pax headers describe the whole archive
+        b'g' => continue,
+        _ => {},
+      }
+      let path = long_path.take().unwrap_or(path);
+      let link = long_link.take().unwrap_or_else(|| read_text(&header[157..257]));
+      let kind = match type_flag {
+        b'0' | 0 | b'7' if path.ends_with('/') => Kind::Directory,
+        b'0' | 0 | b'7' => Kind::File,
+        b'5' => Kind::Directory,
+        b'2' => Kind::Symlink(link),
+        b'1' => Kind::HardLink(link),
+        flag => Kind::Other(flag),
+      };
+      let mtime = pax_mtime.take().map_or_else(|| read_number(&header[136..148]), Ok)?;
+      let mode = (read_number(&header[100..108])? & 0o7777) as u16;
+      let id = |field: &[u8]| read_number(field).map(|id| std::cmp::min(id, u32::MAX as u64) as u32);
+      let uid = pax_uid.take().map_or_else(|| id(&header[108..116]), Ok)?;
+      let gid = pax_gid.take().map_or_else(|| id(&header[116..124]), Ok)?;
+      let path = path.trim_end_matches('/').to_owned();
+      return Ok(Some(TarEntry { path, kind, size, mode, uid, gid, mtime }));
+    }
+  }
+}
+
+// Records of a pax extended header
+fn parse_pax(content: &[u8]) -> Result<Vec<(String, String)>> {
+  let mut records = vec![];
+  let mut rest = content;
+  while !rest.is_empty() && rest[0] != 0 {
+    let space = rest.iter().position(|&byte| byte == b' ').ok_or(anyhow!("Invalid pax record"))?;
+    let len: usize = std::str::from_utf8(&rest[..space])?.parse()?;
+    if len <= space + 1 || len > rest.len() { return Err(anyhow!("Invalid pax record")) };
+    let record = String::from_utf8_lossy(&rest[space + 1..len - 1]).into_owned();
+    let (key, value) = record.split_once('=').ok_or(anyhow!("Invalid pax record"))?;
+    records.push((key.to_owned(), value.to_owned()));
+    rest = &rest[len..];
+  }
+  Ok(records)
+}
+
+/// Writes `path` of `fs` and everything below it to `writer` as an archive, see `TarWriter::append_tree`
+pub fn export<F: Filesystem, W: Write>(fs: &F, path: &str, writer: W) -> Result<Copied> {
+  let mut copied = Copied::default();
+  let mut tar = TarWriter::new(writer);
+  tar.append_tree(fs, path, &mut copied)?;
+  tar.finish()?;
+  Ok(copied)
+}
+
+// Creates the directories leading to `components` below `dest` that are missing
+fn make_parents<F: Filesystem>(fs: &mut F, dest: &str, components: &[&str]) -> Result<()> {
+  let mut dir = dest.trim_end_matches('/').to_owned();
+  for name in components {
+    dir = format!("{}/{}", dir, name);
+    match fs.lstat(&dir) {
+      Ok(meta) if meta.file_type == FileType::Directory => {},
+      Ok(_) => return Err(anyhow!("Is not a directory: {}", dir)),
+      Err(_) => fs.mkdir(&dir)?,
+    }
+  }
+  Ok(())
+}
+
+/// Recreates the entries of the archive read from `reader` inside the directory `dest` of `fs`,
+/// replacing files and links already there. Hard links become copies, as the image has none, and
+/// entries of other types or leading outside of `dest` are left out.
+pub fn import<F: Filesystem, R: Read>(fs: &mut F, reader: R, dest: &str) -> Result<Copied> {
+  let mut copied = Copied::default();
+  let mut tar = TarReader::new(reader);
+  let dest = path::resolve("/", dest);
+  // Directories get their times, permissions and owners last, as adding entries changes the times
+  let mut directories = vec![];
+  while let Some(entry) = tar.next_entry()? {
+    let components: Vec<&str> = entry.path.split('/').filter(|&name| !name.is_empty() && name != ".").collect();
+    if components.contains(&"..") {
+      copied.skipped.push(format!("{}: leads outside of {}", entry.path, dest));
+      continue;
+    }
+    if let Some(why) = components.iter().find_map(|name| path::check_name(name).err()) {
+      copied.skipped.push(format!("{}: {}", entry.path, why));
+      continue;
+    }
+    let target = components.iter().fold(dest.clone(), |dir, name| format!("{}/{}", dir.trim_end_matches('/'), name));
+    let parents = match components.split_last() {
+      Some((_, parents)) => parents,
+      None if entry.kind == Kind::Directory => { directories.push((target, entry)); continue },
+      None => { copied.skipped.push(format!("{}: empty name", entry.path)); continue },
+    };
+    if let Kind::Other(flag) = entry.kind {
+      copied.skipped.push(format!("{}: unsupported entry type {:?}", entry.path, flag as char));
+      continue;
+    }
+    let source = match &entry.kind {
+      Kind::HardLink(link) => match link_source(fs, &dest, link) {
+        Ok(source) => Some(source),
+        Err(why) => { copied.skipped.push(format!("{}: {}", entry.path, why)); continue },
+      },
+      _ => None,
+    };
+    make_parents(fs, &dest, parents)?;
+    let existing = fs.lstat(&target).ok().map(|meta| meta.file_type);
+    match (&entry.kind, existing) {
+      (Kind::Directory, Some(FileType::Directory)) => {},
+      (_, Some(FileType::Directory)) => return Err(anyhow!("Is a directory: {}", target)),
+      (Kind::File, Some(FileType::File)) | (Kind::HardLink(_), Some(FileType::File)) => fs.write(&target, &[])?,
+      (_, Some(_)) => fs.unlink(&target)?,
+      (_, None) => {},
+    }
+    match &entry.kind {
+      Kind::Directory => {
+        if existing.is_none() { fs.mkdir(&target)? };
+        directories.push((target, entry));
+        copied.directories += 1;
+        continue;
+      }
+      Kind::File => {
+        if existing.is_none() { fs.create(&target, &[])? };
+        let handle = fs.open(&target)?;
+        let mut offset = 0;
+        let written = (|| {
+          while tar.left > 0 {
+            let chunk = tar.read_chunk()?;
+            fs.write_at(handle, offset, &chunk)?;
+            offset += chunk.len();
+          }
+          Ok(())
+        })();
+        fs.close(handle)?;
+        written.map_err(|why: anyhow::Error| why.context(format!("Could not import {}", entry.path)))?;
+        copied.files += 1;
+      }
+      Kind::HardLink(link) => {
+        let source = source.as_ref().unwrap();
+        let content = fs.read(source).map_err(|why| why.context(format!("Hard link {} to {}", entry.path, link)))?;
+        if existing.is_none() { fs.create(&target, &content)? } else { fs.write(&target, &content)? };
+        copied.files += 1;
+      }
+      Kind::Symlink(link) => {
+        fs.symlink(link, &target)?;
+        copied.symlinks += 1;
+      }
+      Kind::Other(_) => unreachable!(),
+    }
+    fs.set_mode(&target, entry.mode)?;
+    fs.set_owner(&target, entry.uid, entry.gid)?;
+    fs.set_mtime(&target, entry.mtime)?;
+  }
+  for (dir, entry) in directories.iter().rev() {
+    fs.set_mode(dir, entry.mode)?;
+    fs.set_owner(dir, entry.uid, entry.gid)?;
+    fs.set_mtime(dir, entry.mtime)?;
+  }
+  Ok(copied)
+}
+
+// The file a hard link to `link` stands for, which must have been extracted into `dest` before.
+// Neither `..` nor symbolic links on the way may lead it out of there.
+fn link_source<F: Filesystem>(fs: &F, dest: &str, link: &str) -> Result<String> {
+  let components: Vec<&str> = link.split('/').filter(|&name| !name.is_empty() && name != ".").collect();
+  if components.is_empty() || components.contains(&"..") { return Err(anyhow!("link to {} leads outside of {}", link, dest)) };
+  let mut source = dest.to_owned();
+  for (i, name) in components.iter().enumerate() {
+    source = format!("{}/{}", source.trim_end_matches('/'), name);
+    let expected = if i + 1 == components.len() { FileType::File } else { FileType::Directory };
+    match fs.lstat(&source).map(|meta| meta.file_type) {
+      Ok(file_type) if file_type == expected => {},
+      Ok(FileType::Symlink) => return Err(anyhow!("link to {} goes through a symbolic link", link)),
+      _ => return Err(anyhow!("link to {} is not a file extracted before", link)),
+    }
+  }
+  Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::structure::INCOMPAT_EXTENTS;
+  use crate::testing::temp_fs;
+
+  fn entries(archive: &[u8]) -> Vec<TarEntry> {
+    let mut reader = TarReader::new(archive);
+    let mut entries = vec![];
+    while let Some(entry) = reader.next_entry().unwrap() { entries.push(entry) };
+    entries
+  }
+
+  #[test]
+  fn round_trip() {
+    let (mut fs, _image) = temp_fs("tar-export", INCOMPAT_EXTENTS);
+    let deep = format!("/home/{}/{}", "d".repeat(120), "f".repeat(120));
+    fs.mkdir("/home").unwrap();
+    fs.mkdir(&path::split_parent(&deep).unwrap().0).unwrap();
+    fs.create(&deep, &[5; 3000]).unwrap();
+    fs.create("/home/notes", b"notes").unwrap();
+    fs.symlink(&"../".repeat(40), "/home/up").unwrap();
+    fs.set_mtime("/home/notes", 1_234_567_890).unwrap();
+    fs.set_owner("/home/notes", 1000, 100).unwrap();
+    fs.set_owner("/home/up", 1 << 30, 0).unwrap();
+
+    let mut archive = vec![];
+    let copied = export(&fs, "/home", &mut archive).unwrap();
+    assert_eq!((copied.files, copied.directories, copied.symlinks), (2, 2, 1));
+    assert_eq!(archive.len() % BLOCK, 0);
+    let listed = entries(&archive);
+    let paths: Vec<&str> = listed.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, vec!["home", &deep[1..deep.rfind('/').unwrap()], &deep[1..], "home/notes", "home/up"]);
+    assert_eq!(listed[2].size, 3000);
+    assert_eq!((listed[3].mode, listed[3].uid, listed[3].gid, listed[3].mtime), (0o644, 1000, 100, 1_234_567_890));
+    assert_eq!((listed[4].uid, listed[4].gid), (1 << 30, 0));
+    assert_eq!(listed[4].kind, Kind::Symlink("../".repeat(40)));
+
+    let (mut other, _other_image) = temp_fs("tar-import", INCOMPAT_EXTENTS);
+    other.mkdir("/backup").unwrap();
+    import(&mut other, &archive[..], "/backup").unwrap();
+    assert_eq!(other.read(&format!("/backup{}", deep)).unwrap(), vec![5; 3000]);
+    assert_eq!(other.stat("/backup/home/notes").unwrap().mtime, 1_234_567_890);
+    assert_eq!(other.stat("/backup/home/notes").unwrap().owner, Some((1000, 100)));
+    assert_eq!(other.lstat("/backup/home/up").unwrap().owner, Some((1 << 30, 0)));
+    assert_eq!(other.stat("/backup/home").unwrap().mode, Some(0o755));
+    assert_eq!(other.readlink("/backup/home/up").unwrap(), "../".repeat(40));
+    // Importing again replaces what is there
+    other.write("/backup/home/notes", b"changed").unwrap();
+    import(&mut other, &archive[..], "/backup").unwrap();
+    assert_eq!(other.read("/backup/home/notes").unwrap(), b"notes");
+  }
+
+  #[test]
+  fn unusual_entries() {
+    let mut archive = TarWriter::new(vec![]);
+    let entry = |path: &str, kind: Kind| TarEntry { path: path.to_owned(), kind, size: 0, mode: 0o644, uid: 0, gid: 0, mtime: 0 };
+    archive.append_entry(&entry("./a/b/file", Kind::File), b"content").unwrap();
+    archive.append_entry(&TarEntry { mode: 0o700, ..entry("a/b/script", Kind::File) }, b"").unwrap();
+    archive.append_entry(&entry("a/hard", Kind::HardLink("./a/b/file".to_owned())), b"").unwrap();
+    archive.append_entry(&entry("../escape", Kind::File), b"x").unwrap();
+    archive.append_entry(&entry("a/fifo", Kind::Other(b'6')), b"").unwrap();
+    let archive = archive.finish().unwrap();
+
+    let (mut fs, _image) = temp_fs("tar-unusual", INCOMPAT_EXTENTS);
+    let copied = import(&mut fs, &archive[..], "/").unwrap();
+    assert_eq!(copied.files, 3);
+    assert_eq!(fs.stat("/a/b/script").unwrap().mode, Some(0o700));
+    assert_eq!(copied.skipped, vec!["../escape: leads outside of /", "a/fifo: unsupported entry type '6'"]);
+    assert_eq!(fs.read("/a/hard").unwrap(), b"content");
+    assert!(fs.lookup("/escape").is_err());
+    assert!(import(&mut fs, &archive[..BLOCK + 3], "/").is_err());
+    assert!(import(&mut fs, &archive[..BLOCK + 10], "/").is_err());
+  }
+
+  #[test]
+  fn hard_links_stay_inside() {
+    let mut archive = TarWriter::new(vec![]);
+    let entry = |path: &str, kind: Kind| TarEntry { path: path.to_owned(), kind, size: 0, mode: 0o644, uid: 0, gid: 0, mtime: 0 };
+    archive.append_entry(&entry("up", Kind::HardLink("../secret".to_owned())), b"").unwrap();
+    archive.append_entry(&entry("root", Kind::Symlink("/".to_owned())), b"").unwrap();
+    archive.append_entry(&entry("through", Kind::HardLink("root/secret".to_owned())), b"").unwrap();
+    archive.append_entry(&entry("dir", Kind::Directory), b"").unwrap();
+    archive.append_entry(&entry("copy", Kind::HardLink("dir".to_owned())), b"").unwrap();
+    let archive = archive.finish().unwrap();
+
+    let (mut fs, _image) = temp_fs("tar-links", INCOMPAT_EXTENTS);
+    fs.create("/secret", b"secret").unwrap();
+    fs.mkdir("/dest").unwrap();
+    let copied = import(&mut fs, &archive[..], "/dest").unwrap();
+    assert_eq!(copied.skipped, vec![
+      "up: link to ../secret leads outside of /dest",
+      "through: link to root/secret goes through a symbolic link",
+      "copy: link to dir is not a file extracted before",
+    ]);
+    assert_eq!(fs.readdir("/dest").unwrap().len(), 2);
+  }
+}
@@ -0,0 +1,113 @@
+use std::io;
+use std::io::{Read, Write};
+
+// Wire format shared by `ext2client` put/get and the server. The client sends
+//   upload <size> <crc> <length> followed by the <length> bytes of the path, then <size> raw bytes
+//   download <path>
+//   entries <path>
+//   mkdirp <path>
+// and the server answers each with a single `ok [...]` or `error <reason>` line
+// (a download reply `ok <size>` is followed by the raw bytes and then `ok <crc>`, or `error <reason>`
+// if reading the file failed halfway, an entries reply `ok <count>` by one `d name`, `f name`
+// or `l name` line per entry). None of these
+// commands is followed by a prompt. The path of an upload is framed by its length rather than
+// quoted, so that the header is read without the shell tokenizer. A header the server cannot
+// parse ends the connection, the bytes after it are never taken for commands.
+
+pub const CHUNK_SIZE: usize = 4096;
+/// Longest path an upload may announce
+pub const MAX_PATH: usize = 4096;
+
+pub fn upload_header(path: &str, size: usize, crc: u32) -> String {
+  format!("upload {} {} {}", size, crc, path.len())
+}
+
+/// Size, checksum and path length of an upload header, None if the line is malformed
+pub fn parse_upload_header(line: &str) -> Option<(usize, u32, usize)> {
+  match line.split(' ').collect::<Vec<_>>().as_slice() {
+    ["upload", size, crc, length] => Some((size.parse().ok()?, crc.parse().ok()?, length.parse().ok()?)),
+    _ => None,
+  }
+}
+
+/// Incremental CRC-32 (IEEE), the checksum transfers are verified against
+pub struct Crc32 {
+  value: u32,
+}
+
+impl Crc32 {
+  pub fn new() -> Self { Crc32 { value: !0 } }
+
+  pub fn update(&mut self, bytes: &[u8]) {
+    for &byte in bytes {
+      self.value ^= byte as u32;
+      for _ in 0..8 {
+        let mask = (self.value & 1).wrapping_neg();
+        self.value = (self.value >> 1) ^ (0xEDB8_8320 & mask);
+      }
+    }
+  }
+
+  pub fn finish(&self) -> u32 { !self.value }
+}
+
+impl Default for Crc32 {
+  fn default() -> Self { Crc32::new() }
+}
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+  let mut crc = Crc32::new();
+  crc.update(bytes);
+  crc.finish()
+}
+
+/// Copies exactly `size` bytes in chunks, calling `progress` with the amount copied so far after each one.
+/// Returns the checksum of everything copied.
+pub fn copy_chunked<R: Read, W: Write, P: FnMut(usize)>(reader: &mut R, writer: &mut W, size: usize, mut progress: P)
+                                                         -> io::Result<u32> {
+  let mut crc = Crc32::new();
+  let mut buffer = [0u8; CHUNK_SIZE];
+  let mut copied = 0;
+  while copied < size {
+    let chunk = std::cmp::min(CHUNK_SIZE, size - copied);
+    reader.read_exact(&mut buffer[..chunk])?;
+    writer.write_all(&buffer[..chunk])?;
+    crc.update(&buffer[..chunk]);
+    copied += chunk;
+    progress(copied);
+  }
+  Ok(crc.finish())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn crc32() {
+    assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+    assert_eq!(checksum(b""), 0);
+  }
+
+  #[test]
+  fn copy_in_chunks() {
+    let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    let mut copied = vec![];
+    let mut reports = vec![];
+    let crc = copy_chunked(&mut &content[..], &mut copied, content.len(), |done| reports.push(done)).unwrap();
+    assert_eq!(copied, content);
+    assert_eq!(crc, checksum(&content));
+    assert_eq!(reports, vec![4096, 8192, 10_000]);
+    assert!(copy_chunked(&mut &content[..10], &mut vec![], 20, |_| ()).is_err());
+  }
+
+  #[test]
+  fn upload_headers() {
+    let header = upload_header("/home/my notes.txt", 1200, 77);
+    assert_eq!(header, "upload 1200 77 18");
+    assert_eq!(parse_upload_header(&header), Some((1200, 77, 18)));
+    assert_eq!(parse_upload_header("upload /home/notes.txt 1200 77"), None);
+    assert_eq!(parse_upload_header("upload 1200 77"), None);
+    assert_eq!(parse_upload_header("upload -1 77 18"), None);
+  }
+}
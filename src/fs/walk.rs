@@ -0,0 +1,142 @@
+use crate::filesystem::{DirEntry, FileType, Filesystem};
+use crate::path;
+
+use std::collections::VecDeque;
+use anyhow::{anyhow, Result};
+
+// An entry met while walking a tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+  /// Absolute path of the entry
+  pub path: String,
+  /// Distance from the starting point, which has depth 0
+  pub depth: usize,
+  /// Name and metadata; those of the target for followed symbolic links
+  pub entry: DirEntry,
+}
+
+type Prune<'a> = Box<dyn FnMut(&WalkEntry) -> bool + 'a>;
+
+struct Pending {
+  item: WalkEntry,
+  // Inodes of the directories above, to notice loops made of symbolic links
+  ancestors: Vec<usize>,
+}
+
+// Iterator over a directory tree, depth-first by default. Every directory is yielded
+// before its entries, which come in directory order. Failures to read a directory are
+// yielded in place of its entries, and the walk goes on.
+pub struct Walk<'a, F: Filesystem> {
+  fs: &'a F,
+  pending: VecDeque<Result<Pending>>,
+  breadth_first: bool,
+  follow_links: bool,
+  max_depth: Option<usize>,
+  prune: Option<Prune<'a>>,
+}
+
+impl<'a, F: Filesystem> Walk<'a, F> {
+  pub fn new(fs: &'a F, path: &str) -> Self {
+    let root = path::resolve("/", path);
+    let name = path::components(&root).last().map_or("/", |name| *name).to_owned();
+    let start = fs.stat(&root).map(|meta| Pending {
+      item: WalkEntry { path: root, depth: 0, entry: DirEntry::new(&name, meta) },
+      ancestors: vec![],
+    });
+    Walk { fs, pending: vec![start].into(), breadth_first: false, follow_links: false, max_depth: None, prune: None }
+  }
+
+  /// Visits all entries at one depth before going deeper
+  pub fn breadth_first(mut self, yes: bool) -> Self { self.breadth_first = yes; self }
+
+  /// Descends into directories behind symbolic links instead of yielding the links themselves
+  pub fn follow_links(mut self, yes: bool) -> Self { self.follow_links = yes; self }
+
+  /// Does not descend below `depth`
+  pub fn max_depth(mut self, depth: usize) -> Self { self.max_depth = Some(depth); self }
+
+  /// Yields the directories `prune` returns true for, but not their entries
+  pub fn prune<P: FnMut(&WalkEntry) -> bool + 'a>(mut self, prune: P) -> Self {
+    self.prune = Some(Box::new(prune));
+    self
+  }
+
+  fn children(&self, parent: &Pending) -> Vec<Result<Pending>> {
+    let entries = match self.fs.readdir(&parent.item.path) {
+      Ok(entries) => entries,
+      Err(why) => return vec![Err(why)],
+    };
+    let mut ancestors = parent.ancestors.clone();
+    ancestors.push(parent.item.entry.inode);
+    entries.into_iter().map(|entry| {
+      let path = format!("{}/{}", parent.item.path.trim_end_matches('/'), entry.name);
+      let entry = match entry.file_type {
+        FileType::Symlink if self.follow_links => match self.fs.stat(&path) {
+          Ok(meta) if ancestors.contains(&meta.inode) => return Err(anyhow!("File system loop: {}", path)),
+          Ok(meta) => DirEntry::new(&entry.name, meta),
+          Err(_) => entry,
+        },
+        _ => entry,
+      };
+      Ok(Pending { item: WalkEntry { path, depth: parent.item.depth + 1, entry }, ancestors: ancestors.clone() })
+    }).collect()
+  }
+}
+
+impl<'a, F: Filesystem> Iterator for Walk<'a, F> {
+  type Item = Result<WalkEntry>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let next = if self.breadth_first { self.pending.pop_front()? } else { self.pending.pop_back()? };
+    let next = match next {
+      Ok(next) => next,
+      Err(why) => return Some(Err(why)),
+    };
+    let descend = next.item.entry.file_type == FileType::Directory
+      && self.max_depth.is_none_or(|depth| next.item.depth < depth)
+      && !self.prune.as_mut().is_some_and(|prune| prune(&next.item));
+    if descend {
+      let children = self.children(&next);
+      if self.breadth_first { self.pending.extend(children) } else { self.pending.extend(children.into_iter().rev()) }
+    }
+    Some(Ok(next.item))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Walk;
+  use crate::filesystem::Filesystem;
+  use crate::testing::{temp_fs, TempPath};
+  use crate::Fs;
+
+  fn sample_tree(name: &str) -> (Fs, TempPath) {
+    let (mut fs, image) = temp_fs(&format!("walk-{}", name), 0);
+    for dir in ["/a", "/a/b", "/c"].iter() { fs.mkdir(dir).unwrap() };
+    fs.create("/a/b/file", b"").unwrap();
+    fs.create("/c/file", b"").unwrap();
+    fs.symlink("/a", "/c/link").unwrap();
+    (fs, image)
+  }
+
+  fn paths<I: Iterator<Item = anyhow::Result<super::WalkEntry>>>(walk: I) -> Vec<String> {
+    walk.map(|entry| entry.map_or_else(|why| why.to_string(), |entry| entry.path)).collect()
+  }
+
+  #[test]
+  fn orders() {
+    let (fs, _image) = sample_tree("orders");
+    assert_eq!(paths(Walk::new(&fs, "/")), vec!["/", "/a", "/a/b", "/a/b/file", "/c", "/c/file", "/c/link"]);
+    assert_eq!(paths(Walk::new(&fs, "/").breadth_first(true)), vec!["/", "/a", "/c", "/a/b", "/c/file", "/c/link", "/a/b/file"]);
+    assert_eq!(paths(Walk::new(&fs, "/a").max_depth(1)), vec!["/a", "/a/b"]);
+  }
+
+  #[test]
+  fn pruning_and_links() {
+    let (mut fs, _image) = sample_tree("prune");
+    assert_eq!(paths(Walk::new(&fs, "/").prune(|entry| entry.entry.name == "a")), vec!["/", "/a", "/c", "/c/file", "/c/link"]);
+    assert_eq!(paths(Walk::new(&fs, "/c").follow_links(true)), vec!["/c", "/c/file", "/c/link", "/c/link/b", "/c/link/b/file"]);
+    fs.symlink("..", "/a/b/up").unwrap();
+    assert_eq!(paths(Walk::new(&fs, "/a").follow_links(true)), vec!["/a", "/a/b", "/a/b/file", "File system loop: /a/b/up"]);
+  }
+}